@@ -1,5 +1,3 @@
-use reflink;
-
 use std::fs;
 use std::io::{self, Read};
 use std::time::Instant;
@@ -0,0 +1,23 @@
+use reflink::ReflinkOptions;
+
+use std::env;
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    let (from, to) = match (args.next(), args.next()) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            eprintln!("usage: copy_tree <from> <to>");
+            return;
+        }
+    };
+
+    let report = reflink::copy_tree(from, to, &ReflinkOptions::new()).unwrap();
+    println!(
+        "{} files reflinked, {} copied ({} bytes)",
+        report.reflinked, report.copied, report.bytes
+    );
+    for (path, e) in &report.failures {
+        println!("failed to clone {}: {}", path.display(), e);
+    }
+}
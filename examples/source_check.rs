@@ -0,0 +1,27 @@
+use reflink::ReflinkOptions;
+
+use std::fs;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 10_000;
+
+fn run(options: &ReflinkOptions) {
+    let before = Instant::now();
+    for _ in 0..ITERATIONS {
+        // the clone itself may be unsupported, we only care about the overhead
+        let _ = options.reflink("base.txt", "reflinked.txt");
+        let _ = fs::remove_file("reflinked.txt");
+    }
+    println!("{:?} per reflink", (Instant::now() - before) / ITERATIONS);
+}
+
+fn main() {
+    fs::write("base.txt", b"this is a test").unwrap();
+
+    print!("With source check: ");
+    run(&ReflinkOptions::new());
+    print!("Without source check: ");
+    run(ReflinkOptions::new().skip_source_check(true));
+
+    fs::remove_file("base.txt").unwrap();
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::error::aborted;
+use crate::Mechanism;
+
+/// A clone about to be performed, passed to the callback set with
+/// [`ReflinkOptions::audit`](crate::ReflinkOptions::audit).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AuditRecord {
+    /// The source path, or `None` when cloning from an open file with
+    /// [`reflink_fanout`](crate::ReflinkOptions::reflink_fanout).
+    pub source: Option<PathBuf>,
+    /// The destination path as requested, before the conflict policy picks another name or
+    /// removes an existing file.
+    pub dest: PathBuf,
+    /// When the clone was requested.
+    pub time: SystemTime,
+    /// The mechanism which will attempt the clone, or `None` if the data will only be copied,
+    /// e.g. from a FIFO with [`allow_nonregular_copy`](crate::ReflinkOptions::allow_nonregular_copy).
+    pub mechanism: Option<Mechanism>,
+    /// Whether the data is copied if the clone is not possible.
+    pub copy_fallback: bool,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            Some(ref source) => write!(f, "{}", source.display())?,
+            None => f.write_str("open file")?,
+        }
+        write!(f, " -> {}", self.dest.display())?;
+        match (self.mechanism, self.copy_fallback) {
+            (Some(mechanism), false) => write!(f, " ({})", mechanism),
+            (Some(mechanism), true) => write!(f, " ({}, or copy)", mechanism),
+            (None, _) => f.write_str(" (copy)"),
+        }
+    }
+}
+
+/// The callback set with [`ReflinkOptions::audit`](crate::ReflinkOptions::audit).
+#[derive(Clone)]
+pub(crate) struct AuditHook(Arc<dyn Fn(&AuditRecord) -> bool + Send + Sync>);
+
+impl AuditHook {
+    pub(crate) fn new<F>(audit: F) -> Self
+    where
+        F: Fn(&AuditRecord) -> bool + Send + Sync + 'static,
+    {
+        AuditHook(Arc::new(audit))
+    }
+
+    /// Passes the intended clone to the callback, failing with `ReflinkError::Aborted` if it
+    /// denies it.
+    pub(crate) fn check(
+        &self,
+        source: Option<&Path>,
+        dest: &Path,
+        mechanism: Option<Mechanism>,
+        copy_fallback: bool,
+    ) -> io::Result<()> {
+        let record = AuditRecord {
+            source: source.map(Path::to_path_buf),
+            dest: dest.to_path_buf(),
+            time: SystemTime::now(),
+            mechanism,
+            copy_fallback,
+        };
+        if (self.0)(&record) {
+            Ok(())
+        } else {
+            Err(aborted())
+        }
+    }
+}
+
+impl fmt::Debug for AuditHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditHook(..)")
+    }
+}
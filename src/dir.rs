@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{sys, Mechanism, ReflinkOptions};
+
+/// A file cloned by [`ReflinkDirIter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClonedEntry {
+    /// The path of the destination file.
+    pub path: PathBuf,
+    /// The mechanism which cloned the file, or `None` if it was copied instead, or hard linked
+    /// to an earlier entry with [`preserve_hard_links`](ReflinkOptions::preserve_hard_links).
+    pub mechanism: Option<Mechanism>,
+}
+
+/// A summary of a directory tree cloned by [`copy_tree`](crate::copy_tree).
+#[derive(Debug, Default)]
+pub struct TreeReport {
+    /// The number of files which were reflinked.
+    pub reflinked: usize,
+    /// The number of files which were copied, or hard linked to an earlier entry with
+    /// [`preserve_hard_links`](ReflinkOptions::preserve_hard_links).
+    pub copied: usize,
+    /// The total size of all reflinked and copied files.
+    pub bytes: u64,
+    /// The destination paths of the entries which could not be cloned, with the error for each.
+    pub failures: Vec<(PathBuf, io::Error)>,
+}
+
+impl fmt::Display for ClonedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mechanism {
+            Some(mechanism) => write!(f, "{} (reflinked with {})", self.path.display(), mechanism),
+            None => write!(f, "{} (copied)", self.path.display()),
+        }
+    }
+}
+
+impl fmt::Display for TreeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} reflinked, {} copied, {} bytes",
+            self.reflinked, self.copied, self.bytes
+        )?;
+        if !self.failures.is_empty() {
+            write!(f, ", {} failed", self.failures.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// An iterator which clones a directory tree lazily, one file per call to `next`.
+///
+/// Created by [`reflink_dir_iter`](crate::reflink_dir_iter) and
+/// [`ReflinkOptions::reflink_dir_iter`].
+#[derive(Debug)]
+pub struct ReflinkDirIter {
+    options: ReflinkOptions,
+    root: Option<(PathBuf, PathBuf)>,
+    /// The directories being walked, with the destination directory they are cloned into.
+    stack: Vec<(fs::ReadDir, PathBuf)>,
+    /// The destinations of files with several links, for `preserve_hard_links`.
+    links: HashMap<(u64, u64), PathBuf>,
+    /// Whether files can be reflinked from one device (volume on Windows) into another, as
+    /// probed for the first file of each pair.
+    support: HashMap<(u64, u64), bool>,
+}
+
+impl ReflinkDirIter {
+    pub(crate) fn new(options: ReflinkOptions, from: &Path, to: &Path) -> Self {
+        ReflinkDirIter {
+            options,
+            root: Some((from.to_path_buf(), to.to_path_buf())),
+            stack: Vec::new(),
+            links: HashMap::new(),
+            support: HashMap::new(),
+        }
+    }
+
+    /// Enters the root directory, unless that already happened.
+    fn start(&mut self) -> io::Result<()> {
+        if let Some((from, to)) = self.root.take() {
+            self.enter(&from, to)?;
+        }
+        Ok(())
+    }
+
+    /// Clones the next file, returning its destination path along with the result.
+    fn next_entry(&mut self) -> Option<(PathBuf, io::Result<ClonedEntry>)> {
+        loop {
+            let (entries, dest_dir) = self.stack.last_mut()?;
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => return Some((dest_dir.clone(), Err(e))),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let dest = dest_dir.join(entry.file_name());
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    if let Err(e) = self.enter(&entry.path(), dest.clone()) {
+                        return Some((dest, Err(e)));
+                    }
+                }
+                Ok(_) => {
+                    let res = self.clone_file(&entry.path(), dest.clone());
+                    return Some((dest, res));
+                }
+                Err(e) => return Some((dest, Err(e))),
+            }
+        }
+    }
+
+    /// Creates the destination directory before walking the source directory, so that the
+    /// parents of every file exist once it is cloned.
+    fn enter(&mut self, from: &Path, to: PathBuf) -> io::Result<()> {
+        let entries = fs::read_dir(from)?;
+        fs::create_dir(&to)?;
+        self.stack.push((entries, to));
+        Ok(())
+    }
+
+    /// Clones a single file, or links it to an earlier clone of the same inode.
+    fn clone_file(&mut self, from: &Path, to: PathBuf) -> io::Result<ClonedEntry> {
+        let id = if self.options.preserve_hard_links {
+            sys::hard_link_id(from)?
+        } else {
+            None
+        };
+        if let Some(first) = id.and_then(|id| self.links.get(&id)) {
+            fs::hard_link(first, &to)?;
+            return Ok(ClonedEntry {
+                path: to,
+                mechanism: None,
+            });
+        }
+        let reflink = self.reflink_supported(from, &to);
+        let (_, copied) = self.options.reflink_or_copy_inner(from, &to, reflink)?;
+        if let Some(id) = id {
+            self.links.insert(id, to.clone());
+        }
+        Ok(ClonedEntry {
+            path: to,
+            mechanism: match copied {
+                None => Some(Mechanism::current()),
+                Some(_) => None,
+            },
+        })
+    }
+}
+
+impl ReflinkDirIter {
+    /// Probes once per pair of source and destination devices whether reflinks are possible, so
+    /// that trees on file systems without support are copied without a failed clone per file.
+    ///
+    /// Errors while probing leave the decision to the reflink attempt of each file.
+    fn reflink_supported(&mut self, from: &Path, to: &Path) -> bool {
+        let dest_dir = match to.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        // opening special files to probe them could block, e.g. on FIFOs without a writer
+        if !fs::metadata(from).is_ok_and(|m| m.is_file()) {
+            return true;
+        }
+        let key = match (sys::device_id(from), sys::device_id(dest_dir)) {
+            (Ok(Some(src)), Ok(Some(dest))) => (src, dest),
+            _ => return true,
+        };
+        *self.support.entry(key).or_insert_with(|| {
+            #[cfg(test)]
+            tests::PROBES.with(|probes| probes.set(probes.get() + 1));
+            sys::can_reflink(from, dest_dir).unwrap_or(true)
+        })
+    }
+}
+
+impl Iterator for ReflinkDirIter {
+    type Item = io::Result<ClonedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.start() {
+            return Some(Err(e));
+        }
+        self.next_entry().map(|(_, res)| res)
+    }
+}
+
+pub(crate) fn copy_tree(
+    options: &ReflinkOptions,
+    from: &Path,
+    to: &Path,
+) -> io::Result<TreeReport> {
+    if !fs::metadata(from)?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the source path is not a directory",
+        ));
+    }
+    let mut report = TreeReport::default();
+    // clonefile clones whole directories at once, unless each file must be audited or passed
+    // to the post clone hook
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        if options.audit.is_none()
+            && options.post_clone.is_none()
+            && fs::symlink_metadata(to).is_err()
+            && sys::reflink(from, to, options).is_ok()
+        {
+            count_cloned(to, &mut report)?;
+            return Ok(report);
+        }
+    }
+    let mut iter = ReflinkDirIter::new(options.clone(), from, to);
+    iter.start()?;
+    while let Some((dest, res)) = iter.next_entry() {
+        let res = res.and_then(|entry| Ok((fs::metadata(&entry.path)?.len(), entry.mechanism)));
+        match res {
+            Ok((len, mechanism)) => {
+                match mechanism {
+                    Some(_) => report.reflinked += 1,
+                    None => report.copied += 1,
+                }
+                report.bytes += len;
+            }
+            Err(e) => report.failures.push((dest, e)),
+        }
+    }
+    Ok(report)
+}
+
+/// Counts the files below `dir` as reflinked.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn count_cloned(dir: &Path, report: &mut TreeReport) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            count_cloned(&entry.path(), report)?;
+        } else {
+            report.reflinked += 1;
+            report.bytes += metadata.len();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fs;
+
+    use crate::ReflinkOptions;
+
+    thread_local! {
+        /// The reflink probes of `ReflinkDirIter` on the current thread.
+        pub(super) static PROBES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[test]
+    fn probe_once_per_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        for sub in &["a", "b", "c"] {
+            fs::create_dir_all(src.join(sub)).unwrap();
+            for i in 0..20 {
+                fs::write(src.join(sub).join(format!("{}.txt", i)), b"data").unwrap();
+            }
+        }
+
+        PROBES.with(|probes| probes.set(0));
+        let report = super::copy_tree(&ReflinkOptions::new(), &src, &dir.path().join("dest"));
+        let report = report.unwrap();
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+        assert_eq!(report.reflinked + report.copied, 60);
+        // every file lives on the same file system, unless clonefile cloned the whole tree
+        let whole_tree =
+            report.reflinked == 60 && cfg!(any(target_os = "macos", target_os = "ios"));
+        let expected = if whole_tree || !cfg!(any(unix, windows)) {
+            0
+        } else {
+            1
+        };
+        assert_eq!(PROBES.with(Cell::get), expected);
+        assert_eq!(
+            fs::read(dir.path().join("dest").join("b").join("7.txt")).unwrap(),
+            b"data"
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// Structured information about why a reflink operation failed.
+///
+/// All functions in this crate return `io::Result`, so that their signatures stay compatible
+/// with `std::fs`. When a failure can be classified, the returned `io::Error` carries a
+/// `ReflinkError` as its inner error, which can be retrieved with [`ReflinkError::from_io`].
+///
+/// ```rust
+/// use reflink::{ReflinkError, UnsupportedReason};
+///
+/// if let Err(e) = reflink::reflink("src.txt", "dest.txt") {
+///     match ReflinkError::from_io(&e) {
+///         Some(ReflinkError::Unsupported { reason: UnsupportedReason::CrossDevice, .. }) => {
+///             println!("source and destination are on different file systems")
+///         }
+///         Some(ReflinkError::Unsupported { reason, .. }) => println!("unsupported: {:?}", reason),
+///         _ => println!("error while reflinking: {:?}", e),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub enum ReflinkError {
+    /// The reflink could not be performed, without any fault on the caller's side.
+    /// A conventional copy will most likely succeed.
+    Unsupported {
+        /// Why the reflink is not supported.
+        reason: UnsupportedReason,
+        /// The OS error reported by the platform, if any.
+        source: Option<io::Error>,
+    },
+    /// The file system ran out of space while cloning, e.g. for the metadata of the shared
+    /// extents. A conventional copy would fail as well, so `reflink_or_copy` does not attempt one.
+    OutOfSpace {
+        /// The OS error reported by the platform.
+        source: io::Error,
+    },
+    /// The source was removed after a failed reflink, but before the fallback copy opened it,
+    /// e.g. by another process.
+    SourceVanished {
+        /// The OS error reported by the platform.
+        source: io::Error,
+    },
+    /// The callback set with [`ReflinkOptions::audit`](crate::ReflinkOptions::audit) denied the
+    /// clone, before anything was changed on disk.
+    Aborted,
+    /// The callback set with [`ReflinkOptions::cancel`](crate::ReflinkOptions::cancel) asked to
+    /// stop a clone or copy in progress. The partial destination was removed.
+    Cancelled,
+    /// The clone differs from its source when read back, as checked by
+    /// [`ReflinkOptions::verify`](crate::ReflinkOptions::verify). The destination was removed.
+    VerificationFailed {
+        /// The first byte offset where the files differ, or the size of the shorter file.
+        offset: u64,
+    },
+    /// The source exists, but is not a regular file, so there is nothing to clone.
+    NotRegular {
+        /// What the source is instead.
+        kind: FileKind,
+    },
+}
+
+/// The type of a source which is not a regular file, see [`ReflinkError::NotRegular`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    /// A directory.
+    Directory,
+    /// A symbolic link, e.g. a dangling one on Windows, where links to files are not followed
+    /// when opening the source.
+    Symlink,
+    /// A named pipe (FIFO).
+    Fifo,
+    /// A Unix domain socket.
+    Socket,
+    /// A block device, such as a disk.
+    BlockDevice,
+    /// A character device, such as `/dev/null`.
+    CharDevice,
+    /// Any other file type the platform knows of.
+    Other,
+}
+
+impl FileKind {
+    /// Classifies a file type which is not a regular file.
+    pub(crate) fn of(file_type: fs::FileType) -> FileKind {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return FileKind::Fifo;
+            } else if file_type.is_socket() {
+                return FileKind::Socket;
+            } else if file_type.is_block_device() {
+                return FileKind::BlockDevice;
+            } else if file_type.is_char_device() {
+                return FileKind::CharDevice;
+            }
+        }
+        if file_type.is_dir() {
+            FileKind::Directory
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        }
+    }
+}
+
+/// The reason why a reflink is not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnsupportedReason {
+    /// The operating system does not provide any block cloning mechanism.
+    Os,
+    /// The file system does not support block cloning.
+    Filesystem,
+    /// The clone syscall (or library function) is not available on the running system.
+    Syscall,
+    /// Source and destination reside on different file systems.
+    CrossDevice,
+}
+
+impl ReflinkError {
+    /// Retrieves the `ReflinkError` carried by an `io::Error` returned from this crate, if any.
+    pub fn from_io(err: &io::Error) -> Option<&ReflinkError> {
+        err.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    /// Returns the reason if this error denotes an unsupported operation.
+    pub fn unsupported_reason(&self) -> Option<UnsupportedReason> {
+        match *self {
+            ReflinkError::Unsupported { reason, .. } => Some(reason),
+            ReflinkError::OutOfSpace { .. }
+            | ReflinkError::SourceVanished { .. }
+            | ReflinkError::Aborted
+            | ReflinkError::Cancelled
+            | ReflinkError::VerificationFailed { .. }
+            | ReflinkError::NotRegular { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ReflinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReflinkError::Unsupported { reason, .. } => {
+                write!(f, "reflink not supported: {}", reason)
+            }
+            ReflinkError::OutOfSpace { .. } => f.write_str("not enough space left for the reflink"),
+            ReflinkError::SourceVanished { .. } => {
+                f.write_str("the source was removed before it could be copied")
+            }
+            ReflinkError::Aborted => f.write_str("the reflink was denied by the audit callback"),
+            ReflinkError::Cancelled => f.write_str("the reflink was cancelled"),
+            ReflinkError::VerificationFailed { offset } => {
+                write!(f, "the clone differs from the source at byte {}", offset)
+            }
+            ReflinkError::NotRegular { kind } => {
+                write!(f, "the source is {}, not a regular file", kind)
+            }
+        }
+    }
+}
+
+impl Error for ReflinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReflinkError::Unsupported { source, .. } => {
+                source.as_ref().map(|e| e as &(dyn Error + 'static))
+            }
+            ReflinkError::OutOfSpace { source } | ReflinkError::SourceVanished { source } => {
+                Some(source)
+            }
+            ReflinkError::Aborted
+            | ReflinkError::Cancelled
+            | ReflinkError::VerificationFailed { .. }
+            | ReflinkError::NotRegular { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileKind::Directory => "a directory",
+            FileKind::Symlink => "a symbolic link",
+            FileKind::Fifo => "a FIFO",
+            FileKind::Socket => "a socket",
+            FileKind::BlockDevice => "a block device",
+            FileKind::CharDevice => "a character device",
+            FileKind::Other => "a special file",
+        })
+    }
+}
+
+impl fmt::Display for UnsupportedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnsupportedReason::Os => "the operating system does not support block cloning",
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            UnsupportedReason::Filesystem => {
+                "the file system does not support block cloning, which requires APFS"
+            }
+            #[cfg(windows)]
+            UnsupportedReason::Filesystem => {
+                "the volume does not support block cloning, which requires ReFS or a Dev Drive"
+            }
+            #[cfg(not(any(target_os = "macos", target_os = "ios", windows)))]
+            UnsupportedReason::Filesystem => "the file system does not support block cloning",
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            UnsupportedReason::Syscall => "the running kernel lacks a syscall needed for cloning",
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            UnsupportedReason::Syscall => {
+                "clonefile is not available, it requires macOS 10.12 or iOS 10"
+            }
+            #[cfg(windows)]
+            UnsupportedReason::Syscall => "the running Windows version cannot clone blocks",
+            #[cfg(not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                windows
+            )))]
+            UnsupportedReason::Syscall => "the clone syscall is not available on this system",
+            UnsupportedReason::CrossDevice => {
+                "source and destination are on different file systems"
+            }
+        })
+    }
+}
+
+/// Creates an `io::Error` of kind `Unsupported` carrying a `ReflinkError::Unsupported`.
+pub(crate) fn unsupported(reason: UnsupportedReason, source: Option<io::Error>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        ReflinkError::Unsupported { reason, source },
+    )
+}
+
+/// Creates an `io::Error` of kind `StorageFull` carrying a `ReflinkError::OutOfSpace`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+pub(crate) fn out_of_space(source: io::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::StorageFull,
+        ReflinkError::OutOfSpace { source },
+    )
+}
+
+/// Creates an `io::Error` of kind `NotFound` carrying a `ReflinkError::SourceVanished`.
+pub(crate) fn source_vanished(source: io::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        ReflinkError::SourceVanished { source },
+    )
+}
+
+/// Creates an `io::Error` of kind `PermissionDenied` carrying a `ReflinkError::Aborted`.
+pub(crate) fn aborted() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, ReflinkError::Aborted)
+}
+
+/// Creates an `io::Error` of kind `Other` carrying a `ReflinkError::Cancelled`.
+pub(crate) fn cancelled() -> io::Error {
+    io::Error::other(ReflinkError::Cancelled)
+}
+
+/// Creates an `io::Error` of kind `InvalidData` carrying a `ReflinkError::VerificationFailed`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+pub(crate) fn verification_failed(offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        ReflinkError::VerificationFailed { offset },
+    )
+}
+
+/// Creates an `io::Error` of kind `InvalidInput` carrying a `ReflinkError::NotRegular` for a
+/// source of type `file_type`.
+pub(crate) fn not_regular(file_type: fs::FileType) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        ReflinkError::NotRegular {
+            kind: FileKind::of(file_type),
+        },
+    )
+}
+
+/// Creates an `io::Error` of kind `InvalidInput` explaining that a file handle passed in is
+/// stale, or lacks the access the clone needs.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+pub(crate) fn bad_handle(err: io::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{}; a file handle is closed, or the source is not open for reading or the \
+             destination not for writing",
+            err
+        ),
+    )
+}
+
+/// Whether `err` carries a `ReflinkError::Cancelled`.
+pub(crate) fn is_cancelled(err: &io::Error) -> bool {
+    matches!(ReflinkError::from_io(err), Some(ReflinkError::Cancelled))
+}
+
+/// Whether `err` carries a `ReflinkError::Unsupported`.
+pub(crate) fn is_unsupported(err: &io::Error) -> bool {
+    ReflinkError::from_io(err)
+        .and_then(ReflinkError::unsupported_reason)
+        .is_some()
+}
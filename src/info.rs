@@ -0,0 +1,229 @@
+use std::fmt;
+
+use crate::sys;
+
+/// The block cloning mechanism used to reflink files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mechanism {
+    /// The `FICLONE` ioctl on Linux and Android.
+    Ficlone,
+    /// The `clonefile` function on OS X and ios.
+    Clonefile,
+    /// The `FSCTL_DUPLICATE_EXTENTS_TO_FILE` ioctl on Windows.
+    DuplicateExtents,
+    /// No block cloning mechanism is available on this platform.
+    None,
+}
+
+impl Mechanism {
+    /// The mechanism compiled in for the current target.
+    pub const fn current() -> Mechanism {
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            Mechanism::Ficlone
+        } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+            Mechanism::Clonefile
+        } else if cfg!(windows) {
+            Mechanism::DuplicateExtents
+        } else {
+            Mechanism::None
+        }
+    }
+
+    /// Whether a clone made with this mechanism is complete when the call returns.
+    ///
+    /// `FICLONE`, `clonefile` and `FSCTL_DUPLICATE_EXTENTS_TO_FILE` only return once the shared
+    /// extents are referenced by the destination, even if the file system updates its reference
+    /// counts lazily. The asynchronous `FSCTL_DUPLICATE_EXTENTS_TO_FILE_EX` is not used. `false`
+    /// for [`Mechanism::None`], which never clones.
+    ///
+    /// Whether the clone is on disk yet is another matter, see
+    /// [`ReflinkOptions::durable`](crate::ReflinkOptions::durable).
+    pub const fn is_synchronous(&self) -> bool {
+        !matches!(self, Mechanism::None)
+    }
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mechanism::Ficlone => "ioctl FICLONE",
+            Mechanism::Clonefile => "clonefile",
+            Mechanism::DuplicateExtents => "ioctl FSCTL_DUPLICATE_EXTENTS_TO_FILE",
+            Mechanism::None => "none",
+        })
+    }
+}
+
+/// A version of an operating system, or of the Linux kernel.
+///
+/// Versions compare component by component, e.g. `10.12.0 < 10.13.0 < 11.0.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OsVersion {
+    /// The major version.
+    pub major: u32,
+    /// The minor version.
+    pub minor: u32,
+    /// The patch level, or the build number on Windows.
+    pub patch: u32,
+}
+
+impl OsVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> OsVersion {
+        OsVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses the leading `major[.minor[.patch]]` of a version string, ignoring anything after
+    /// it, e.g. `5.15.90.1-microsoft-standard-WSL2` or `6.8.0-rc1`.
+    fn parse(version: &str) -> Option<OsVersion> {
+        let end = version
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(version.len());
+        let mut numbers = version[..end].split('.');
+        let major = numbers.next()?.parse().ok()?;
+        // missing components count as 0, e.g. macOS reports `14.0` as `14`
+        let mut next = || numbers.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+        let minor = next();
+        let patch = next();
+        Some(OsVersion::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Returns the oldest operating system version providing the clone mechanism of this platform,
+/// or `None` if there is none.
+///
+/// * Linux / Android: kernel 4.5, which made `FICLONE` available to all file systems.
+/// * OS X: 10.12 (Sierra), which introduced `clonefile`.
+/// * ios: 10.0.
+/// * Windows: 10.0.14393 (Windows Server 2016 and Windows 10 1607), whose ReFS supports block
+///   cloning.
+pub const fn min_os_version() -> Option<OsVersion> {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        Some(OsVersion::new(4, 5, 0))
+    } else if cfg!(target_os = "macos") {
+        Some(OsVersion::new(10, 12, 0))
+    } else if cfg!(target_os = "ios") {
+        Some(OsVersion::new(10, 0, 0))
+    } else if cfg!(windows) {
+        Some(OsVersion::new(10, 0, 14393))
+    } else {
+        None
+    }
+}
+
+/// Returns the version of the running operating system (the kernel release on Linux and
+/// Android), if it can be determined.
+pub fn os_version() -> Option<OsVersion> {
+    sys::os_version().as_deref().and_then(OsVersion::parse)
+}
+
+/// Checks whether the running operating system is recent enough to provide the clone mechanism,
+/// e.g. to hide features which depend on reflinks.
+///
+/// This says nothing about the file systems, which need to support cloning as well, see
+/// [`reflink_supported`](crate::reflink_supported). If the running version cannot be
+/// determined, this optimistically returns `true` on platforms with a clone mechanism.
+///
+/// ```rust
+/// if !reflink::runtime_supported() {
+///     println!("reflinks need at least {:?}", reflink::min_os_version());
+/// }
+/// ```
+pub fn runtime_supported() -> bool {
+    match (min_os_version(), os_version()) {
+        (Some(min), Some(running)) => running >= min,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// Describes what this build of the library supports, e.g. for "about" screens or diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VersionInfo {
+    /// The version of this crate.
+    pub version: &'static str,
+    /// The target operating system, as in `std::env::consts::OS`.
+    pub os: &'static str,
+    /// The target architecture, as in `std::env::consts::ARCH`.
+    pub arch: &'static str,
+    /// The clone mechanism compiled in.
+    pub mechanism: Mechanism,
+    /// The enabled optional cargo features.
+    pub features: &'static [&'static str],
+}
+
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "reflink {} ({}-{}, {})",
+            self.version, self.os, self.arch, self.mechanism
+        )?;
+        if !self.features.is_empty() {
+            write!(f, " with {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns information about the capabilities of this build.
+///
+/// ```rust
+/// let info = reflink::version_info();
+/// println!("reflink {} on {}: {}", info.version, info.os, info.mechanism);
+/// ```
+pub fn version_info() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        mechanism: Mechanism::current(),
+        features: &[
+            #[cfg(feature = "tempfile")]
+            "tempfile",
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OsVersion;
+
+    #[test]
+    fn parse_os_versions() {
+        let parse = |version| OsVersion::parse(version);
+        assert_eq!(parse("6.1.0-18-amd64"), Some(OsVersion::new(6, 1, 0)));
+        assert_eq!(
+            parse("5.15.90.1-microsoft-standard-WSL2"),
+            Some(OsVersion::new(5, 15, 90))
+        );
+        assert_eq!(
+            parse("4.4.0-19041-Microsoft"),
+            Some(OsVersion::new(4, 4, 0))
+        );
+        assert_eq!(parse("6.8-rc1"), Some(OsVersion::new(6, 8, 0)));
+        assert_eq!(parse("14"), Some(OsVersion::new(14, 0, 0)));
+        assert_eq!(parse("10.0.22631"), Some(OsVersion::new(10, 0, 22631)));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("Darwin"), None);
+    }
+
+    #[test]
+    fn compare_os_versions() {
+        assert!(OsVersion::new(10, 12, 0) < OsVersion::new(10, 13, 0));
+        assert!(OsVersion::new(10, 13, 6) < OsVersion::new(11, 0, 0));
+        assert!(OsVersion::new(4, 4, 302) < OsVersion::new(4, 5, 0));
+        assert!(OsVersion::new(10, 0, 14393) <= OsVersion::new(10, 0, 14393));
+        assert_eq!(OsVersion::new(6, 1, 0).to_string(), "6.1.0");
+    }
+}
@@ -9,17 +9,93 @@
 //! At the moment Linux, Android, OSX, ios and Windows are supported.
 //! As soon as other OS support the functionality, support will be added.
 
+mod audit;
+mod dir;
+mod error;
+mod info;
+mod observer;
+mod options;
+mod source;
+mod steps;
 mod sys;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+mod verify;
 
-use std::fs;
-use std::io;
-use std::path::Path;
+pub use crate::audit::AuditRecord;
+pub use crate::dir::{ClonedEntry, ReflinkDirIter, TreeReport};
+pub use crate::error::{FileKind, ReflinkError, UnsupportedReason};
+pub use crate::info::{
+    min_os_version, os_version, runtime_supported, version_info, Mechanism, OsVersion, VersionInfo,
+};
+pub use crate::observer::ReflinkObserver;
+pub use crate::options::{Conflict, CopyMethod, CostEstimate, ReflinkOptions, ShareStats, Sharing};
+pub use crate::source::ReflinkSource;
+pub use crate::steps::{ReflinkCopyState, StepResult};
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+pub use crate::verify::VerifyLevel;
+
+/// Re-exports the functions and types needed for most uses of this crate.
+///
+/// ```rust
+/// use reflink::prelude::*;
+///
+/// match ReflinkOptions::new().on_conflict(Conflict::Suffix).reflink("src.txt", "dest.txt") {
+///     Ok(()) => println!("file has been reflinked using {}", Mechanism::current()),
+///     Err(e) => match ReflinkError::from_io(&e) {
+///         Some(err) => println!("{}", err),
+///         None => println!("error while reflinking: {:?}", e),
+///     },
+/// }
+/// ```
+pub mod prelude {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub use crate::VerifyLevel;
+    pub use crate::{
+        reflink, reflink_or_copy, try_reflink, Conflict, CopyMethod, Mechanism, ReflinkError,
+        ReflinkOptions, ShareStats, Sharing, UnsupportedReason,
+    };
+}
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Copies a file using COW semantics.
 ///
 /// For compatibility reasons with macos, the target file will be created using `OpenOptions::create_new`.
 /// If you want to overwrite existing files, make sure you manually delete the target file first
-/// if it exists.
+/// if it exists, or use [`ReflinkOptions::on_conflict`]. On Linux, Android and Windows,
+/// [`ReflinkOptions::exclusive_create`] lifts this restriction.
+///
+/// A destination ending in a path separator, such as `backups/` (or `backups\` on Windows),
+/// names an existing directory: the clone is created in it under the file name of the source,
+/// the same way on every platform. The same holds for the destinations of
+/// [`reflink_or_copy`], [`ReflinkSource::clone_to`] and the other functions taking a source path,
+/// but not for [`reflink_fanout`], whose open source has no name.
+///
+/// This function never falls back to copying the data, so it never does anything expensive. If
+/// the reflink is not possible, no destination file is left behind and the error tells why (see
+/// [Errors](#errors)), so callers can decide for themselves whether to copy, e.g. with
+/// [`reflink_or_copy`].
 ///
 /// ```rust
 /// use reflink;
@@ -31,22 +107,74 @@ use std::path::Path;
 /// # Implementation details per platform
 /// ## Linux / Android
 /// Uses `ioctl_ficlone`. Supported file systems include btrfs and XFS (and maybe more in the future).
+///
+/// Whether source and destination are on the same file system is decided on the opened files
+/// (`st_dev`), never by comparing paths. Bind mounts of one file system therefore count as the
+/// same file system; kernels before 5.18 still refuse to clone across mounts, which is reported
+/// as [`UnsupportedReason::Syscall`] rather than [`UnsupportedReason::CrossDevice`].
+///
+/// `FICLONE` clones the whole file in one step. The kernel locks both inodes and writes back the
+/// dirty pages of the source before sharing its extents, so every `write` to the source by
+/// another process lands entirely before or entirely after the clone, and the clone never holds
+/// part of one. No locking is needed on top. A writer issuing several writes can still be caught
+/// in between them, as with any reader. This does not hold for the ZFS clones through
+/// `copy_file_range` described below, which may take several calls, nor for the copies made by
+/// [`reflink_or_copy`].
+///
+/// tmpfs, which holds `/dev/shm` and the files of `memfd_create`, has no copy on write. Clones
+/// from it fail with [`UnsupportedReason::Filesystem`] and a message saying so, or with
+/// [`UnsupportedReason::CrossDevice`] if the destination is on another file system.
+///
+/// OpenZFS 2.2 and later clone blocks through `copy_file_range` if the `zfs_bclone_enabled`
+/// module parameter is set, but not every release and kernel supports `FICLONE`. If `FICLONE`
+/// is not supported and both files are on ZFS with block cloning enabled, `copy_file_range` is
+/// used instead; without block cloning, ZFS clones fail as before rather than copy. OpenZFS
+/// silently copies blocks it cannot clone, such as blocks of the source written in the current
+/// transaction group, and does not tell which, so these clones are reported as
+/// [`Sharing::Shared`].
 /// ## OS X / ios
 /// Uses `clonefile` library function. This is supported on OS X Version >=10.12 and iOS version >= 10.0
 /// This will work on APFS partitions (which means most desktop systems are capable).
+///
+/// `clonefile` copies the BSD file flags (`chflags`, e.g. `hidden` or `uchg`), extended attributes
+/// and timestamps along with the data. The `CLONE_NOOWNERCOPY` flag used by this crate only
+/// affects the owner and group of the clone, not the file flags; see
+/// [`ReflinkOptions::preserve_ownership`] to drop it.
+///
+/// Resource forks are stored as the `com.apple.ResourceFork` extended attribute, so clones keep
+/// them with or without `CLONE_NOOWNERCOPY`. The copies made by [`reflink_or_copy`] keep the
+/// resource fork as well, including sparse ones, but not those made in chunks for
+/// [`ReflinkOptions::cancel`].
+///
+/// Since macOS 10.15 the read-only system volume and the data volume are separate APFS volumes
+/// which firmlinks join into one directory tree. Cloning between them fails with
+/// [`UnsupportedReason::CrossDevice`] and a message naming the volume split.
 /// ## Windows
 /// Uses ioctl `FSCTL_DUPLICATE_EXTENTS_TO_FILE`.
 /// Only supports ReFS on Windows Server. *Important note*: The windows implementation is currently
 /// untested and probably buggy. Contributions/testers with access to a Windows Server welcome.
+///
+/// Files of 4 GiB and more are cloned in chunks, so that writes to the source while cloning may
+/// show up in some chunks of the clone but not in others.
+///
+/// The volume is checked through the opened destination handle, so a destination behind a
+/// junction or volume mount point is judged by the volume it resolves to.
+///
+/// # Errors
+/// If the platform or file system cannot reflink the given files, an error of kind
+/// `io::ErrorKind::Unsupported` is returned, carrying a [`ReflinkError::Unsupported`] with the
+/// [`UnsupportedReason`]. Use [`ReflinkError::from_io`] to inspect it.
+///
+/// If the file system runs out of space while cloning, an error of kind
+/// `io::ErrorKind::StorageFull` carrying a [`ReflinkError::OutOfSpace`] is returned.
+///
+/// If the source is not an existing regular file, an error of kind `io::ErrorKind::InvalidInput`
+/// is returned. For sources which exist, such as directories, FIFOs, sockets and devices, it
+/// carries a [`ReflinkError::NotRegular`] naming the [`FileKind`].
+/// The source is opened once, checked on the open handle and cloned through it, so replacing or
+/// removing it in the meantime cannot make the clone read another file.
 pub fn reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
-    let (from, to) = (from.as_ref(), to.as_ref());
-    if !from.is_file() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "the source path is not an existing regular file",
-        ));
-    }
-    sys::reflink(from, to)
+    ReflinkOptions::new().reflink(from, to)
 }
 
 /// Attempts to reflink a file. If the operation fails, a conventional copy operation is
@@ -56,6 +184,26 @@ pub fn reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()>
 ///
 /// If the function copied a file, the return value will be `Ok(Some(written))`.
 ///
+/// On Windows, large files are cloned in chunks of up to 4 GiB. If a chunk fails after others
+/// were cloned, the cloned chunks are kept and only the rest of the file is copied, so the
+/// result is `Ok(Some(written))` with the number of bytes copied.
+///
+/// No copy is attempted if the source is not an existing regular file, since the copy would
+/// fail the same way; the error of the reflink attempt is returned instead.
+///
+/// If the source is removed after the reflink attempt, but before it could be copied, an error of
+/// kind `io::ErrorKind::NotFound` carrying a [`ReflinkError::SourceVanished`] is returned.
+///
+/// On Linux and Android, copies between NFS mounts use `copy_file_range`, which NFSv4.2 servers
+/// perform themselves (server-side `COPY`) without sending the data through the client. Likewise
+/// on Windows, copies between files on SMB shares are made by the server with
+/// `FSCTL_SRV_COPYCHUNK_WRITE` if it supports it, since block cloning does not work over SMB.
+///
+/// Sparse sources, such as disk images, are copied sparsely: only their data is written and the
+/// holes stay holes, rather than being filled with zeros. See
+/// [`ReflinkOptions::preserve_sparseness`] for how they are detected. `written` still counts the
+/// holes.
+///
 /// ```rust
 /// use reflink;
 /// match reflink::reflink_or_copy("src.txt", "dest.txt") {
@@ -65,9 +213,551 @@ pub fn reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()>
 /// }
 /// ```
 pub fn reflink_or_copy<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<Option<u64>> {
-    if let Ok(()) = reflink(&from, &to) {
-        Ok(None)
-    } else {
-        fs::copy(from, to).map(Some)
+    ReflinkOptions::new().reflink_or_copy(from, to)
+}
+
+/// Attempts to reflink a file, telling apart a reflink which is not supported from other errors.
+///
+/// Returns `Ok(true)` if the file has been reflinked, and `Ok(false)` if the platform, file
+/// system or the pair of files does not support reflinks (an error of [`reflink`] carrying a
+/// [`ReflinkError::Unsupported`]). In that case no destination is left behind, and the caller
+/// may copy the file or carry on without it. Any other failure, such as a missing source or an
+/// existing destination, is returned as an error. Unlike [`reflink_or_copy`], nothing is ever
+/// copied.
+///
+/// ```rust
+/// match reflink::try_reflink("src.txt", "dest.txt") {
+///     Ok(true) => println!("file has been reflinked"),
+///     Ok(false) => println!("reflinks are not supported here"),
+///     Err(e) => println!("error while reflinking: {:?}", e),
+/// }
+/// ```
+pub fn try_reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<bool> {
+    ReflinkOptions::new().try_reflink(from, to)
+}
+
+/// Reflinks a file with all of its metadata, like `cp --reflink=always --preserve=all`.
+///
+/// Fails like [`reflink`] if the file cannot be reflinked; nothing is ever copied. Besides the
+/// data, the clone gets the metadata of the source which the platform can preserve:
+/// * Linux / Android: the permissions, the access and modification times and the extended
+///   attributes, which include the POSIX ACLs and the security labels, as well as the owner and
+///   group as far as the caller is allowed to change them, like `cp` does.
+/// * OS X / ios: everything `clonefile` copies (permissions, timestamps, extended attributes,
+///   ACLs and BSD file flags), and the owner and group for root callers.
+/// * Windows: the creation, access and modification times. The security descriptor is not
+///   copied, so the clone inherits the ACLs of its directory.
+///
+/// This is a shortcut for [`ReflinkOptions::preserve_metadata`] and
+/// [`ReflinkOptions::preserve_ownership`] (where available).
+///
+/// ```rust
+/// match reflink::reflink_preserve_all("src.txt", "dest.txt") {
+///     Ok(()) => println!("file has been reflinked with its metadata"),
+///     Err(e) => println!("error while reflinking: {:?}", e),
+/// }
+/// ```
+pub fn reflink_preserve_all<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let mut options = ReflinkOptions::new();
+    options.preserve_metadata(true);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    options.preserve_ownership(true);
+    options.reflink(from, to)
+}
+
+/// Like [`reflink`], but reports whether the data blocks are actually shared with the source.
+///
+/// Some mechanisms may succeed by physically duplicating the data, which gives none of the space
+/// savings of a reflink. Those report [`Sharing::Duplicated`]. The native clone calls used on
+/// Linux, Android, OS X, ios and Windows always share the blocks and report [`Sharing::Shared`],
+/// as do clones on ZFS, for which OpenZFS does not tell (see [`reflink`]).
+///
+/// ```rust
+/// use reflink::Sharing;
+///
+/// match reflink::reflink_reported("src.txt", "dest.txt") {
+///     Ok(Sharing::Shared) => println!("file has been reflinked"),
+///     Ok(Sharing::Duplicated) => println!("file has been physically copied"),
+///     Err(e) => println!("error while reflinking: {:?}", e)
+/// }
+/// ```
+pub fn reflink_reported<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<Sharing> {
+    ReflinkOptions::new().reflink_reported(from, to)
+}
+
+/// Checks whether `from` can be reflinked to `to`, without creating `to`.
+///
+/// Returns `Ok(false)` if `from` is not a regular file, `to` already exists, the two are on
+/// different file systems, or the file system cannot clone. Errors are returned if the files
+/// cannot be inspected, e.g. because the parent directory of `to` does not exist. Like for
+/// [`reflink`], a `to` ending in a path separator names the directory to clone into.
+///
+/// ```rust,no_run
+/// if reflink::can_reflink("src.txt", "dest.txt")? {
+///     reflink::reflink("src.txt", "dest.txt")?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// Clones the first block of `from` into an unnamed `O_TMPFILE` file next to `to`, which
+/// vanishes right away. On ZFS, checks that block cloning is enabled instead.
+/// ## OS X / ios
+/// Checks that both are on the same APFS volume.
+/// ## Windows
+/// Checks that both are on the same volume, which supports block cloning
+/// (`FILE_SUPPORTS_BLOCK_REFCOUNTING`). Junctions and volume mount points are resolved.
+pub fn can_reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<bool> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let in_dir = options::dest_in_dir(from, to)?;
+    let to = in_dir.as_deref().unwrap_or(to);
+    if !from.is_file() || std::fs::symlink_metadata(to).is_ok() {
+        return Ok(false);
+    }
+    let dest_dir = match to.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    sys::can_reflink(from, dest_dir)
+}
+
+/// Estimates what [`reflink_or_copy`] from `from` to `to` would cost, without creating `to`.
+///
+/// The estimate tells whether the file can be cloned, which takes about the same short time
+/// regardless of its size, or would be copied, which takes time proportional to its size, and
+/// includes that size. A progress UI can use it to decide whether to show a progress bar.
+///
+/// Whether the file can be cloned is checked as by [`can_reflink`], except that an existing `to`
+/// is judged by its directory, as if it were to be replaced, and a `to` ending in a path
+/// separator names the directory to clone into. An error is returned if `from` is not a regular
+/// file, or if the files cannot be inspected.
+///
+/// ```rust,no_run
+/// let estimate = reflink::estimate_cost("disk.img", "copy.img")?;
+/// if !estimate.reflink {
+///     println!("copying {} bytes, this may take a while", estimate.size);
+/// }
+/// reflink::reflink_or_copy("disk.img", "copy.img")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn estimate_cost<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<CostEstimate> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let in_dir = options::dest_in_dir(from, to)?;
+    let to = in_dir.as_deref().unwrap_or(to);
+    let metadata = std::fs::metadata(from)?;
+    if !metadata.is_file() {
+        return Err(error::not_regular(metadata.file_type()));
+    }
+    let dest_dir = match to.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    Ok(CostEstimate {
+        reflink: sys::can_reflink(from, dest_dir)?,
+        size: metadata.len(),
+    })
+}
+
+/// Clones the directory tree `from` into the new directory `to`, lazily.
+///
+/// Each call to `next` on the returned iterator clones one file (falling back to a copy, as
+/// [`reflink_or_copy`] does) and yields its destination, or the error for that entry. Directories
+/// are created before their contents are cloned; they are not yielded themselves. Iteration
+/// continues after an error, so callers may collect the failures and carry on.
+///
+/// Whether files can be reflinked is probed once per pair of source and destination file
+/// systems (volumes on Windows), like [`can_reflink`] does. Files of pairs without support are
+/// copied right away, without attempting a reflink each.
+///
+/// ```rust,no_run
+/// for entry in reflink::reflink_dir_iter("src", "dest") {
+///     match entry {
+///         Ok(entry) => println!("{} ({:?})", entry.path.display(), entry.mechanism),
+///         Err(e) => println!("error while cloning: {}", e),
+///     }
+/// }
+/// ```
+pub fn reflink_dir_iter<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> ReflinkDirIter {
+    ReflinkOptions::new().reflink_dir_iter(from, to)
+}
+
+/// Clones the directory tree `from` into the new directory `to` and summarizes the result.
+///
+/// Files which cannot be cloned do not stop the walk; they are listed in
+/// [`TreeReport::failures`] instead. An error is only returned if `from` is not a directory or
+/// `to` cannot be created.
+///
+/// ```rust,no_run
+/// use reflink::ReflinkOptions;
+///
+/// let report = reflink::copy_tree("src", "dest", &ReflinkOptions::new())?;
+/// println!("{} reflinked, {} copied", report.reflinked, report.copied);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## OS X / ios
+/// Clones the whole tree with a single `clonefile` call if possible, which reports every file
+/// as reflinked and ignores all options but `macos_raw_flags`. Otherwise,
+/// e.g. across volumes, the tree is walked as on the other platforms.
+/// ## Others
+/// Walks the tree like [`ReflinkOptions::reflink_dir_iter`], falling back to a copy per file.
+pub fn copy_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    options: &ReflinkOptions,
+) -> io::Result<TreeReport> {
+    dir::copy_tree(options, from.as_ref(), to.as_ref())
+}
+
+/// Reflinks the open file `source` to each of `targets`, e.g. to share data written once among
+/// many files.
+///
+/// The source handle is reused for all targets instead of opening the source again for every
+/// clone. Each target is handled like a destination of [`reflink`], and gets its own result in
+/// the returned `Vec`, in the order of `targets`; a failure does not stop the remaining clones.
+///
+/// ```rust,no_run
+/// use std::fs::File;
+/// use std::io::Write;
+/// use std::path::Path;
+///
+/// let mut base = File::create("base.bin")?;
+/// base.write_all(&[0; 4096])?;
+/// base.sync_all()?;
+/// let targets = [Path::new("a.bin"), Path::new("b.bin"), Path::new("c.bin")];
+/// for result in reflink::reflink_fanout(&File::open("base.bin")?, &targets) {
+///     result?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## OS X / ios
+/// Uses `fclonefileat`, which clones from the file descriptor.
+pub fn reflink_fanout(source: &File, targets: &[&Path]) -> Vec<io::Result<()>> {
+    ReflinkOptions::new().reflink_fanout(source, targets)
+}
+
+/// Checks whether files in the directory `dir` can be reflinked.
+///
+/// This probes the file system by reflinking a small file within `dir`; both probe files are
+/// removed afterwards. Their names are unique among processes and calls, so concurrent probes
+/// of the same directory do not interfere, and probe files left behind by a crash are skipped.
+/// Returns `Ok(false)` if the reflink is not supported, and an error if the probe files cannot
+/// be created (e.g. `dir` is not writable).
+///
+/// ```rust,no_run
+/// if reflink::reflink_supported(".")? {
+///     println!("reflinks are supported in the current directory");
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// Windows drives mounted into WSL (drvfs) never support reflinks; errors mention this.
+pub fn reflink_supported<P: AsRef<Path>>(dir: P) -> io::Result<bool> {
+    static PROBES: AtomicUsize = AtomicUsize::new(0);
+
+    let (src, dest) = loop {
+        let name = format!(
+            ".reflink-probe-{}.{}",
+            std::process::id(),
+            PROBES.fetch_add(1, Ordering::Relaxed)
+        );
+        let src = dir.as_ref().join(&name);
+        let dest = dir.as_ref().join(name + ".clone");
+        if std::fs::symlink_metadata(&dest).is_ok() {
+            // left behind by a crashed probe
+            continue;
+        }
+        let probe = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&src);
+        match probe {
+            Ok(mut probe) => {
+                if let Err(e) = probe.write_all(b"reflink") {
+                    let _ = std::fs::remove_file(&src);
+                    return Err(e);
+                }
+                break (src, dest);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    let res = ReflinkOptions::new().reflink(&src, &dest);
+    let _ = std::fs::remove_file(&src);
+    let _ = std::fs::remove_file(&dest);
+    match res {
+        Ok(()) => Ok(true),
+        Err(ref e) if error::is_unsupported(e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reflinks `from` to the new file `to`, both relative to the directory `dir` and confined to it.
+///
+/// Both paths are resolved with `openat2` and `RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS`, so
+/// absolute paths, `..` components leaving `dir` and symlinks anywhere in the paths are
+/// rejected with `io::ErrorKind::PermissionDenied`. This makes it safe to clone untrusted,
+/// user supplied paths within a sandbox directory. Like [`reflink`], the destination must not
+/// exist yet and is removed if the clone fails.
+///
+/// `openat2` needs Linux 5.6 or newer; older kernels fail with [`UnsupportedReason::Syscall`].
+///
+/// ```rust,no_run
+/// use std::fs::File;
+///
+/// let sandbox = File::open("/srv/uploads")?;
+/// reflink::reflink_beneath(&sandbox, "user/src.txt", "user/dest.txt")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn reflink_beneath<P: AsRef<Path>, Q: AsRef<Path>>(
+    dir: &File,
+    from: P,
+    to: Q,
+) -> io::Result<()> {
+    sys::reflink_beneath(dir, from.as_ref(), to.as_ref())
+}
+
+/// Reflinks `src` into a new, unnamed file in the directory `dir`, opened with `O_TMPFILE`.
+///
+/// The returned file has no name until the caller links it into place with
+/// `linkat(AT_FDCWD, "/proc/self/fd/<fd>", AT_FDCWD, path, AT_SYMLINK_FOLLOW)`, which makes for
+/// fully atomic reflinks on Linux. If it is never linked, the file vanishes once closed.
+///
+/// Linking is optional: the handle is open for reading and writing, so the clone can serve as a
+/// private snapshot which never appears under any name, or be passed to another process over a
+/// Unix socket.
+///
+/// `dir` must be the open destination directory, and the file system must support both
+/// `O_TMPFILE` and `ioctl_ficlone`.
+///
+/// ```rust,no_run
+/// use std::fs::File;
+///
+/// let src = File::open("src.txt")?;
+/// let dir = File::open(".")?;
+/// let tmp = reflink::reflink_to_tmpfile_fd(&src, &dir)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn reflink_to_tmpfile_fd(src: &File, dir: &File) -> io::Result<File> {
+    sys::reflink_to_tmpfile(src, dir)
+}
+
+/// Like [`reflink`], but measures how much of the destination's data is physically shared with
+/// the source after cloning.
+///
+/// A fresh clone shares all of it. The numbers differ once either file is modified, or if the
+/// file system stores small files inline in its metadata, which is never shared. If the
+/// measurement fails, the error is returned and the destination is kept.
+///
+/// ```rust,no_run
+/// let stats = reflink::reflink_accounted("src.txt", "dest.txt")?;
+/// println!("{} of {} bytes shared", stats.shared_bytes, stats.total_bytes);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// Intersects the physical extents reported by `FS_IOC_FIEMAP`.
+/// ## OS X / ios
+/// Not supported, fails with [`UnsupportedReason::Os`] after cloning.
+/// ## Windows
+/// Intersects the cluster runs reported by `FSCTL_GET_RETRIEVAL_POINTERS`.
+pub fn reflink_accounted<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<ShareStats> {
+    ReflinkOptions::new().reflink_accounted(from, to)
+}
+
+/// Checks whether two files share all their data blocks, e.g. to verify that a clone did not
+/// silently end up as a full copy.
+///
+/// Returns `false` for files without any blocks, such as empty or entirely sparse files.
+///
+/// ```rust,no_run
+/// use std::fs::File;
+///
+/// reflink::reflink("src.txt", "dest.txt")?;
+/// assert!(reflink::extents_shared(&File::open("src.txt")?, &File::open("dest.txt")?)?);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// Compares the physical extents reported by `FS_IOC_FIEMAP`. Data stored inline in the file
+/// system metadata (small files on btrfs) is never shared.
+/// ## OS X / ios
+/// Not supported, fails with [`UnsupportedReason::Os`].
+/// ## Windows
+/// Compares the cluster runs reported by `FSCTL_GET_RETRIEVAL_POINTERS`.
+pub fn extents_shared(a: &File, b: &File) -> io::Result<bool> {
+    sys::extents_shared(a, b)
+}
+
+/// Returns the alignment required for offsets and lengths passed to [`reflink_range`].
+///
+/// ```rust,no_run
+/// let src = std::fs::File::open("src.img")?;
+/// let align = reflink::clone_alignment(&src)?;
+/// let len = 1_000_000 / align * align; // round down to a multiple of the alignment
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// The file system block size (`f_bsize` of `fstatvfs`). Unaligned `ioctl_ficlonerange` calls
+/// fail with `EINVAL`.
+/// ## OS X / ios
+/// Range cloning is not supported, so this fails with [`UnsupportedReason::Os`].
+/// ## Windows
+/// The ReFS cluster size, as reported by `FSCTL_GET_INTEGRITY_INFORMATION`.
+pub fn clone_alignment(file: &File) -> io::Result<u64> {
+    sys::clone_alignment(file)
+}
+
+/// Reflinks `len` bytes of `src`, starting at `src_offset`, into `dest` at `dest_offset`.
+///
+/// `dest` must be opened for writing. Unlike [`reflink`], the destination file is neither created
+/// nor removed on failure; existing data in the destination range is replaced.
+///
+/// ```rust,no_run
+/// use std::fs::{File, OpenOptions};
+///
+/// let src = File::open("src.img")?;
+/// let dest = OpenOptions::new().write(true).open("dest.img")?;
+/// // clone the second 64K block of src to the beginning of dest
+/// reflink::reflink_range(&src, 64 * 1024, &dest, 0, 64 * 1024)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// Uses `ioctl_ficlonerange`. Offsets and length must be multiples of the file system block
+/// size (see [`clone_alignment`]), except that the range may end at the end of the source file.
+/// A length of 0 clones everything up to the end of the source file. The alignment is checked
+/// before cloning: violations yield an `InvalidInput` error naming the offending value and the
+/// block size.
+/// A closed descriptor, a source not open for reading or a destination not open for writing
+/// (`EBADF`) yields an `InvalidInput` error saying so.
+/// ## OS X / ios
+/// `clonefile` can only clone whole files, so this always fails with
+/// [`UnsupportedReason::Os`].
+/// ## Windows
+/// Uses ioctl `FSCTL_DUPLICATE_EXTENTS_TO_FILE`. Offsets and length must be multiples of the
+/// cluster size of the volume (4K or 64K on ReFS), even at the end of the source file, otherwise
+/// an `InvalidInput` error naming the offending value and the cluster size is returned.
+/// The destination must already be large enough to hold the range. The ioctl needs
+/// `FILE_READ_DATA` access to `src` and `FILE_WRITE_DATA` access to `dest`, which `File::open`
+/// and `OpenOptions::write` grant; otherwise it fails with `PermissionDenied`.
+/// A closed handle (`ERROR_INVALID_HANDLE`) yields an `InvalidInput` error.
+pub fn reflink_range(
+    src: &File,
+    src_offset: u64,
+    dest: &File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    sys::reflink_range(src, src_offset, dest, dest_offset, len)
+}
+
+/// Feeds the contents of a file into `hasher` and returns it, e.g. to key a deduplicating store.
+///
+/// Any hasher implementing `io::Write` can be used, such as the ones of the `sha2` or `blake3`
+/// crates. The file is read in large chunks, bypassing `BufReader`.
+///
+/// ```rust,ignore
+/// use sha2::{Digest, Sha256};
+///
+/// let digest = reflink::hash_file("src.txt", Sha256::new())?.finalize();
+/// ```
+pub fn hash_file<P: AsRef<Path>, W: Write>(path: P, mut hasher: W) -> io::Result<W> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(hasher),
+            Ok(n) => hasher.write_all(&buf[..n])?,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reflinks a file into a content-addressed store, at a path derived from the hash of its
+/// contents, and returns that path.
+///
+/// The contents of `from` are fed into `hasher` as by [`hash_file`], then `dest` turns the
+/// hasher into the destination path, e.g. by finalizing it and joining the hex digest to the
+/// store directory. The file is then reflinked there, falling back to a copy, as by
+/// [`reflink_or_copy`]. Build systems and package managers keep their artifacts like this.
+///
+/// Hashing reads the whole source, so even when the clone itself is nearly free, the call takes
+/// about as long as reading the file. The source is read again by a copy fallback. A source
+/// modified between hashing and cloning ends up under the hash of its old contents.
+///
+/// If the store already holds the content, its path is returned right away and the entry is left
+/// alone, whatever the [`Conflict`] policy, so that clones and hard links of it stay intact.
+///
+/// ```rust,ignore
+/// use sha2::{Digest, Sha256};
+///
+/// let path = reflink::reflink_or_copy_by_hash("build/app.bin", Sha256::new(), |hasher| {
+///     let digest = hasher.finalize();
+///     let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+///     std::path::Path::new("store").join(hex)
+/// })?;
+/// ```
+pub fn reflink_or_copy_by_hash<P, W, F>(from: P, hasher: W, dest: F) -> io::Result<PathBuf>
+where
+    P: AsRef<Path>,
+    W: Write,
+    F: FnOnce(W) -> PathBuf,
+{
+    ReflinkOptions::new().reflink_or_copy_by_hash(from, hasher, dest)
+}
+
+/// Reflinks a file into a new, uniquely named temporary file in `dir`.
+///
+/// The returned `NamedTempFile` is removed when dropped, unless the caller persists it, which
+/// makes it easy to atomically move the clone into place:
+///
+/// ```rust,no_run
+/// let tmp = reflink::reflink_to_tempfile("src.txt", ".")?;
+/// tmp.persist("dest.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// Pass the parent directory of the final destination as `dir`. Persisting renames the file,
+/// which fails with a cross-device error rather than copying if the destination is on another
+/// volume, and a clone can only be created on the volume of its source anyway.
+///
+/// The clone creates the file itself, after a unique name was picked. Should another process
+/// take that name in between, the call fails with `io::ErrorKind::AlreadyExists`.
+///
+/// Requires the `tempfile` feature.
+#[cfg(feature = "tempfile")]
+pub fn reflink_to_tempfile<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    dir: Q,
+) -> io::Result<tempfile::NamedTempFile> {
+    let path = tempfile::Builder::new()
+        .prefix(".reflink")
+        .tempfile_in(dir)?
+        .into_temp_path();
+    // The clone has to create the file itself, so the name is free until it does. Should another
+    // process take it in between, the clone fails with `AlreadyExists` as it creates the file
+    // with `create_new`. Either way the file at `path` after a failure is not ours to remove.
+    std::fs::remove_file(&path)?;
+    if let Err(e) = reflink(from, &path) {
+        let _ = path.keep();
+        return Err(e);
     }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)?;
+    Ok(tempfile::NamedTempFile::from_parts(file, path))
 }
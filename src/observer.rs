@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{Mechanism, ReflinkError, UnsupportedReason};
+
+/// Receives the clone operations of a [`ReflinkOptions`](crate::ReflinkOptions), set with
+/// [`ReflinkOptions::observer`](crate::ReflinkOptions::observer), e.g. to log them or to count
+/// fallbacks with whatever instrumentation the application uses.
+///
+/// Every method does nothing by default, so implementations only override what they need.
+/// For each clone, `on_attempt` is called first, followed by either `on_success` or, if a copy
+/// is made instead, `on_fallback`. Clones failing with an error that rules out a copy are
+/// reported by `on_attempt` alone, the error is returned to the caller.
+///
+/// ```rust,no_run
+/// use std::path::Path;
+/// use std::sync::Arc;
+/// use reflink::{Mechanism, ReflinkError, ReflinkObserver, ReflinkOptions};
+///
+/// struct Logger;
+///
+/// impl ReflinkObserver for Logger {
+///     fn on_attempt(&self, from: &Path, to: &Path) {
+///         eprintln!("cloning {} to {}", from.display(), to.display());
+///     }
+///     fn on_fallback(&self, reason: &ReflinkError) {
+///         eprintln!("copying instead: {}", reason);
+///     }
+/// }
+///
+/// ReflinkOptions::new()
+///     .observer(Arc::new(Logger))
+///     .reflink_or_copy("src.txt", "dest.txt")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub trait ReflinkObserver: Send + Sync {
+    /// A clone of `from` to `to` is about to be attempted. `to` is the destination as requested,
+    /// before the conflict policy picks another name.
+    fn on_attempt(&self, from: &Path, to: &Path) {
+        let _ = (from, to);
+    }
+
+    /// The clone attempted last succeeded with `mechanism`.
+    fn on_success(&self, mechanism: Mechanism) {
+        let _ = mechanism;
+    }
+
+    /// The clone attempted last failed for `reason`, and the data is copied instead. Errors
+    /// which carry no [`ReflinkError`] are passed as [`ReflinkError::Unsupported`] with
+    /// [`UnsupportedReason::Filesystem`] and the original error as the source.
+    fn on_fallback(&self, reason: &ReflinkError) {
+        let _ = reason;
+    }
+}
+
+/// The observer set with [`ReflinkOptions::observer`](crate::ReflinkOptions::observer).
+#[derive(Clone)]
+pub(crate) struct ObserverHook(pub(crate) Arc<dyn ReflinkObserver>);
+
+impl ObserverHook {
+    /// Passes the result of a clone to `on_success` if it succeeded.
+    pub(crate) fn cloned<T>(&self, res: &io::Result<T>) {
+        if res.is_ok() {
+            self.0.on_success(Mechanism::current());
+        }
+    }
+
+    /// Passes the error of a clone, which is replaced by a copy, to `on_fallback`.
+    pub(crate) fn fallback(&self, err: io::Error) {
+        if ReflinkError::from_io(&err).is_some() {
+            if let Some(Ok(reason)) = err.into_inner().map(|e| e.downcast::<ReflinkError>()) {
+                self.0.on_fallback(&reason);
+            }
+        } else {
+            self.0.on_fallback(&ReflinkError::Unsupported {
+                reason: UnsupportedReason::Filesystem,
+                source: Some(err),
+            });
+        }
+    }
+}
+
+impl fmt::Debug for ObserverHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ObserverHook(..)")
+    }
+}
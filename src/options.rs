@@ -0,0 +1,1807 @@
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(windows)]
+use std::time::Duration;
+
+use crate::audit::{AuditHook, AuditRecord};
+use crate::error::{
+    cancelled, is_cancelled, is_unsupported, not_regular, source_vanished, unsupported,
+    ReflinkError, UnsupportedReason,
+};
+use crate::observer::{ObserverHook, ReflinkObserver};
+use crate::sys;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+use crate::verify::{self, VerifyLevel};
+use crate::{Mechanism, ReflinkCopyState, ReflinkDirIter, ReflinkSource};
+
+/// Options and flags which can be used to configure how a file is reflinked.
+///
+/// The free functions [`reflink`](crate::reflink) and [`reflink_or_copy`](crate::reflink_or_copy)
+/// use the default options. Builder methods which only apply to a single platform are only
+/// available when compiling for that platform.
+///
+/// ```rust
+/// use reflink::ReflinkOptions;
+///
+/// match ReflinkOptions::new().reflink_or_copy("src.txt", "dest.txt") {
+///     Ok(None) => println!("file has been reflinked"),
+///     Ok(Some(written)) => println!("file has been copied ({} bytes)", written),
+///     Err(e) => println!("an error occured: {:?}", e)
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ReflinkOptions {
+    skip_source_check: bool,
+    allow_nonregular_copy: bool,
+    pub(crate) on_conflict: Conflict,
+    case_insensitive_conflicts: bool,
+    pub(crate) preserve_metadata: bool,
+    pub(crate) rich_copy_fallback: bool,
+    pub(crate) preallocate_fallback: bool,
+    pub(crate) preserve_sparseness: bool,
+    pub(crate) preserve_hard_links: bool,
+    pub(crate) keep_on_failure: bool,
+    pub(crate) drop_caches: bool,
+    pub(crate) durable: bool,
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    verify: VerifyLevel,
+    pub(crate) audit: Option<AuditHook>,
+    observer: Option<ObserverHook>,
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub(crate) post_clone: Option<PostCloneHook>,
+    cancel: Option<CancelHook>,
+    methods: Option<Vec<CopyMethod>>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) copy_file_flags: bool,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) chown: Option<(u32, u32)>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) dest_mode: Option<u32>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) preserve_compression: bool,
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    pub(crate) reuse_existing_dest: bool,
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub(crate) macos_raw_flags: i32,
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    pub(crate) preserve_ownership: bool,
+    #[cfg(windows)]
+    pub(crate) copy_file2_fallback: bool,
+    #[cfg(windows)]
+    pub(crate) strict_integrity: bool,
+    #[cfg(windows)]
+    pub(crate) resumable: bool,
+    #[cfg(windows)]
+    pub(crate) force_sparse: Option<bool>,
+    #[cfg(windows)]
+    pub(crate) allow_dense_clone: bool,
+    #[cfg(windows)]
+    pub(crate) sharing_retries: Option<(u32, Duration)>,
+}
+
+/// What to do if the destination already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Conflict {
+    /// Fail with `io::ErrorKind::AlreadyExists`.
+    #[default]
+    Error,
+    /// Remove the existing destination file before reflinking.
+    ///
+    /// This is not atomic: if the reflink fails, the old destination is gone nonetheless, see
+    /// [`Conflict::Replace`] instead. A destination which already shares all its blocks with the
+    /// source is left alone.
+    Overwrite,
+    /// Pick the first free name among `name (1).ext`, `name (2).ext`, and so on.
+    Suffix,
+    /// Clone (or copy) into a temporary file next to the destination, then move it over the
+    /// destination in one step, so that readers see either the old or the new file, never a
+    /// partial one. If the reflink fails, an existing destination stays untouched.
+    ///
+    /// On Linux and Android, an existing destination is swapped with the temporary file by
+    /// `renameat2(RENAME_EXCHANGE)` and removed afterwards, and a missing one is created with
+    /// `RENAME_NOREPLACE`. Kernels and file systems without `renameat2` fall back to `rename`,
+    /// which replaces the destination atomically as well. Elsewhere `fs::rename` is used; on
+    /// Windows it fails while another process has the destination open without
+    /// `FILE_SHARE_DELETE`.
+    Replace,
+}
+
+/// A way of creating the destination, tried in the order given to
+/// [`ReflinkOptions::methods`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CopyMethod {
+    /// Clone the whole file, as [`reflink`](crate::reflink) does.
+    Reflink,
+    /// Clone the whole file with a single [`reflink_range`](crate::reflink_range) call into an
+    /// empty destination. On Windows, this only succeeds for sources whose size is a multiple
+    /// of the cluster size; not supported on OS X and ios.
+    ReflinkRange,
+    /// Create the destination as a hard link to the source, which shares even the metadata.
+    /// Writes through either path change both.
+    HardLink,
+    /// Copy with `copy_file_range`, which lets the file system copy the data in the kernel, or
+    /// on the server for NFS. Only supported on Linux and Android.
+    CopyFileRange,
+    /// Copy the data like the fallback of [`reflink_or_copy`](crate::reflink_or_copy),
+    /// honoring the options for copies.
+    PlainCopy,
+}
+
+/// Whether a successful reflink shares the data blocks with the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sharing {
+    /// Source and destination share the same blocks on disk until either is modified.
+    Shared,
+    /// The clone call succeeded, but the file system physically duplicated the data.
+    ///
+    /// None of the currently used mechanisms (`FICLONE`, `clonefile` and
+    /// `FSCTL_DUPLICATE_EXTENTS_TO_FILE`) ever do this, as they fail instead of copying.
+    Duplicated,
+}
+
+/// How much of a clone's data is physically shared with its source, as reported by
+/// [`reflink_accounted`](crate::reflink_accounted).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ShareStats {
+    /// The number of bytes of the destination stored in the same blocks as the source.
+    pub shared_bytes: u64,
+    /// The size of the destination.
+    pub total_bytes: u64,
+}
+
+/// What [`reflink_or_copy`](crate::reflink_or_copy) would cost, as estimated by
+/// [`estimate_cost`](crate::estimate_cost).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CostEstimate {
+    /// Whether the file can be cloned, at a cost independent of its size. Otherwise it would be
+    /// copied.
+    pub reflink: bool,
+    /// The size of the source.
+    pub size: u64,
+}
+
+impl CostEstimate {
+    /// The number of bytes that would be copied: none for a clone, all of them otherwise.
+    pub fn bytes_to_copy(&self) -> u64 {
+        if self.reflink {
+            0
+        } else {
+            self.size
+        }
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Conflict::Error => "fail if the destination exists",
+            Conflict::Overwrite => "overwrite an existing destination",
+            Conflict::Suffix => "pick a free name for the destination",
+            Conflict::Replace => "atomically replace an existing destination",
+        })
+    }
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::ReflinkRange => "range reflink",
+            CopyMethod::HardLink => "hard link",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::PlainCopy => "copy",
+        })
+    }
+}
+
+impl fmt::Display for Sharing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Sharing::Shared => "blocks shared with the source",
+            Sharing::Duplicated => "data duplicated by the file system",
+        })
+    }
+}
+
+impl fmt::Display for ShareStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes shared with the source",
+            self.shared_bytes, self.total_bytes
+        )
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let how = if self.reflink { "reflink" } else { "copy" };
+        write!(f, "{} of {} bytes", how, self.size)
+    }
+}
+
+impl ReflinkOptions {
+    /// Creates a blank set of options, equivalent to the behaviour of the free functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skip checking that the source is an existing regular file before reflinking.
+    ///
+    /// The source is opened once and checked with an `fstat` call on the handle, which is
+    /// wasteful in tight loops where the caller already validated the source. Without the check,
+    /// the platform's clone call reports invalid sources itself, so the error kind differs (e.g.
+    /// `NotFound` or an OS specific error instead of `InvalidInput`).
+    ///
+    /// Defaults to `false`.
+    pub fn skip_source_check(&mut self, skip_source_check: bool) -> &mut Self {
+        self.skip_source_check = skip_source_check;
+        self
+    }
+
+    /// Let `reflink_or_copy` copy from sources which are not regular files, such as FIFOs or
+    /// character devices.
+    ///
+    /// Such sources cannot be reflinked, so the reflink attempt is skipped and the data is
+    /// streamed into the destination until the end of the input, with `splice` on Linux and
+    /// Android. Note that this never ends for endless sources like `/dev/zero`. Directories are
+    /// still rejected.
+    ///
+    /// Defaults to `false`, rejecting such sources with `io::ErrorKind::InvalidInput`.
+    pub fn allow_nonregular_copy(&mut self, allow_nonregular_copy: bool) -> &mut Self {
+        self.allow_nonregular_copy = allow_nonregular_copy;
+        self
+    }
+
+    /// Keep the destination if the reflink fails after creating it, e.g. to inspect a partial
+    /// clone while debugging a file system.
+    ///
+    /// Such a destination may be empty, incomplete or otherwise corrupt. If `reflink_or_copy`
+    /// falls back to a copy, the copy replaces it. On OS X and ios, `clonefile` never leaves a
+    /// partial destination behind, so this has no effect there.
+    ///
+    /// Defaults to `false`, removing the destination on failure.
+    pub fn keep_on_failure(&mut self, keep_on_failure: bool) -> &mut Self {
+        self.keep_on_failure = keep_on_failure;
+        self
+    }
+
+    /// Make a clone durable before reporting success, so that it survives a crash or power loss.
+    ///
+    /// A clone is complete once the call returns (see [`Mechanism::is_synchronous`]), but like
+    /// any other write it may only be in the cache of the file system. With this option the
+    /// destination is flushed with `fsync` (`F_FULLFSYNC` on OS X and ios, `FlushFileBuffers` on
+    /// Windows) after the metadata options and the [`post_clone`](ReflinkOptions::post_clone)
+    /// callback were applied. On Unix, the directory holding the destination is flushed as well,
+    /// after a [`Conflict::Replace`] moved it into place, so that its name is durable too. A
+    /// failed flush is returned as an error and the destination is removed, unless
+    /// [`keep_on_failure`](ReflinkOptions::keep_on_failure) is set.
+    ///
+    /// Copies made by `reflink_or_copy` are not flushed.
+    ///
+    /// Defaults to `false`.
+    pub fn durable(&mut self, durable: bool) -> &mut Self {
+        self.durable = durable;
+        self
+    }
+
+    /// Read back every clone and compare it with its source before reporting success, as a
+    /// guard against file system bugs.
+    ///
+    /// [`VerifyLevel::Sampled`] compares the sizes and a few blocks, [`VerifyLevel::Full`] every
+    /// byte, which reads both files entirely and takes as long as a copy would. The comparison
+    /// runs after the metadata options were applied and before the
+    /// [`post_clone`](ReflinkOptions::post_clone) callback, which may change the clone. If the
+    /// files differ, the destination is removed (unless
+    /// [`keep_on_failure`](ReflinkOptions::keep_on_failure) is set) and
+    /// [`ReflinkError::VerificationFailed`](crate::ReflinkError::VerificationFailed) is returned
+    /// with the kind `InvalidData`; `reflink_or_copy` then makes a copy instead.
+    ///
+    /// Copies made by `reflink_or_copy` are not verified.
+    ///
+    /// Defaults to [`VerifyLevel::None`]. Only available on Linux, Android, OS X, ios and Windows.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub fn verify(&mut self, level: VerifyLevel) -> &mut Self {
+        self.verify = level;
+        self
+    }
+
+    /// Drop the cached pages of source and destination after a successful reflink or copy.
+    ///
+    /// Bulk clones of large trees otherwise fill the page cache with data read for metadata or
+    /// by a fallback copy, pushing out pages other processes still need. This issues
+    /// `posix_fadvise(POSIX_FADV_DONTNEED)` on both files; failures are ignored, as the advice
+    /// is only a hint.
+    ///
+    /// Defaults to `false`. Only has an effect on Linux and Android.
+    pub fn drop_caches(&mut self, drop_caches: bool) -> &mut Self {
+        self.drop_caches = drop_caches;
+        self
+    }
+
+    /// Pass every intended clone to `audit` before anything is changed on disk, e.g. to log it
+    /// or to enforce a policy.
+    ///
+    /// The callback receives an [`AuditRecord`] with the source, the requested destination, the
+    /// time and the mechanism to be used. If it returns `false`, the operation fails with
+    /// `io::ErrorKind::PermissionDenied` carrying a [`ReflinkError::Aborted`], without creating,
+    /// removing or copying any file. When cloning directory trees, each file is audited on its
+    /// own, while the directories are created regardless.
+    ///
+    /// Defaults to no callback.
+    pub fn audit<F>(&mut self, audit: F) -> &mut Self
+    where
+        F: Fn(&AuditRecord) -> bool + Send + Sync + 'static,
+    {
+        self.audit = Some(AuditHook::new(audit));
+        self
+    }
+
+    /// Report every clone to `observer`: the attempt, and then its success or the fallback to
+    /// a copy. See [`ReflinkObserver`] for details.
+    ///
+    /// This covers `reflink`, `reflink_or_copy` and their variants, the files of directory trees
+    /// and the [`CopyMethod::Reflink`] and [`CopyMethod::ReflinkRange`] methods of
+    /// [`reflink_with_methods`](ReflinkOptions::reflink_with_methods), where trying the next
+    /// method counts as a fallback. Copies made without attempting a clone, and clones from an
+    /// open file with [`reflink_fanout`](ReflinkOptions::reflink_fanout), are not reported.
+    ///
+    /// Defaults to no observer, which costs nothing.
+    pub fn observer(&mut self, observer: Arc<dyn ReflinkObserver>) -> &mut Self {
+        self.observer = Some(ObserverHook(observer));
+        self
+    }
+
+    /// Sets the ways [`reflink_with_methods`](ReflinkOptions::reflink_with_methods) tries to
+    /// create the destination, in order of preference.
+    ///
+    /// Each method is tried until one succeeds, so e.g. `[Reflink, CopyFileRange, PlainCopy]`
+    /// only copies in user space if neither the clone nor the in-kernel copy works, and
+    /// `[Reflink]` never copies at all.
+    ///
+    /// Defaults to `[Reflink, PlainCopy]`, the behaviour of `reflink_or_copy`. An empty list
+    /// makes `reflink_with_methods` fail with `io::ErrorKind::InvalidInput`.
+    pub fn methods(&mut self, methods: &[CopyMethod]) -> &mut Self {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Pass the open destination of every successful clone to `post_clone`, e.g. to set custom
+    /// metadata, append a header or sync it, without opening it again.
+    ///
+    /// The callback runs after the clone and the metadata options were applied, and before the
+    /// destination is moved into place for [`Conflict::Replace`]. The handle is open for
+    /// writing, positioned at the start of the file. If the callback fails, the destination is
+    /// removed (unless [`keep_on_failure`](ReflinkOptions::keep_on_failure) is set) and its error
+    /// is returned; `reflink_or_copy` then falls back to a copy only for errors which allow one.
+    /// Copies made by `reflink_or_copy` are not passed to the callback.
+    ///
+    /// On OS X and ios, `clonefile` does not return a handle, so the clone is opened after
+    /// cloning; clones of read-only sources are opened for reading only.
+    ///
+    /// Defaults to no callback. Only available on Linux, Android, OS X, ios and Windows.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub fn post_clone<F>(&mut self, post_clone: F) -> &mut Self
+    where
+        F: Fn(&fs::File) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.post_clone = Some(PostCloneHook(Arc::new(post_clone)));
+        self
+    }
+
+    /// Polls `cancel` while a clone or copy is in progress, and stops it as soon as it returns
+    /// `true`, e.g. once the user hit a cancel button.
+    ///
+    /// The callback is asked between the chunks of a clone on Windows, and between the buffers
+    /// of a copy made by `reflink_or_copy`. The operation then fails with an `io::Error` of kind
+    /// `io::ErrorKind::Other` carrying a [`ReflinkError::Cancelled`], after removing the partial
+    /// destination (unless [`keep_on_failure`](ReflinkOptions::keep_on_failure) is set; a
+    /// `resumable` clone on Windows keeps it to be resumed). Clones made by a
+    /// single call, which is all of them on Linux and OS X and small ones on Windows, cannot be
+    /// interrupted, so the callback is not asked at all. Neither is it asked by copies made with
+    /// `copy_file2_fallback` on Windows. On OS X, a copy which may be cancelled does not keep
+    /// extended attributes.
+    ///
+    /// Defaults to no callback.
+    pub fn cancel<F>(&mut self, cancel: F) -> &mut Self
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.cancel = Some(CancelHook(Arc::new(cancel)));
+        self
+    }
+
+    /// Sets the behaviour if the destination already exists.
+    ///
+    /// Use [`reflink_path`](ReflinkOptions::reflink_path) to learn the destination picked by
+    /// [`Conflict::Suffix`].
+    ///
+    /// Defaults to [`Conflict::Error`]. Note that `reflink_or_copy` falls back to a copy after
+    /// the reflink failed with `AlreadyExists`, which overwrites the destination.
+    pub fn on_conflict(&mut self, on_conflict: Conflict) -> &mut Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Treat destinations whose name only differs in case from an existing file as existing.
+    ///
+    /// Case-insensitive file systems (APFS and NTFS by default) refuse to create `foo.txt` next
+    /// to `Foo.txt`, while case-sensitive ones happily create both. With this option, the
+    /// [`Conflict`] policy applies to such collisions on every file system, so that e.g. build
+    /// tools behave the same on all platforms. Checking for collisions lists the destination
+    /// directory, comparing names by their lowercase form; names which are not valid UTF-8 are
+    /// compared exactly.
+    ///
+    /// Defaults to `false`, leaving collisions to the file system.
+    pub fn case_insensitive_conflicts(&mut self, case_insensitive_conflicts: bool) -> &mut Self {
+        self.case_insensitive_conflicts = case_insensitive_conflicts;
+        self
+    }
+
+    /// Preserve the permissions and timestamps of the source on the reflinked destination.
+    ///
+    /// * Linux / Android: `FICLONE` only shares the data, so the permissions, extended attributes
+    ///   and the access and modification times are copied afterwards. The creation time (`btime`
+    ///   of `statx`) cannot be set by any syscall, so it remains the time of the clone.
+    /// * OS X / ios: no-op, `clonefile` already preserves them, including the creation time.
+    /// * Windows: the creation, access and modification times are copied with `SetFileTime`.
+    ///
+    /// This does not apply to the copy fallback of `reflink_or_copy`, see
+    /// [`rich_copy_fallback`](ReflinkOptions::rich_copy_fallback).
+    ///
+    /// Defaults to `false`.
+    pub fn preserve_metadata(&mut self, preserve_metadata: bool) -> &mut Self {
+        self.preserve_metadata = preserve_metadata;
+        self
+    }
+
+    /// Make the copy fallback of `reflink_or_copy` preserve the same metadata as a reflink.
+    ///
+    /// `std::fs::copy` preserves permissions, but not always timestamps and extended attributes,
+    /// whereas a clone on macOS inherits all of them. With this option, the access and
+    /// modification times (and the creation time on OS X, ios and Windows) are copied after the
+    /// fallback copy, as well as the extended attributes on Linux (macOS and Windows copy those
+    /// already). Combined with [`preserve_metadata`](ReflinkOptions::preserve_metadata), the
+    /// result is the same whichever path was taken. If copying the metadata fails, the error is
+    /// returned and the copy is removed.
+    ///
+    /// Defaults to `false`.
+    pub fn rich_copy_fallback(&mut self, rich_copy_fallback: bool) -> &mut Self {
+        self.rich_copy_fallback = rich_copy_fallback;
+        self
+    }
+
+    /// Preallocate the destination before `reflink_or_copy` falls back to copying.
+    ///
+    /// Reserving the space for the whole file upfront reduces fragmentation and fails early if
+    /// the destination volume is too small, instead of after copying most of the data.
+    /// Uses `fallocate` on Linux and Android and `SetFileInformationByHandle` on Windows; a no-op
+    /// on other platforms. Ignored on Windows if `copy_file2_fallback` is set.
+    ///
+    /// Defaults to `false`.
+    pub fn preallocate_fallback(&mut self, preallocate_fallback: bool) -> &mut Self {
+        self.preallocate_fallback = preallocate_fallback;
+        self
+    }
+
+    /// Keep the holes of every source when `reflink_or_copy` falls back to copying, not only of
+    /// those which look sparse.
+    ///
+    /// A clone shares the hole map of the source, but a plain copy may write the holes out as
+    /// zeros, which turns a mostly empty disk image into a huge file. Sources which look sparse
+    /// are therefore copied sparsely, unless
+    /// [`preallocate_fallback`](ReflinkOptions::preallocate_fallback) is set: on Unix those with
+    /// fewer blocks allocated than their size needs, on Windows those marked sparse. This option
+    /// extends that to all sources, e.g. on file systems whose block counts hide the holes. A
+    /// sparse copy writes only the data ranges of the source and extends the destination over
+    /// the trailing hole:
+    /// * Linux / Android, OS X / ios: the ranges are found with `lseek` and `SEEK_DATA` /
+    ///   `SEEK_HOLE`; file systems without support are copied in full.
+    /// * Windows: the ranges of a sparse source are queried with `FSCTL_QUERY_ALLOCATED_RANGES`
+    ///   and the destination is marked sparse.
+    ///
+    /// Like a preallocated copy, the copy transfers the data and permissions; on OS X and ios
+    /// also the extended attributes, such as the resource fork. Takes precedence over
+    /// [`preallocate_fallback`](ReflinkOptions::preallocate_fallback), and is ignored on Windows
+    /// if `copy_file2_fallback` is set.
+    ///
+    /// Defaults to `false`.
+    pub fn preserve_sparseness(&mut self, preserve_sparseness: bool) -> &mut Self {
+        self.preserve_sparseness = preserve_sparseness;
+        self
+    }
+
+    /// Recreate hard links when cloning directory trees with
+    /// [`reflink_dir_iter`](ReflinkOptions::reflink_dir_iter).
+    ///
+    /// Files with several links are tracked by device and inode (volume serial number and file
+    /// index on Windows). The first path of such a file is cloned, all further paths become hard
+    /// links to that clone, so the destination tree has the same link structure as the source.
+    /// Not supported on platforms other than Unix and Windows, where this is a no-op.
+    ///
+    /// Defaults to `false`.
+    pub fn preserve_hard_links(&mut self, preserve_hard_links: bool) -> &mut Self {
+        self.preserve_hard_links = preserve_hard_links;
+        self
+    }
+
+    /// Copy the inode flags (as shown by `lsattr`, e.g. `nodump` or `noatime`) to the destination.
+    ///
+    /// `FICLONE` only shares the data blocks, so the flags are read with `FS_IOC_GETFLAGS` and
+    /// applied with `FS_IOC_SETFLAGS`. This also applies when `reflink_or_copy` falls back to
+    /// copying. The immutable and append-only flags are skipped if the caller lacks
+    /// `CAP_LINUX_IMMUTABLE`; file systems without inode flags are ignored. If applying the
+    /// flags fails otherwise, the error is returned and the destination is removed.
+    ///
+    /// Defaults to `false`. Only available on Linux and Android.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn copy_file_flags(&mut self, copy_file_flags: bool) -> &mut Self {
+        self.copy_file_flags = copy_file_flags;
+        self
+    }
+
+    /// Set the owner and group of the destination, e.g. to restore the ownership of an archived
+    /// file.
+    ///
+    /// The destination is changed with `fchown` after cloning, and after copying if
+    /// `reflink_or_copy` falls back to a copy. This needs `CAP_CHOWN` (usually root); without it,
+    /// the operation fails with `io::ErrorKind::PermissionDenied` and the destination is
+    /// removed.
+    ///
+    /// Defaults to keeping the owner of the calling process. Only available on Linux and Android.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn chown(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.chown = Some((uid, gid));
+        self
+    }
+
+    /// Set the exact permission bits of the destination, regardless of the process umask.
+    ///
+    /// The destination is created with `mode` and changed to it with `fchmod` after cloning
+    /// (and after copying if `reflink_or_copy` falls back to a copy), so the umask cannot mask
+    /// any bits. If `fchmod` fails, the error is returned and the destination is removed. This
+    /// takes precedence over the mode copied by
+    /// [`preserve_metadata`](ReflinkOptions::preserve_metadata).
+    ///
+    /// Defaults to `0o666` minus the umask for clones and the source mode for copies. Only
+    /// available on Linux and Android.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn dest_mode(&mut self, mode: u32) -> &mut Self {
+        self.dest_mode = Some(mode);
+        self
+    }
+
+    /// Keep copies compressed like their source when `reflink_or_copy` falls back to a copy,
+    /// e.g. from one btrfs file system to another.
+    ///
+    /// A clone shares the compressed extents anyway, but a copy is compressed according to the
+    /// settings of the destination. With this option, the compression inode flags
+    /// (`FS_COMPR_FL` / `FS_NOCOMP_FL`) and the `btrfs.compression` property of the source are
+    /// applied to the empty destination before any data is written. Destinations which do not
+    /// support compression are copied as usual.
+    ///
+    /// Defaults to `false`. Only available on Linux and Android.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn preserve_compression(&mut self, preserve_compression: bool) -> &mut Self {
+        self.preserve_compression = preserve_compression;
+        self
+    }
+
+    /// Create the destination with `OpenOptions::create_new` (`O_EXCL` / `CREATE_NEW`), so
+    /// that an existing destination makes the reflink fail with `io::ErrorKind::AlreadyExists`.
+    ///
+    /// This mirrors OS X and ios, where `clonefile` requires that the destination does not
+    /// exist yet and therefore cannot be relaxed. When disabled, an existing destination file is
+    /// opened, truncated and replaced by the clone instead; like a newly created one, it is
+    /// removed if the reflink fails.
+    ///
+    /// Defaults to `true`. Only available on Linux, Android and Windows.
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    pub fn exclusive_create(&mut self, exclusive_create: bool) -> &mut Self {
+        self.reuse_existing_dest = !exclusive_create;
+        self
+    }
+
+    /// Additional raw flags for `clonefile`, ORed into the flags this crate passes
+    /// (`CLONE_NOOWNERCOPY`, unless [`preserve_ownership`](ReflinkOptions::preserve_ownership)
+    /// is set).
+    ///
+    /// This is an escape hatch for flags not covered by the other options, e.g. `CLONE_NOFOLLOW`
+    /// (`0x0001`) or flags added by future OS versions. The flags are passed on without any
+    /// validation; unknown flags make `clonefile` fail with `EINVAL`.
+    ///
+    /// Defaults to `0`. Only available on OS X and ios.
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub fn macos_raw_flags(&mut self, macos_raw_flags: i32) -> &mut Self {
+        self.macos_raw_flags = macos_raw_flags;
+        self
+    }
+
+    /// Give the clone the owner and group of the source instead of the caller's, e.g. when
+    /// restoring files as root.
+    ///
+    /// * Linux / Android: the owner and group are copied with `fchown` after cloning. Like
+    ///   `cp --preserve=ownership`, a caller without `CAP_CHOWN` silently keeps the owner and
+    ///   only copies the group, if it is a member of it, and keeps its own group otherwise. An
+    ///   explicit [`chown`](ReflinkOptions::chown) takes precedence.
+    /// * OS X / ios: by default `clonefile` is passed `CLONE_NOOWNERCOPY`, so the clone belongs
+    ///   to the calling user like a copy would. With this option the flag is dropped. Copying
+    ///   the ownership needs root privileges: for other callers, `clonefile` silently makes the
+    ///   caller the owner nonetheless.
+    ///
+    /// Like [`preserve_metadata`](ReflinkOptions::preserve_metadata), this does not apply to the
+    /// copy fallback of `reflink_or_copy`.
+    ///
+    /// Defaults to `false`. Only available on Linux, Android, OS X and ios.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    pub fn preserve_ownership(&mut self, preserve_ownership: bool) -> &mut Self {
+        self.preserve_ownership = preserve_ownership;
+        self
+    }
+
+    /// Use `CopyFile2` instead of `std::fs::copy` when `reflink_or_copy` falls back to copying.
+    ///
+    /// `CopyFile2` handles alternate data streams and metadata itself, and recent Windows versions
+    /// (Windows 11 24H2, Server 2025) transparently clone blocks on ReFS and Dev Drive volumes.
+    /// Since there is no way to tell whether blocks were actually shared, the result is always
+    /// reported as a copy.
+    ///
+    /// Defaults to `false`. Only available on Windows.
+    #[cfg(windows)]
+    pub fn copy_file2_fallback(&mut self, copy_file2_fallback: bool) -> &mut Self {
+        self.copy_file2_fallback = copy_file2_fallback;
+        self
+    }
+
+    /// Retry opening a source which another process holds open without read sharing, e.g. an
+    /// application briefly locking a file while it writes it.
+    ///
+    /// The source is always opened with `FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE`,
+    /// so the clone never locks out other processes, but Windows still refuses the open with
+    /// `ERROR_SHARING_VIOLATION` while another handle denies read sharing. With this option, the
+    /// open is retried up to `retries` times, waiting `delay` before each retry, and the sharing
+    /// violation is returned only if it persists.
+    ///
+    /// Defaults to no retries. Only available on Windows.
+    #[cfg(windows)]
+    pub fn retry_sharing_violations(&mut self, retries: u32, delay: Duration) -> &mut Self {
+        self.sharing_retries = Some((retries, delay));
+        self
+    }
+
+    /// Fail the reflink if the integrity information (checksum algorithm and enforcement) of the
+    /// source cannot be applied to the destination.
+    ///
+    /// The integrity settings are not needed for cloning, and some volumes such as Dev Drives
+    /// refuse `FSCTL_SET_INTEGRITY_INFORMATION`, so by default such failures are ignored. When
+    /// strict, the destination is removed and the error is returned instead.
+    ///
+    /// Defaults to `false`. Only available on Windows.
+    #[cfg(windows)]
+    pub fn strict_integrity(&mut self, strict_integrity: bool) -> &mut Self {
+        self.strict_integrity = strict_integrity;
+        self
+    }
+
+    /// Keep the progress of a reflink in a sidecar file, so that an interrupted reflink can be
+    /// resumed by running it again.
+    ///
+    /// Large files are cloned in chunks of up to 4 GiB. After each chunk, the progress is written
+    /// to `<dest>.reflink-progress`, and the partial destination is kept if cloning fails. When
+    /// reflinking to the same destination again, the remaining chunks are cloned, provided the
+    /// source size and last write time did not change; otherwise the partial destination is
+    /// discarded and the reflink starts over. The sidecar is removed once the reflink completes.
+    ///
+    /// The sidecar holds a single line:
+    /// `reflink-progress 1 <source size> <source last write time> <offset>`, where the last write
+    /// time is in 100ns intervals since 1601 (as in `MetadataExt::last_write_time`) and `offset`
+    /// is the number of bytes cloned so far.
+    ///
+    /// Use this with the default [`Conflict::Error`] policy, the others would discard or sidestep
+    /// the partial destination. Defaults to `false`. Only available on Windows.
+    #[cfg(windows)]
+    pub fn resumable(&mut self, resumable: bool) -> &mut Self {
+        self.resumable = resumable;
+        self
+    }
+
+    /// Whether the destination is a sparse file, regardless of the source.
+    ///
+    /// With `Some(true)`, `FSCTL_SET_SPARSE` is applied to every destination. With `Some(false)`,
+    /// the destination is fully allocated: since a sparse source can only be cloned into a sparse
+    /// file, its destination is made dense after cloning, which allocates (and no longer shares)
+    /// the clusters of all holes. `None` follows the source.
+    ///
+    /// Defaults to `None`. Only available on Windows.
+    #[cfg(windows)]
+    pub fn force_sparse(&mut self, force_sparse: Option<bool>) -> &mut Self {
+        self.force_sparse = force_sparse;
+        self
+    }
+
+    /// Clone a sparse source into a dense destination if the destination volume does not
+    /// support sparse files.
+    ///
+    /// A sparse source is normally cloned into a sparse destination, and volumes which refuse
+    /// `FSCTL_SET_SPARSE` (`ERROR_INVALID_FUNCTION` or `ERROR_NOT_SUPPORTED`) make the reflink
+    /// fail with [`UnsupportedReason::Filesystem`](crate::UnsupportedReason::Filesystem), so that
+    /// `reflink_or_copy` copies instead. With this option, the clone goes on into a dense
+    /// destination, which allocates the clusters of the holes, or fails if the volume cannot
+    /// clone into it. Does not apply if [`force_sparse`](ReflinkOptions::force_sparse) asks for a
+    /// sparse destination.
+    ///
+    /// Defaults to `false`. Only available on Windows.
+    #[cfg(windows)]
+    pub fn allow_dense_clone(&mut self, allow_dense_clone: bool) -> &mut Self {
+        self.allow_dense_clone = allow_dense_clone;
+        self
+    }
+
+    /// Copies a file using COW semantics, honoring the configured options.
+    ///
+    /// See [`reflink`](crate::reflink) for details.
+    pub fn reflink<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        self.reflink_inner(from.as_ref(), to.as_ref()).map(drop)
+    }
+
+    /// Attempts to reflink a file, returning `Ok(false)` if reflinks are not supported, honoring
+    /// the configured options.
+    ///
+    /// See [`try_reflink`](crate::try_reflink) for details. With
+    /// [`keep_on_failure`](ReflinkOptions::keep_on_failure), a partial destination may be left
+    /// behind even if `Ok(false)` is returned.
+    pub fn try_reflink<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<bool> {
+        match self.reflink(from, to) {
+            Ok(()) => Ok(true),
+            Err(ref e) if is_unsupported(e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like [`reflink`](ReflinkOptions::reflink), but reports whether the data blocks are
+    /// actually shared.
+    ///
+    /// See [`reflink_reported`](crate::reflink_reported) for details.
+    pub fn reflink_reported<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> io::Result<Sharing> {
+        self.reflink_inner(from.as_ref(), to.as_ref())
+            .map(|(_, sharing)| sharing)
+    }
+
+    /// Like [`reflink`](ReflinkOptions::reflink), but measures how much data is shared afterwards.
+    ///
+    /// See [`reflink_accounted`](crate::reflink_accounted) for details.
+    pub fn reflink_accounted<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> io::Result<ShareStats> {
+        let from = from.as_ref();
+        let (to, _) = self.reflink_inner(from, to.as_ref())?;
+        let dest = fs::File::open(&to)?;
+        let total_bytes = dest.metadata()?.len();
+        let shared_bytes = sys::shared_bytes(&fs::File::open(from)?, &dest)?;
+        Ok(ShareStats {
+            // extents cover whole blocks, the last one may reach past the end of the file
+            shared_bytes: shared_bytes.min(total_bytes),
+            total_bytes,
+        })
+    }
+
+    /// Like [`reflink`](ReflinkOptions::reflink), but returns the path of the destination.
+    ///
+    /// The path differs from `to` if [`Conflict::Suffix`] picked another name.
+    pub fn reflink_path<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> io::Result<PathBuf> {
+        self.reflink_inner(from.as_ref(), to.as_ref())
+            .map(|(to, _)| to.into_owned())
+    }
+
+    /// Attempts to reflink a file, falling back to a conventional copy, honoring the configured
+    /// options.
+    ///
+    /// See [`reflink_or_copy`](crate::reflink_or_copy) for details.
+    pub fn reflink_or_copy<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> io::Result<Option<u64>> {
+        let from = from.as_ref();
+        let in_dir = dest_in_dir(from, to.as_ref())?;
+        let to = in_dir.as_deref().unwrap_or_else(|| to.as_ref());
+        if self.allow_nonregular_copy {
+            match fs::metadata(from) {
+                Ok(ref m) if !m.is_file() && !m.is_dir() => {
+                    self.check_audit(Some(from), to, None, true)?;
+                    let dest = self.resolve_dest(to)?;
+                    let res = sys::copy_stream(from, &dest);
+                    return self
+                        .finish_copy(res, dest, to)
+                        .map(|(_, written)| Some(written));
+                }
+                _ => {}
+            }
+        }
+        self.reflink_or_copy_inner(from, to, true)
+            .map(|(_, copied)| copied)
+    }
+
+    /// Reflinks (or copies) a file into a content-addressed store, honoring the configured
+    /// options.
+    ///
+    /// See [`reflink_or_copy_by_hash`](crate::reflink_or_copy_by_hash) for details.
+    pub fn reflink_or_copy_by_hash<P, W, F>(
+        &self,
+        from: P,
+        hasher: W,
+        dest: F,
+    ) -> io::Result<PathBuf>
+    where
+        P: AsRef<Path>,
+        W: io::Write,
+        F: FnOnce(W) -> PathBuf,
+    {
+        let from = from.as_ref();
+        let to = dest(crate::hash_file(from, hasher)?);
+        if fs::symlink_metadata(&to).is_ok() {
+            // the store holds the content already, and other files may share its blocks
+            return Ok(to);
+        }
+        self.reflink_or_copy_inner(from, &to, true)
+            .map(|(to, _)| to.into_owned())
+    }
+
+    /// `reflink_or_copy` for regular files, which goes straight to copying unless `reflink` is
+    /// set, e.g. because the file system is known to lack support. Returns the path of the
+    /// destination along with the bytes copied.
+    pub(crate) fn reflink_or_copy_inner<'a>(
+        &self,
+        from: &Path,
+        to: &'a Path,
+        reflink: bool,
+    ) -> io::Result<(Cow<'a, Path>, Option<u64>)> {
+        let src = self.open_source(from)?;
+        let mechanism = Some(Mechanism::current()).filter(|_| reflink);
+        self.check_audit(Some(from), to, mechanism, true)?;
+        if reflink {
+            self.observe_attempt(from, to);
+        }
+        let dest = self.resolve_dest(to)?;
+        let res = reflink_or_copy_with(
+            || {
+                if reflink {
+                    self.observe_clone(sys::reflink_file_completing(&src, &dest, self))
+                } else {
+                    Err(unsupported(UnsupportedReason::Filesystem, None))
+                }
+            },
+            |err| {
+                if reflink {
+                    self.observe_fallback(err);
+                }
+                // the copy replaces a partial destination kept by `keep_on_failure`
+                if self.keep_on_failure {
+                    let _ = fs::remove_file(&dest);
+                }
+                sys::copy(from, &dest, self).inspect_err(|err| {
+                    if !self.keep_on_failure && is_cancelled(err) {
+                        let _ = fs::remove_file(&dest);
+                    }
+                })
+            },
+        )
+        .map_err(|err| self.vanished(from, err));
+        self.finish_copy(res, dest, to)
+    }
+
+    /// Creates the destination with the first of the configured
+    /// [`methods`](ReflinkOptions::methods) which succeeds, and returns that method.
+    ///
+    /// After a failed method, a partially written destination is removed and the next method is
+    /// tried. If all of them fail, the error of the last one is returned. Errors which no other
+    /// method could avoid end the attempts right away: an existing destination
+    /// (`io::ErrorKind::AlreadyExists`), [`ReflinkError::OutOfSpace`],
+    /// [`ReflinkError::SourceVanished`] and [`ReflinkError::Cancelled`]. Invalid sources are
+    /// rejected before any method is tried, as by [`reflink`](ReflinkOptions::reflink).
+    ///
+    /// ```rust,no_run
+    /// use reflink::{CopyMethod, ReflinkOptions};
+    ///
+    /// let method = ReflinkOptions::new()
+    ///     .methods(&[CopyMethod::Reflink, CopyMethod::CopyFileRange, CopyMethod::PlainCopy])
+    ///     .reflink_with_methods("src.txt", "dest.txt")?;
+    /// println!("created by {}", method);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn reflink_with_methods<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> io::Result<CopyMethod> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let in_dir = dest_in_dir(from, to)?;
+        let to = in_dir.as_deref().unwrap_or(to);
+        let methods = match self.methods {
+            Some(ref methods) => methods.as_slice(),
+            None => &[CopyMethod::Reflink, CopyMethod::PlainCopy],
+        };
+        if methods.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no copy methods to try",
+            ));
+        }
+        let src = self.open_source(from)?;
+        let clones = |m: &CopyMethod| matches!(m, CopyMethod::Reflink | CopyMethod::ReflinkRange);
+        let mechanism = Some(Mechanism::current()).filter(|_| methods.iter().any(clones));
+        let copy_fallback = !methods.iter().all(clones);
+        self.check_audit(Some(from), to, mechanism, copy_fallback)?;
+        if mechanism.is_some() {
+            self.observe_attempt(from, to);
+        }
+        let dest = self.resolve_dest(to)?;
+
+        for (i, &method) in methods.iter().enumerate() {
+            let res = match method {
+                CopyMethod::Reflink => {
+                    self.observe_clone(sys::reflink_file(&src, &dest, self).map(drop))
+                }
+                CopyMethod::ReflinkRange => self.observe_clone(reflink_whole_range(&src, &dest)),
+                CopyMethod::HardLink => fs::hard_link(from, &dest),
+                CopyMethod::CopyFileRange => sys::copy_file_range(&src, &dest).map(drop),
+                CopyMethod::PlainCopy => sys::copy(from, &dest, self).map(drop),
+            };
+            let err = match res {
+                Ok(()) => return self.commit_dest(dest, to).map(|_| method),
+                Err(err) => self.vanished(from, err),
+            };
+            if err.kind() == io::ErrorKind::AlreadyExists {
+                // not created by this call, so never removed
+                return Err(err);
+            }
+            let done = i + 1 == methods.len()
+                || matches!(
+                    ReflinkError::from_io(&err),
+                    Some(
+                        ReflinkError::OutOfSpace { .. }
+                            | ReflinkError::SourceVanished { .. }
+                            | ReflinkError::Cancelled
+                    )
+                );
+            if !done || !self.keep_on_failure || self.on_conflict == Conflict::Replace {
+                let _ = fs::remove_file(&dest);
+            }
+            if done {
+                return Err(err);
+            }
+            if clones(&method) {
+                self.observe_fallback(err);
+            }
+        }
+        unreachable!("the list of methods is not empty")
+    }
+
+    /// Moves a successful copy into place with `commit_dest` and returns its final path, or
+    /// removes the partial temporary file of `Conflict::Replace`, which nobody could make sense
+    /// of.
+    fn finish_copy<'a, T>(
+        &self,
+        res: io::Result<T>,
+        dest: Cow<'a, Path>,
+        to: &'a Path,
+    ) -> io::Result<(Cow<'a, Path>, T)> {
+        match res {
+            Ok(written) => self.commit_dest(dest, to).map(|dest| (dest, written)),
+            Err(err) => {
+                if self.on_conflict == Conflict::Replace {
+                    let _ = fs::remove_file(&dest);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Reflinks the open file `source` to each of `targets`, honoring the configured options.
+    ///
+    /// See [`reflink_fanout`](crate::reflink_fanout) for details.
+    pub fn reflink_fanout(&self, source: &fs::File, targets: &[&Path]) -> Vec<io::Result<()>> {
+        let file_type = match source.metadata() {
+            Ok(ref m) if !self.skip_source_check && !m.is_file() => Some(m.file_type()),
+            _ => None,
+        };
+        targets
+            .iter()
+            .map(|&to| {
+                if let Some(file_type) = file_type {
+                    return Err(not_regular(file_type));
+                }
+                self.check_audit(None, to, Some(Mechanism::current()), false)?;
+                let dest = self.resolve_dest(to)?;
+                sys::reflink_file(source, &dest, self)?;
+                let dest = self.commit_dest(dest, to)?;
+                self.sync_parent(&dest)
+            })
+            .collect()
+    }
+
+    /// Opens and checks the source once, to reflink it to many destinations with the configured
+    /// options.
+    ///
+    /// See [`ReflinkSource`] for details.
+    pub fn reflink_source<P: AsRef<Path>>(&self, from: P) -> io::Result<ReflinkSource> {
+        ReflinkSource::new(self.clone(), from.as_ref())
+    }
+
+    /// Like [`reflink_or_copy`](ReflinkOptions::reflink_or_copy), but driven by the caller one
+    /// step at a time.
+    ///
+    /// See [`ReflinkCopyState`] for details.
+    pub fn reflink_or_copy_steps<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> ReflinkCopyState {
+        ReflinkCopyState::new(self.clone(), from.as_ref(), to.as_ref())
+    }
+
+    /// Clones the directory tree `from` into the new directory `to`, lazily, honoring the
+    /// configured options for every file.
+    ///
+    /// See [`reflink_dir_iter`](crate::reflink_dir_iter) for details.
+    pub fn reflink_dir_iter<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        from: P,
+        to: Q,
+    ) -> ReflinkDirIter {
+        ReflinkDirIter::new(self.clone(), from.as_ref(), to.as_ref())
+    }
+
+    /// Borrows the destination path unless the conflict policy picked another one, so that
+    /// the common case does not allocate.
+    fn reflink_inner<'a>(&self, from: &Path, to: &'a Path) -> io::Result<(Cow<'a, Path>, Sharing)> {
+        self.reflink_inner_with(from, to, || {})
+    }
+
+    /// `before_clone` runs between opening the source and cloning it, so that tests can
+    /// simulate races.
+    fn reflink_inner_with<'a, F: FnOnce()>(
+        &self,
+        from: &Path,
+        to: &'a Path,
+        before_clone: F,
+    ) -> io::Result<(Cow<'a, Path>, Sharing)> {
+        let src = self.open_source(from)?;
+        match dest_in_dir(from, to)? {
+            Some(to) => self
+                .reflink_handle(&src, from, &to, before_clone)
+                .map(|(dest, sharing)| (Cow::Owned(dest.into_owned()), sharing)),
+            None => self.reflink_handle(&src, from, to, before_clone),
+        }
+    }
+
+    /// Reflinks the source `src`, opened from `from` by `open_source`, to `to`.
+    pub(crate) fn reflink_handle<'a, F: FnOnce()>(
+        &self,
+        src: &fs::File,
+        from: &Path,
+        to: &'a Path,
+        before_clone: F,
+    ) -> io::Result<(Cow<'a, Path>, Sharing)> {
+        self.check_audit(Some(from), to, Some(Mechanism::current()), false)?;
+        self.observe_attempt(from, to);
+        let overwrites = matches!(self.on_conflict, Conflict::Overwrite | Conflict::Replace);
+        if overwrites && already_cloned(src, to) {
+            return self.observe_clone(Ok((Cow::Borrowed(to), Sharing::Shared)));
+        }
+        let dest = self.resolve_dest(to)?;
+        before_clone();
+        // the handle keeps cloning the checked file, even if the path is replaced meanwhile
+        let sharing = self.observe_clone(sys::reflink_file(src, &dest, self))?;
+        let dest = self.commit_dest(dest, to)?;
+        self.sync_parent(&dest)?;
+        Ok((dest, sharing))
+    }
+
+    /// Turns a `NotFound` error into `ReflinkError::SourceVanished` if the source passed the
+    /// check, but is gone now. Otherwise the error may as well concern the destination, e.g. a
+    /// missing parent directory.
+    pub(crate) fn vanished(&self, from: &Path, err: io::Error) -> io::Error {
+        if !self.skip_source_check
+            && err.kind() == io::ErrorKind::NotFound
+            && ReflinkError::from_io(&err).is_none()
+            && fs::symlink_metadata(from).is_err()
+        {
+            source_vanished(err)
+        } else {
+            err
+        }
+    }
+
+    /// Runs the post clone callback, if any, on the freshly cloned `dest`.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub(crate) fn run_post_clone(&self, dest: &fs::File) -> io::Result<()> {
+        match self.post_clone {
+            Some(PostCloneHook(ref post_clone)) => post_clone(dest),
+            None => Ok(()),
+        }
+    }
+
+    /// Compares the freshly cloned `to` with `src` as thoroughly as the verify level asks for.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub(crate) fn verify_clone(&self, src: &fs::File, to: &Path) -> io::Result<()> {
+        if self.verify == VerifyLevel::None {
+            return Ok(());
+        }
+        verify::verify(src, &fs::File::open(to)?, self.verify)
+    }
+
+    /// Fails with `ReflinkError::Cancelled` if the cancel callback asks to stop.
+    pub(crate) fn check_cancel(&self) -> io::Result<()> {
+        match self.cancel {
+            Some(CancelHook(ref cancel)) if cancel() => Err(cancelled()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a cancel callback is set, so copies have to be made in chunks to ask it.
+    pub(crate) fn is_cancellable(&self) -> bool {
+        self.cancel.is_some()
+    }
+
+    /// Flushes the freshly cloned `dest` to disk if the clone is to be durable.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    pub(crate) fn sync_clone(&self, dest: &fs::File) -> io::Result<()> {
+        if !self.durable {
+            return Ok(());
+        }
+        #[cfg(test)]
+        tests::SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+        dest.sync_all()
+    }
+
+    /// Flushes the directory entry of the clone `dest` to disk if the clone is to be durable.
+    ///
+    /// Windows offers no way to flush a directory, its metadata is journaled by NTFS and ReFS.
+    fn sync_parent(&self, dest: &Path) -> io::Result<()> {
+        if !self.durable || cfg!(not(unix)) {
+            return Ok(());
+        }
+        #[cfg(test)]
+        tests::SYNCS.with(|syncs| syncs.set(syncs.get() + 1));
+        let dir = match dest.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()
+    }
+
+    /// Runs the audit callback, if any, on the intended clone.
+    pub(crate) fn check_audit(
+        &self,
+        source: Option<&Path>,
+        dest: &Path,
+        mechanism: Option<Mechanism>,
+        copy_fallback: bool,
+    ) -> io::Result<()> {
+        match self.audit {
+            Some(ref audit) => audit.check(source, dest, mechanism, copy_fallback),
+            None => Ok(()),
+        }
+    }
+
+    /// Reports a clone of `from` to `to` about to be attempted to the observer, if any.
+    pub(crate) fn observe_attempt(&self, from: &Path, to: &Path) {
+        if let Some(ObserverHook(ref observer)) = self.observer {
+            observer.on_attempt(from, to);
+        }
+    }
+
+    /// Reports the outcome of a clone to the observer, if any, unless it failed. Whether a
+    /// failure is followed by a copy is only known to the caller.
+    pub(crate) fn observe_clone<T>(&self, res: io::Result<T>) -> io::Result<T> {
+        if let Some(ref observer) = self.observer {
+            observer.cloned(&res);
+        }
+        res
+    }
+
+    /// Reports the error of a clone which is replaced by a copy to the observer, if any.
+    pub(crate) fn observe_fallback(&self, err: io::Error) {
+        if let Some(ref observer) = self.observer {
+            observer.fallback(err);
+        }
+    }
+
+    /// Opens the source once and checks that it is a regular file with `fstat` on the handle,
+    /// so that the file which passed the check is the one that gets cloned.
+    pub(crate) fn open_source(&self, from: &Path) -> io::Result<fs::File> {
+        #[cfg(test)]
+        tests::SOURCE_OPENS.with(|opens| opens.set(opens.get() + 1));
+        let src = match sys::open_source(from, self) {
+            Ok(src) => src,
+            // e.g. Windows refuses to open directories without `FILE_FLAG_BACKUP_SEMANTICS`, and
+            // sockets cannot be opened at all
+            Err(err) if !self.skip_source_check => {
+                return Err(match fs::metadata(from) {
+                    Ok(ref m) if m.is_file() => err,
+                    Ok(m) => not_regular(m.file_type()),
+                    Err(_) => io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the source path is not an existing regular file",
+                    ),
+                })
+            }
+            Err(err) => return Err(err),
+        };
+        if !self.skip_source_check {
+            let metadata = src.metadata()?;
+            if !metadata.is_file() {
+                return Err(not_regular(metadata.file_type()));
+            }
+        }
+        Ok(src)
+    }
+
+    /// Applies the conflict policy to the destination.
+    pub(crate) fn resolve_dest<'a>(&self, to: &'a Path) -> io::Result<Cow<'a, Path>> {
+        match self.on_conflict {
+            Conflict::Error => {
+                if self.case_insensitive_conflicts && self.existing(to)?.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "a file whose name only differs in case already exists",
+                    ));
+                }
+                Ok(Cow::Borrowed(to))
+            }
+            Conflict::Overwrite => {
+                if let Some(existing) = self.existing(to)? {
+                    if !fs::symlink_metadata(&existing)?.is_dir() {
+                        fs::remove_file(existing)?;
+                    }
+                }
+                Ok(Cow::Borrowed(to))
+            }
+            Conflict::Suffix => {
+                if self.existing(to)?.is_none() {
+                    return Ok(Cow::Borrowed(to));
+                }
+                let mut n = 1;
+                let mut candidate = suffixed(to, n);
+                while self.existing(&candidate)?.is_some() {
+                    n += 1;
+                    candidate = suffixed(to, n);
+                }
+                Ok(Cow::Owned(candidate))
+            }
+            Conflict::Replace => temp_sibling(to).map(Cow::Owned),
+        }
+    }
+
+    /// Moves a destination written under the temporary name picked for `Conflict::Replace`
+    /// into place, and returns the final path.
+    pub(crate) fn commit_dest<'a>(
+        &self,
+        written: Cow<'a, Path>,
+        to: &'a Path,
+    ) -> io::Result<Cow<'a, Path>> {
+        if self.on_conflict != Conflict::Replace {
+            return Ok(written);
+        }
+        if let Err(err) = sys::replace(&written, to) {
+            let _ = fs::remove_file(&written);
+            return Err(err);
+        }
+        Ok(Cow::Borrowed(to))
+    }
+
+    /// Returns the existing entry `path` collides with, if any.
+    ///
+    /// That is `path` itself, or with `case_insensitive_conflicts` an entry of the same
+    /// directory whose name only differs in case.
+    fn existing<'a>(&self, path: &'a Path) -> io::Result<Option<Cow<'a, Path>>> {
+        if fs::symlink_metadata(path).is_ok() {
+            return Ok(Some(Cow::Borrowed(path)));
+        }
+        let name = match path.file_name() {
+            Some(name) if self.case_insensitive_conflicts => name,
+            _ => return Ok(None),
+        };
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let folded = name.to_str().map(str::to_lowercase);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if folded.is_some() && entry.file_name().to_str().map(str::to_lowercase) == folded {
+                return Ok(Some(Cow::Owned(entry.path())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Clones all of `src` into the new file `to` with a single range clone.
+fn reflink_whole_range(src: &fs::File, to: &Path) -> io::Result<()> {
+    let len = src.metadata()?.len();
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    // Windows only clones into the existing size of the destination
+    dest.set_len(len)?;
+    sys::reflink_range(src, 0, &dest, 0, len)?;
+    dest.set_permissions(src.metadata()?.permissions())
+}
+
+/// Whether `to` is already a clone of the open source `src`, so that overwriting it would change
+/// nothing.
+///
+/// Any error, e.g. because `to` does not exist or the platform cannot compare extents, counts
+/// as not cloned.
+fn already_cloned(src: &fs::File, to: &Path) -> bool {
+    let shared = || -> io::Result<bool> {
+        let dest = fs::File::open(to)?;
+        let (src_meta, dest_meta) = (src.metadata()?, dest.metadata()?);
+        if !dest_meta.is_file() || src_meta.len() != dest_meta.len() {
+            return Ok(false);
+        }
+        sys::extents_shared(src, &dest)
+    };
+    shared().unwrap_or(false)
+}
+
+/// Resolves a destination ending in a path separator, such as `dir/`, to the file named like
+/// the source in that directory. Returns `None` for any other destination.
+pub(crate) fn dest_in_dir(from: &Path, to: &Path) -> io::Result<Option<PathBuf>> {
+    let ends_in_separator = to
+        .as_os_str()
+        .to_string_lossy()
+        .chars()
+        .next_back()
+        .is_some_and(std::path::is_separator);
+    if !ends_in_separator {
+        return Ok(None);
+    }
+    match from.file_name() {
+        Some(name) => Ok(Some(to.join(name))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the source path has no file name to clone into the destination directory",
+        )),
+    }
+}
+
+/// Picks a temporary name next to `to` for `Conflict::Replace`, unique among processes and calls,
+/// e.g. `dir/.name.ext.1234.0.reflink`.
+fn temp_sibling(to: &Path) -> io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = to.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the destination path has no file name",
+        )
+    })?;
+    let mut temp = OsString::from(".");
+    temp.push(name);
+    temp.push(format!(
+        ".{}.{}.reflink",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok(to.with_file_name(temp))
+}
+
+/// Turns `dir/name.ext` into `dir/name (n).ext`.
+fn suffixed(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::new();
+    if let Some(stem) = path.file_stem() {
+        name.push(stem);
+    }
+    name.push(format!(" ({})", n));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+type PostClone = dyn Fn(&fs::File) -> io::Result<()> + Send + Sync;
+
+/// The callback set with [`ReflinkOptions::post_clone`].
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+#[derive(Clone)]
+pub(crate) struct PostCloneHook(Arc<PostClone>);
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+impl fmt::Debug for PostCloneHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PostCloneHook(..)")
+    }
+}
+
+/// The callback set with [`ReflinkOptions::cancel`].
+#[derive(Clone)]
+struct CancelHook(Arc<dyn Fn() -> bool + Send + Sync>);
+
+impl fmt::Debug for CancelHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CancelHook(..)")
+    }
+}
+
+/// The decision logic of `reflink_or_copy`, independent of the actual operations.
+///
+/// `reflink` returns the bytes it copied to complete a partial clone, if any. `copy` receives
+/// the error of the failed reflink.
+fn reflink_or_copy_with<R, C>(reflink: R, copy: C) -> io::Result<Option<u64>>
+where
+    R: FnOnce() -> io::Result<Option<u64>>,
+    C: FnOnce(io::Error) -> io::Result<u64>,
+{
+    match reflink() {
+        Ok(copied) => Ok(copied),
+        Err(e) if should_fall_back(&e) => copy(e).map(Some),
+        Err(e) => Err(e),
+    }
+}
+
+/// Decides whether a failed reflink should be retried as a conventional copy.
+///
+/// An invalid or missing source, or a full destination file system would make the copy fail
+/// the same way, so the reflink error is reported instead. Neither is a cancelled clone copied.
+pub(crate) fn should_fall_back(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        io::ErrorKind::InvalidInput | io::ErrorKind::NotFound | io::ErrorKind::StorageFull
+    ) && !is_cancelled(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    use crate::error::out_of_space;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// The sources opened by `open_source` on the current thread.
+        pub(super) static SOURCE_OPENS: Cell<usize> = const { Cell::new(0) };
+        /// The files and directories flushed by `sync_clone` and `sync_parent` on the current
+        /// thread.
+        pub(super) static SYNCS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn run(err: io::Error) -> (io::Result<Option<u64>>, bool) {
+        let copied = Cell::new(false);
+        let res = reflink_or_copy_with(
+            || Err(err),
+            |_| {
+                copied.set(true);
+                Ok(42)
+            },
+        );
+        (res, copied.get())
+    }
+
+    #[test]
+    fn reflink_success_does_not_copy() {
+        let copied = Cell::new(false);
+        let res = reflink_or_copy_with(
+            || Ok(None),
+            |_| {
+                copied.set(true);
+                Ok(42)
+            },
+        );
+        assert_eq!(res.unwrap(), None);
+        assert!(!copied.get());
+
+        // the rest of a partial clone was copied already
+        let res = reflink_or_copy_with(
+            || Ok(Some(7)),
+            |_| {
+                copied.set(true);
+                Ok(42)
+            },
+        );
+        assert_eq!(res.unwrap(), Some(7));
+        assert!(!copied.get());
+    }
+
+    #[test]
+    fn fallback_matrix() {
+        let reasons = [
+            UnsupportedReason::Os,
+            UnsupportedReason::Filesystem,
+            UnsupportedReason::Syscall,
+            UnsupportedReason::CrossDevice,
+        ];
+        for &reason in &reasons {
+            let (res, copied) = run(unsupported(reason, None));
+            assert!(copied, "{:?}", reason);
+            assert_eq!(res.unwrap(), Some(42));
+        }
+
+        let cases = [
+            (io::ErrorKind::AlreadyExists, true),
+            (io::ErrorKind::PermissionDenied, true),
+            (io::ErrorKind::Other, true),
+            (io::ErrorKind::InvalidInput, false),
+            (io::ErrorKind::NotFound, false),
+            (io::ErrorKind::StorageFull, false),
+        ];
+        for &(kind, falls_back) in &cases {
+            let (res, copied) = run(io::Error::from(kind));
+            assert_eq!(copied, falls_back, "{:?}", kind);
+            match res {
+                Ok(written) => assert_eq!(written, Some(42)),
+                Err(e) => assert_eq!(e.kind(), kind),
+            }
+        }
+
+        let (res, copied) = run(cancelled());
+        assert!(!copied);
+        assert!(is_cancelled(&res.unwrap_err()));
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    #[test]
+    fn out_of_space_does_not_copy() {
+        let (res, copied) = run(out_of_space(io::Error::from(io::ErrorKind::StorageFull)));
+        assert!(!copied);
+        let e = res.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+        assert!(matches!(
+            ReflinkError::from_io(&e),
+            Some(ReflinkError::OutOfSpace { .. })
+        ));
+    }
+
+    #[test]
+    fn source_replaced_before_clone() {
+        // the clone reads through the handle which passed the check, so removing the source or
+        // replacing it with a directory afterwards changes nothing
+        let replacements: &[bool] = if cfg!(unix) { &[false, true] } else { &[false] };
+        for &with_dir in replacements {
+            let dir = tempfile::tempdir().unwrap();
+            let src = dir.path().join("src.txt");
+            let dest = dir.path().join("dest.txt");
+            fs::write(&src, b"x").unwrap();
+
+            let res = ReflinkOptions::new().reflink_inner_with(&src, &dest, || {
+                fs::remove_file(&src).unwrap();
+                if with_dir {
+                    fs::create_dir(&src).unwrap();
+                }
+            });
+            match res {
+                Ok(_) => assert_eq!(fs::read(&dest).unwrap(), b"x"),
+                Err(e) => {
+                    assert!(
+                        matches!(
+                            ReflinkError::from_io(&e),
+                            Some(ReflinkError::Unsupported { .. })
+                        ),
+                        "{:?}",
+                        e
+                    );
+                    assert!(!dest.exists());
+                }
+            }
+        }
+
+        // a missing destination directory is not mistaken for a vanished source
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.txt");
+        fs::write(&src, b"x").unwrap();
+        let e = ReflinkOptions::new()
+            .reflink(&src, dir.path().join("missing/dest.txt"))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(ReflinkError::from_io(&e).is_none());
+    }
+
+    #[test]
+    fn copy_error_is_reported() {
+        let res = reflink_or_copy_with(
+            || Err(unsupported(UnsupportedReason::Filesystem, None)),
+            |_| Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        );
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn dest_is_borrowed_unless_suffixed() {
+        let dir = tempfile::tempdir().unwrap();
+        let free = dir.path().join("free.txt");
+        let taken = dir.path().join("taken.txt");
+        fs::write(&taken, b"x").unwrap();
+
+        let mut options = ReflinkOptions::new();
+        for &policy in &[Conflict::Error, Conflict::Suffix] {
+            options.on_conflict(policy);
+            assert!(matches!(
+                options.resolve_dest(&free).unwrap(),
+                Cow::Borrowed(_)
+            ));
+        }
+        options.on_conflict(Conflict::Overwrite);
+        assert!(matches!(
+            options.resolve_dest(&taken).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        fs::write(&taken, b"x").unwrap();
+        options.on_conflict(Conflict::Suffix);
+        match options.resolve_dest(&taken).unwrap() {
+            Cow::Owned(p) => assert_eq!(p, dir.path().join("taken (1).txt")),
+            Cow::Borrowed(_) => panic!("expected a suffixed destination"),
+        }
+    }
+
+    #[test]
+    fn reflink_source_opens_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.txt");
+        fs::write(&src, b"base").unwrap();
+
+        SOURCE_OPENS.with(|opens| opens.set(0));
+        let source = ReflinkOptions::new().reflink_source(&src).unwrap();
+        for i in 0..50 {
+            let to = dir.path().join(format!("{}.txt", i));
+            if let Err(e) = source.clone_to(&to) {
+                assert!(crate::error::is_unsupported(&e), "{:?}", e);
+            }
+        }
+        assert_eq!(SOURCE_OPENS.with(Cell::get), 1);
+        for i in 0..50 {
+            let _ = ReflinkOptions::new().reflink(&src, dir.path().join(format!("{}.bin", i)));
+        }
+        assert_eq!(SOURCE_OPENS.with(Cell::get), 51);
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        windows
+    ))]
+    #[test]
+    fn durable_clone_is_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.txt");
+        fs::write(&src, b"durable").unwrap();
+        let file = fs::File::open(&src).unwrap();
+
+        SYNCS.with(|syncs| syncs.set(0));
+        ReflinkOptions::new().sync_clone(&file).unwrap();
+        ReflinkOptions::new().sync_parent(&src).unwrap();
+        assert_eq!(SYNCS.with(Cell::get), 0);
+        let mut options = ReflinkOptions::new();
+        options.durable(true);
+        options.sync_clone(&file).unwrap();
+        options.sync_parent(&src).unwrap();
+        let parent = usize::from(cfg!(unix));
+        assert_eq!(SYNCS.with(Cell::get), 1 + parent);
+
+        // the clone and its directory are flushed once it succeeded, and never after a failure
+        SYNCS.with(|syncs| syncs.set(0));
+        let expected = match options.reflink(&src, dir.path().join("dest.txt")) {
+            Ok(()) => 1 + parent,
+            Err(e) => {
+                assert!(crate::error::is_unsupported(&e), "{:?}", e);
+                0
+            }
+        };
+        assert_eq!(SYNCS.with(Cell::get), expected);
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::options::dest_in_dir;
+use crate::ReflinkOptions;
+
+/// A source opened and checked once, to be reflinked to many destinations, e.g. one base image
+/// cloned into the disks of many virtual machines.
+///
+/// Created by [`ReflinkSource::open`] or [`ReflinkOptions::reflink_source`]. Each call to
+/// [`clone_to`](ReflinkSource::clone_to) behaves like [`reflink`](crate::reflink), but clones
+/// through the handle opened at the start instead of opening the source again, so replacing or
+/// removing the source path later does not affect the clones. Unlike
+/// [`reflink_fanout`](crate::reflink_fanout), the destinations need not be known upfront.
+///
+/// ```rust,no_run
+/// use reflink::ReflinkSource;
+///
+/// let base = ReflinkSource::open("base.img")?;
+/// for i in 0..50 {
+///     base.clone_to(format!("vm-{}.img", i))?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+/// # Implementation details per platform
+/// ## Linux / Android
+/// The descriptor is passed to `ioctl_ficlone` for every destination.
+/// ## OS X / ios
+/// Uses `fclonefileat`, which clones from the file descriptor as well.
+/// ## Windows
+/// The handle is passed to `FSCTL_DUPLICATE_EXTENTS_TO_FILE`; it shares reading, writing and
+/// deleting with other processes, so it does not lock the source while held.
+#[derive(Debug)]
+pub struct ReflinkSource {
+    options: ReflinkOptions,
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl ReflinkSource {
+    /// Opens the source `from` with the default options, failing like [`reflink`](crate::reflink)
+    /// if it is not a regular file.
+    pub fn open<P: AsRef<Path>>(from: P) -> io::Result<ReflinkSource> {
+        ReflinkOptions::new().reflink_source(from)
+    }
+
+    pub(crate) fn new(options: ReflinkOptions, from: &Path) -> io::Result<ReflinkSource> {
+        let file = options.open_source(from)?;
+        Ok(ReflinkSource {
+            options,
+            path: from.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Reflinks the source to `to`, honoring the options the source was opened with.
+    ///
+    /// See [`reflink`](crate::reflink) for the errors.
+    pub fn clone_to<Q: AsRef<Path>>(&self, to: Q) -> io::Result<()> {
+        let in_dir = dest_in_dir(&self.path, to.as_ref())?;
+        let to = in_dir.as_deref().unwrap_or_else(|| to.as_ref());
+        self.options
+            .reflink_handle(&self.file, &self.path, to, || {})
+            .map(drop)
+    }
+
+    /// The path the source was opened from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The open source file.
+    pub fn file(&self) -> &fs::File {
+        &self.file
+    }
+}
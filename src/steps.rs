@@ -0,0 +1,218 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::options::{dest_in_dir, should_fall_back};
+use crate::{sys, Conflict, Mechanism, ReflinkOptions};
+
+/// The number of bytes copied by each step of the copy fallback.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The outcome of a single [`ReflinkCopyState::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StepResult {
+    /// The reflink was not possible and the file is being copied; `copied` of `total` bytes are
+    /// done so far.
+    Progress { copied: u64, total: u64 },
+    /// The file is complete, with the same meaning as the result of
+    /// [`reflink_or_copy`](crate::reflink_or_copy): `None` if it was reflinked, or the number of
+    /// bytes copied.
+    Done(Option<u64>),
+}
+
+impl fmt::Display for StepResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepResult::Progress { copied, total } => {
+                write!(f, "copied {} of {} bytes", copied, total)
+            }
+            StepResult::Done(None) => f.write_str("reflinked"),
+            StepResult::Done(Some(written)) => write!(f, "copied {} bytes", written),
+        }
+    }
+}
+
+/// A reflink with a copy fallback which the caller drives step by step, e.g. from an event
+/// loop which must not block for long.
+///
+/// Created by [`ReflinkOptions::reflink_or_copy_steps`]. Nothing happens until the first call to
+/// [`step`](ReflinkCopyState::step), which attempts the reflink. If the reflink is not possible,
+/// each further step copies up to 1 MiB. The copy transfers the data and permissions only; the
+/// options for rich, preallocated or platform specific copies do not apply.
+///
+/// If a step fails, the partial destination is removed unless
+/// [`keep_on_failure`](ReflinkOptions::keep_on_failure) is set, and further steps return the
+/// same kind of error.
+///
+/// ```rust,no_run
+/// use reflink::{ReflinkOptions, StepResult};
+///
+/// let mut state = ReflinkOptions::new().reflink_or_copy_steps("src.img", "dest.img");
+/// loop {
+///     match state.step()? {
+///         StepResult::Progress { copied, total } => println!("{}/{} bytes", copied, total),
+///         StepResult::Done(_) => break,
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct ReflinkCopyState {
+    options: ReflinkOptions,
+    from: PathBuf,
+    to: PathBuf,
+    /// The final destination while `to` is the temporary file of `Conflict::Replace`.
+    target: Option<PathBuf>,
+    stage: Stage,
+}
+
+#[derive(Debug)]
+enum Stage {
+    Start,
+    Copying {
+        src: fs::File,
+        dest: fs::File,
+        copied: u64,
+        total: u64,
+        buf: Vec<u8>,
+    },
+    Done(Option<u64>),
+    Failed(io::ErrorKind),
+}
+
+impl ReflinkCopyState {
+    pub(crate) fn new(options: ReflinkOptions, from: &Path, to: &Path) -> Self {
+        ReflinkCopyState {
+            options,
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            target: None,
+            stage: Stage::Start,
+        }
+    }
+
+    /// The destination path, which differs from the requested one if
+    /// [`Conflict::Suffix`](crate::Conflict::Suffix) picked another name during the first step.
+    /// With [`Conflict::Replace`](crate::Conflict::Replace), this is the temporary file until
+    /// the last step moves it into place.
+    pub fn dest(&self) -> &Path {
+        &self.to
+    }
+
+    /// Performs the next step: the reflink attempt, or copying the next chunk.
+    pub fn step(&mut self) -> io::Result<StepResult> {
+        let res = match self.stage {
+            Stage::Start => self.start(),
+            Stage::Copying { .. } => self.copy_chunk(),
+            Stage::Done(written) => return Ok(StepResult::Done(written)),
+            Stage::Failed(kind) => {
+                return Err(io::Error::new(kind, "a previous step failed"));
+            }
+        };
+        let res = match res {
+            Ok(StepResult::Done(written)) => self.commit().map(|()| StepResult::Done(written)),
+            res => res,
+        };
+        if let Err(ref err) = res {
+            let created = matches!(self.stage, Stage::Copying { .. });
+            self.stage = Stage::Failed(err.kind());
+            if created && !self.options.keep_on_failure {
+                let _ = fs::remove_file(&self.to);
+            }
+        }
+        res
+    }
+
+    /// Attempts the reflink, and prepares the copy if it is not possible.
+    fn start(&mut self) -> io::Result<StepResult> {
+        if let Some(to) = dest_in_dir(&self.from, &self.to)? {
+            self.to = to;
+        }
+        let src = self.options.open_source(&self.from)?;
+        self.options
+            .check_audit(Some(&self.from), &self.to, Some(Mechanism::current()), true)?;
+        self.options.observe_attempt(&self.from, &self.to);
+        let dest = self.options.resolve_dest(&self.to)?.into_owned();
+        if self.options.on_conflict == Conflict::Replace {
+            self.target = Some(mem::replace(&mut self.to, dest));
+        } else {
+            self.to = dest;
+        }
+        let res = sys::reflink_file(&src, &self.to, &self.options);
+        match self.options.observe_clone(res) {
+            Ok(_) => {
+                self.stage = Stage::Done(None);
+                return Ok(StepResult::Done(None));
+            }
+            Err(err) if !should_fall_back(&err) => return Err(err),
+            Err(err) => self.options.observe_fallback(err),
+        }
+        // the copy reads through the same handle as the reflink attempt
+        let metadata = src.metadata()?;
+        let dest = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.to)?;
+        // the stage owns the destination from now on, so that failures remove it
+        self.stage = Stage::Copying {
+            src,
+            dest,
+            copied: 0,
+            total: metadata.len(),
+            buf: vec![0; CHUNK_SIZE],
+        };
+        Ok(StepResult::Progress {
+            copied: 0,
+            total: metadata.len(),
+        })
+    }
+
+    /// Moves the temporary file of `Conflict::Replace` into place, or removes it on failure.
+    fn commit(&mut self) -> io::Result<()> {
+        if let Some(target) = self.target.take() {
+            if let Err(err) = sys::replace(&self.to, &target) {
+                let _ = fs::remove_file(&self.to);
+                return Err(err);
+            }
+            self.to = target;
+        }
+        Ok(())
+    }
+
+    fn copy_chunk(&mut self) -> io::Result<StepResult> {
+        let (src, dest, copied, total, buf) = match self.stage {
+            Stage::Copying {
+                ref mut src,
+                ref mut dest,
+                ref mut copied,
+                ref mut total,
+                ref mut buf,
+            } => (src, dest, copied, total, buf),
+            _ => unreachable!(),
+        };
+        let n = loop {
+            match src.read(buf) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            dest.set_permissions(src.metadata()?.permissions())?;
+            let written = *copied;
+            self.stage = Stage::Done(Some(written));
+            return Ok(StepResult::Done(Some(written)));
+        }
+        dest.write_all(&buf[..n])?;
+        *copied += n as u64;
+        // the source may grow while it is copied
+        *total = (*total).max(*copied);
+        Ok(StepResult::Progress {
+            copied: *copied,
+            total: *total,
+        })
+    }
+}
@@ -0,0 +1,218 @@
+//! The size computations of the chunked clone and the SMB server-side copy on Windows, kept
+//! separate so they are tested on every platform.
+
+use std::cmp;
+use std::io;
+
+/// Rounds `num_to_round` to the next multiple of `multiple`, if `mutliple is a power of 2`
+///
+/// Fails instead of overflowing for sizes close to `i64::MAX`.
+pub fn round_up(num_to_round: i64, multiple: i64) -> io::Result<i64> {
+    assert!(multiple != 0 && ((multiple & (multiple - 1)) == 0));
+    num_to_round
+        .checked_add(multiple - 1)
+        .map(|num| num & -multiple)
+        .ok_or_else(|| overflow("the cluster aligned file size"))
+}
+
+/// The most bytes a single `FSCTL_DUPLICATE_EXTENTS_TO_FILE` call may clone.
+pub fn max_chunk_len(total_len: i64, cluster_size: i64) -> i64 {
+    // Must be smaller than 4GB; This is always a multiple of ClusterSize
+    if cluster_size == 0 {
+        total_len
+    } else {
+        (4 * 1024 * 1024 * 1024) - cluster_size
+    }
+}
+
+/// Splits a clone of `total_len` bytes into `(offset, len)` chunks, one per
+/// `FSCTL_DUPLICATE_EXTENTS_TO_FILE` call.
+pub fn clone_chunks(total_len: i64, cluster_size: i64) -> impl Iterator<Item = (i64, i64)> {
+    let max_len = max_chunk_len(total_len, cluster_size);
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if offset >= total_len {
+            return None;
+        }
+        // never exceeds `total_len`, so the offset cannot overflow
+        let len = cmp::min(total_len - offset, max_len);
+        let chunk = (offset, len);
+        offset += len;
+        Some(chunk)
+    })
+}
+
+/// The most bytes a single chunk of `FSCTL_SRV_COPYCHUNK_WRITE` may copy, by default.
+pub const COPYCHUNK_LEN: u64 = 1024 * 1024;
+
+/// The most chunks a single `FSCTL_SRV_COPYCHUNK_WRITE` call may pass, so that one request
+/// stays within the default limit of 16 MiB of SMB servers.
+pub const COPYCHUNK_COUNT: usize = 16;
+
+/// The `(offset, len)` chunks of the next `FSCTL_SRV_COPYCHUNK_WRITE` call, copying the file
+/// of `total_len` bytes from `offset` on.
+pub fn copychunk_batch(offset: u64, total_len: u64) -> Vec<(u64, u32)> {
+    let mut chunks = Vec::with_capacity(COPYCHUNK_COUNT);
+    let mut offset = offset;
+    while offset < total_len && chunks.len() < COPYCHUNK_COUNT {
+        let len = cmp::min(total_len - offset, COPYCHUNK_LEN);
+        chunks.push((offset, len as u32));
+        offset += len;
+    }
+    chunks
+}
+
+pub fn overflow(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} exceeds the range of FSCTL_DUPLICATE_EXTENTS_TO_FILE",
+            what
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clone_chunks, copychunk_batch, max_chunk_len, round_up, COPYCHUNK_LEN};
+
+    const CLUSTER: i64 = 64 * 1024;
+    const MAX_CHUNK: i64 = 4 * 1024 * 1024 * 1024 - CLUSTER;
+
+    #[test]
+    fn round_up_to_cluster() {
+        assert_eq!(round_up(0, CLUSTER).unwrap(), 0);
+        assert_eq!(round_up(1, CLUSTER).unwrap(), CLUSTER);
+        assert_eq!(round_up(CLUSTER, CLUSTER).unwrap(), CLUSTER);
+        assert_eq!(round_up(CLUSTER + 1, 4096).unwrap(), CLUSTER + 4096);
+
+        let largest = i64::MAX - i64::MAX % CLUSTER;
+        assert_eq!(round_up(largest, CLUSTER).unwrap(), largest);
+        assert!(round_up(largest + 1, CLUSTER).is_err());
+        assert!(round_up(i64::MAX, CLUSTER).is_err());
+    }
+
+    #[test]
+    fn chunks_cover_the_file() {
+        assert_eq!(clone_chunks(0, CLUSTER).count(), 0);
+        assert_eq!(clone_chunks(0, 0).count(), 0);
+        assert_eq!(
+            clone_chunks(CLUSTER, CLUSTER).collect::<Vec<_>>(),
+            [(0, CLUSTER)]
+        );
+
+        let total = 3 * MAX_CHUNK + CLUSTER;
+        let chunks: Vec<_> = clone_chunks(total, CLUSTER).collect();
+        assert_eq!(
+            chunks,
+            [
+                (0, MAX_CHUNK),
+                (MAX_CHUNK, MAX_CHUNK),
+                (2 * MAX_CHUNK, MAX_CHUNK),
+                (3 * MAX_CHUNK, CLUSTER)
+            ]
+        );
+    }
+
+    #[test]
+    fn single_chunk_limit() {
+        assert_eq!(max_chunk_len(5 * MAX_CHUNK, CLUSTER), MAX_CHUNK);
+        assert_eq!(max_chunk_len(5 * MAX_CHUNK, 0), 5 * MAX_CHUNK);
+        // everything up to the limit is cloned by one call
+        assert_eq!(clone_chunks(MAX_CHUNK, CLUSTER).count(), 1);
+        assert_eq!(clone_chunks(MAX_CHUNK + CLUSTER, CLUSTER).count(), 2);
+    }
+
+    #[test]
+    fn copychunk_batches_stay_within_server_limits() {
+        assert!(copychunk_batch(0, 0).is_empty());
+        assert_eq!(copychunk_batch(0, 10), [(0, 10)]);
+        assert_eq!(
+            copychunk_batch(0, COPYCHUNK_LEN + 1),
+            [(0, COPYCHUNK_LEN as u32), (COPYCHUNK_LEN, 1)]
+        );
+
+        let total = 40 * COPYCHUNK_LEN + 7;
+        let mut offset = 0;
+        let mut batches = 0;
+        while offset < total {
+            let batch = copychunk_batch(offset, total);
+            assert!(batch.len() <= 16);
+            assert!(batch.iter().map(|&(_, len)| u64::from(len)).sum::<u64>() <= 16 << 20);
+            for (chunk_offset, len) in batch {
+                // no gaps, no overlaps
+                assert_eq!(chunk_offset, offset);
+                offset += u64::from(len);
+            }
+            batches += 1;
+        }
+        assert_eq!(offset, total);
+        assert_eq!(batches, 3);
+        // a short write resumes at the first byte the server did not copy
+        assert_eq!(copychunk_batch(total - 7, total), [(total - 7, 7)]);
+        assert!(copychunk_batch(total, total).is_empty());
+    }
+
+    #[test]
+    fn chunks_at_extreme_sizes() {
+        let largest = i64::MAX - i64::MAX % CLUSTER;
+        let mut chunks = clone_chunks(largest, CLUSTER);
+        assert_eq!(chunks.next(), Some((0, MAX_CHUNK)));
+        assert_eq!(chunks.next(), Some((MAX_CHUNK, MAX_CHUNK)));
+
+        assert_eq!(
+            clone_chunks(i64::MAX, 0).collect::<Vec<_>>(),
+            [(0, i64::MAX)]
+        );
+    }
+
+    /// Checks the invariants of the chunked clone for a source of `len` bytes.
+    fn check(len: i64, cluster_size: i64, max_chunks: usize) {
+        let total = if cluster_size == 0 {
+            len
+        } else {
+            match round_up(len, cluster_size) {
+                Ok(total) => total,
+                // only sizes within a cluster of `i64::MAX` may overflow
+                Err(_) => return assert!(len > i64::MAX - cluster_size + 1),
+            }
+        };
+        assert!(total >= len, "{} {}", len, cluster_size);
+        if cluster_size != 0 {
+            assert_eq!(total % cluster_size, 0);
+            assert!(total - len < cluster_size);
+        }
+
+        let mut end = 0;
+        for (offset, chunk_len) in clone_chunks(total, cluster_size).take(max_chunks) {
+            // no gaps, no overlaps
+            assert_eq!(offset, end);
+            assert!(chunk_len > 0);
+            if cluster_size != 0 {
+                assert_eq!(offset % cluster_size, 0);
+                assert_eq!(chunk_len % cluster_size, 0);
+                assert!(chunk_len < 4 * 1024 * 1024 * 1024);
+            }
+            end = offset + chunk_len;
+        }
+        if clone_chunks(total, cluster_size).nth(max_chunks).is_none() {
+            assert_eq!(end, total);
+        }
+    }
+
+    #[test]
+    fn chunk_invariants_hold_for_random_sizes() {
+        let mut rng = fastrand::Rng::with_seed(0x0072_6566_6c69_6e6b);
+        for &cluster_size in &[0, 4 * 1024, 64 * 1024] {
+            for len in (0..16).chain((i64::MAX - 16)..=i64::MAX) {
+                check(len, cluster_size, 4);
+            }
+            for _ in 0..2_000 {
+                // up to 64 TiB, so every chunk can be checked
+                check(rng.i64(0..1 << 46), cluster_size, usize::MAX);
+                // anywhere up to the limit, checking the first chunks
+                check(rng.i64(0..=i64::MAX), cluster_size, 4);
+            }
+        }
+    }
+}
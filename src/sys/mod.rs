@@ -1,24 +1,320 @@
 #[cfg(unix)]
 mod unix;
 #[cfg(unix)]
-pub use self::unix::reflink;
+pub use self::unix::*;
+#[cfg(any(windows, test))]
+mod chunks;
 #[cfg(windows)]
 mod windows;
 #[cfg(windows)]
-pub use self::windows::reflink;
-#[cfg(not(any(unix, windows)))]
+pub use self::windows::*;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
 mod others;
 #[cfg(not(any(unix, windows)))]
-pub use self::others::reflink;
-
-fn _reflink_not_supported() -> std::io::Result<()> {
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        format!(
-            "Operation not supported on {}-{}-{}",
-            std::env::consts::ARCH,
-            std::env::consts::OS,
-            std::env::consts::FAMILY
-        ),
+pub use self::others::*;
+
+/// Returns how many bytes of the physical ranges `b` (start and length) lie within the ranges
+/// `a`, counting bytes covered by several ranges of `a` once.
+#[cfg(any(target_os = "linux", target_os = "android", windows, test))]
+fn overlap(mut a: Vec<(u64, u64)>, b: &[(u64, u64)]) -> u64 {
+    a.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(a.len());
+    for (start, len) in a {
+        let end = start.saturating_add(len);
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    b.iter()
+        .map(|&(start, len)| {
+            let end = start.saturating_add(len);
+            merged
+                .iter()
+                .map(|&(s, e)| e.min(end).saturating_sub(s.max(start)))
+                .sum::<u64>()
+        })
+        .sum()
+}
+
+/// Streams a non-regular source such as a FIFO into `to`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn copy_stream(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<u64> {
+    let mut src = std::fs::File::open(from)?;
+    let mut dest = std::fs::File::create(to)?;
+    std::io::copy(&mut src, &mut dest)
+}
+
+/// Moves the temporary file `from` over `to` for `Conflict::Replace`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn replace(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    std::fs::rename(from, to)
+}
+
+/// Reflinks `src` to `to` like `reflink_file`. Only the chunked clone on Windows can fail
+/// halfway and be completed by a copy, elsewhere nothing is ever copied.
+#[cfg(not(windows))]
+pub fn reflink_file_completing(
+    src: &std::fs::File,
+    to: &std::path::Path,
+    options: &crate::ReflinkOptions,
+) -> std::io::Result<Option<u64>> {
+    reflink_file(src, to, options).map(|_| None)
+}
+
+/// Copies with `copy_file_range`, which only exists on Linux and Android.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn copy_file_range(_src: &std::fs::File, _to: &std::path::Path) -> std::io::Result<u64> {
+    Err(crate::error::unsupported(
+        crate::error::UnsupportedReason::Os,
+        None,
     ))
 }
+
+/// Checks that the range clone of `len` bytes from `src_offset` to `dest_offset` is aligned to
+/// `alignment` (the `unit` of the file system, e.g. its block size), naming the offending value
+/// instead of leaving the clone to fail with a bare `EINVAL`.
+///
+/// With `unaligned_tail`, a `len` which reaches the end of the source of `src_len` bytes may be
+/// unaligned.
+#[cfg(any(target_os = "linux", target_os = "android", windows))]
+fn check_alignment(
+    (src_offset, dest_offset, len): (u64, u64, u64),
+    src_len: u64,
+    alignment: u64,
+    unit: &str,
+    unaligned_tail: bool,
+) -> std::io::Result<()> {
+    let tail = unaligned_tail && src_offset.saturating_add(len) >= src_len;
+    for &(name, value, exempt) in &[
+        ("source offset", src_offset, false),
+        ("destination offset", dest_offset, false),
+        ("length", len, tail),
+    ] {
+        if alignment > 1 && value % alignment != 0 && !exempt {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} {} is not a multiple of the {} {}",
+                    name, value, unit, alignment
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Copies `from` to `to` with `fs::copy`, or in chunks if the copy may be cancelled.
+fn copy_plain(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    options: &crate::ReflinkOptions,
+) -> std::io::Result<u64> {
+    use std::fs;
+
+    if !options.is_cancellable() {
+        return fs::copy(from, to);
+    }
+    let src = fs::File::open(from)?;
+    let metadata = src.metadata()?;
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    let written = copy_range(&src, &dest, u64::MAX, options)?;
+    dest.set_permissions(metadata.permissions())?;
+    Ok(written)
+}
+
+/// Copies up to `len` bytes from the current position of `src` to `dest`, asking the cancel
+/// callback of `options` before every chunk.
+fn copy_range(
+    mut src: &std::fs::File,
+    mut dest: &std::fs::File,
+    len: u64,
+    options: &crate::ReflinkOptions,
+) -> std::io::Result<u64> {
+    use std::io::{self, Read, Write};
+
+    // the same chunk size as `std::io::copy` would use for large files
+    const CHUNK_LEN: usize = 1024 * 1024;
+
+    if !options.is_cancellable() {
+        return io::copy(&mut src.take(len), &mut dest);
+    }
+    let mut buf = vec![0; CHUNK_LEN];
+    let mut written = 0;
+    while written < len {
+        options.check_cancel()?;
+        let want = std::cmp::min(len - written, CHUNK_LEN as u64) as usize;
+        let read = match src.read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        dest.write_all(&buf[..read])?;
+        written += read as u64;
+    }
+    Ok(written)
+}
+
+/// Copies `from` to `to` like `fs::copy`, reserving space for the whole file before copying.
+///
+/// Fails early if the destination volume cannot hold the file. The destination is removed if
+/// the preallocation fails.
+#[cfg(any(target_os = "linux", target_os = "android", windows))]
+fn copy_preallocated(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    preallocate: fn(&std::fs::File, u64) -> std::io::Result<()>,
+    options: &crate::ReflinkOptions,
+) -> std::io::Result<u64> {
+    use std::fs;
+
+    let src = fs::File::open(from)?;
+    let metadata = src.metadata()?;
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    if let Err(err) = preallocate(&dest, metadata.len()) {
+        drop(dest);
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+    let written = copy_range(&src, &dest, u64::MAX, options)?;
+    // in case the source shrunk in the meantime
+    dest.set_len(written)?;
+    dest.set_permissions(metadata.permissions())?;
+    Ok(written)
+}
+
+/// Byte ranges of a file, as offset and length.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+type Ranges = Vec<(u64, u64)>;
+
+/// Whether `copy` writes only the data ranges of `from`: always with `preserve_sparseness`, and
+/// for sources which look sparse unless a preallocated copy was asked for.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+fn copies_sparsely(from: &std::path::Path, options: &crate::ReflinkOptions) -> bool {
+    options.preserve_sparseness || (!options.preallocate_fallback && is_sparse(from))
+}
+
+/// Whether the file at `from` has holes, judged by its allocated blocks falling short of its
+/// size. Compressed files look sparse as well, which only costs the search for their holes.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn is_sparse(from: &std::path::Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::metadata(from)
+        .map(|m| m.blocks().saturating_mul(512) < m.len())
+        .unwrap_or(false)
+}
+
+/// Copies `from` to `to`, writing only the data ranges (offset and length) reported by
+/// `data_ranges` and leaving holes everywhere else, so that the copy is as sparse as the source.
+///
+/// `prepare` runs on the source and the empty destination before any data is written, e.g. to
+/// mark the destination sparse.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+fn copy_sparse(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    data_ranges: fn(&std::fs::File) -> std::io::Result<Ranges>,
+    prepare: fn(&std::fs::File, &std::fs::File) -> std::io::Result<()>,
+    options: &crate::ReflinkOptions,
+) -> std::io::Result<u64> {
+    use std::fs;
+    use std::io::{Seek, SeekFrom};
+
+    let mut src = fs::File::open(from)?;
+    let metadata = src.metadata()?;
+    let mut dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    prepare(&src, &dest)?;
+    for (offset, len) in data_ranges(&src)? {
+        src.seek(SeekFrom::Start(offset))?;
+        dest.seek(SeekFrom::Start(offset))?;
+        copy_range(&src, &dest, len, options)?;
+    }
+    // the trailing hole, if any
+    dest.set_len(metadata.len())?;
+    dest.set_permissions(metadata.permissions())?;
+    Ok(metadata.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::overlap;
+
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    #[test]
+    fn alignment_allows_only_a_tail_at_the_end() {
+        use super::check_alignment;
+
+        let check = |range, tail| check_alignment(range, 10_000, 4096, "block size", tail);
+        assert!(check((0, 0, 0), false).is_ok());
+        assert!(check((4096, 8192, 4096), false).is_ok());
+        let e = check((4096, 100, 4096), true).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "destination offset 100 is not a multiple of the block size 4096"
+        );
+        // the last 1808 bytes of the source
+        assert!(check((8192, 0, 1808), true).is_ok());
+        assert!(check((8192, 0, 5000), true).is_ok());
+        assert!(check((8192, 0, 1808), false).is_err());
+        assert!(check((4096, 0, 1808), true).is_err());
+        // nothing to align to
+        assert!(check_alignment((1, 2, 3), 10, 0, "cluster size", false).is_ok());
+        assert!(check_alignment((1, 2, 3), 10, 1, "block size", false).is_ok());
+    }
+
+    #[test]
+    fn overlap_counts_shared_bytes_once() {
+        assert_eq!(overlap(vec![], &[(0, 10)]), 0);
+        assert_eq!(overlap(vec![(0, 10)], &[]), 0);
+        assert_eq!(overlap(vec![(0, 10)], &[(0, 10)]), 10);
+        assert_eq!(overlap(vec![(20, 10), (0, 10)], &[(5, 20)]), 10);
+        // overlapping and adjacent ranges of `a` are merged
+        assert_eq!(overlap(vec![(0, 10), (5, 10), (15, 5)], &[(0, 30)]), 20);
+        // ranges of `b` are counted separately
+        assert_eq!(overlap(vec![(0, 10)], &[(0, 10), (0, 10)]), 20);
+        assert_eq!(overlap(vec![(u64::MAX - 5, 10)], &[(u64::MAX - 10, 20)]), 5);
+    }
+}
@@ -1,6 +1,59 @@
+use std::fs;
 use std::io;
 use std::path::Path;
 
-pub fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
-    super::_reflink_not_supported()
+use crate::error::{unsupported, UnsupportedReason};
+use crate::{ReflinkOptions, Sharing};
+
+pub fn reflink_file(_src: &fs::File, _to: &Path, _options: &ReflinkOptions) -> io::Result<Sharing> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn os_version() -> Option<String> {
+    None
+}
+
+pub fn can_reflink(_from: &Path, _dest_dir: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+pub fn extents_shared(_a: &fs::File, _b: &fs::File) -> io::Result<bool> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn shared_bytes(_a: &fs::File, _b: &fs::File) -> io::Result<u64> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn reflink_range(
+    _src: &fs::File,
+    _src_offset: u64,
+    _dest: &fs::File,
+    _dest_offset: u64,
+    _len: u64,
+) -> io::Result<()> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn clone_alignment(_file: &fs::File) -> io::Result<u64> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn copy(from: &Path, to: &Path, options: &ReflinkOptions) -> io::Result<u64> {
+    crate::sys::copy_plain(from, to, options)
+}
+
+#[cfg(not(unix))]
+pub fn device_id(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+pub fn hard_link_id(_path: &Path) -> io::Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+pub fn open_source(path: &Path, _options: &ReflinkOptions) -> io::Result<fs::File> {
+    fs::File::open(path)
 }
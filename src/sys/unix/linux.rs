@@ -0,0 +1,929 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::ptr;
+
+use super::{classify, copy_times, data_ranges};
+use crate::error::{not_regular, unsupported, ReflinkError, UnsupportedReason};
+use crate::{ReflinkOptions, Sharing};
+
+// TODO is this equal on all archs? Just tested on x86_64 and x86.
+macro_rules! IOCTL_FICLONE {
+    () => {
+        0x40049409
+    };
+}
+
+// linux/fs.h
+const FS_IMMUTABLE_FL: libc::c_int = 0x0000_0010;
+const FS_APPEND_FL: libc::c_int = 0x0000_0020;
+const FS_COMPR_FL: libc::c_int = 0x0000_0004;
+const FS_NOCOMP_FL: libc::c_int = 0x0000_0400;
+/// The flags which can be changed by unprivileged users (or with `CAP_LINUX_IMMUTABLE`).
+const FS_FL_USER_MODIFIABLE: libc::c_int = 0x0003_80ff;
+
+pub fn reflink_file(src: &fs::File, to: &Path, options: &ReflinkOptions) -> io::Result<Sharing> {
+    // pass O_EXCL to mimic macos behaviour, unless the caller opted out
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create_new(!options.reuse_existing_dest)
+        .create(true)
+        .truncate(options.reuse_existing_dest)
+        .mode(options.dest_mode.unwrap_or(0o666))
+        .open(to)?;
+    let res = ficlone(src, &dest)
+        .and_then(|()| finish(src, &dest, options))
+        .and_then(|()| options.verify_clone(src, to))
+        .and_then(|()| options.run_post_clone(&dest))
+        .and_then(|()| options.sync_clone(&dest));
+    if res.is_ok() && options.drop_caches {
+        drop_caches(src);
+        drop_caches(&dest);
+    }
+
+    if res.is_err() && !options.keep_on_failure {
+        // remove the file that was created.
+        let _ = fs::remove_file(to);
+    }
+    res.map(|()| Sharing::Shared)
+}
+
+pub fn reflink_to_tmpfile(src: &fs::File, dir: &fs::File) -> io::Result<fs::File> {
+    let dest = open_tmpfile(dir)?;
+    ficlone(src, &dest)?;
+    Ok(dest)
+}
+
+/// Opens a new, unnamed file in `dir` with `O_TMPFILE`.
+fn open_tmpfile(dir: &fs::File) -> io::Result<fs::File> {
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            b".\0".as_ptr() as *const libc::c_char,
+            libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC,
+            0o666 as libc::c_uint,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+// linux/openat2.h
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+pub fn reflink_beneath(dir: &fs::File, from: &Path, to: &Path) -> io::Result<()> {
+    let src = openat2(dir, from, libc::O_RDONLY | libc::O_NONBLOCK)?;
+    let metadata = src.metadata()?;
+    if !metadata.is_file() {
+        return Err(not_regular(metadata.file_type()));
+    }
+    let name = match to.file_name() {
+        Some(name) => CString::new(name.as_bytes())?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the destination path has no file name",
+            ))
+        }
+    };
+    let parent = match to.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    // the destination is created and removed relative to its checked parent, so that swapping
+    // a component for a symlink in the meantime cannot redirect either
+    let parent = openat2(dir, parent, libc::O_PATH | libc::O_DIRECTORY)?;
+    let fd = unsafe {
+        libc::openat(
+            parent.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0o666 as libc::c_uint,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let dest = unsafe { fs::File::from_raw_fd(fd) };
+    let res = ficlone(&src, &dest);
+    if res.is_err() {
+        unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), 0) };
+    }
+    res
+}
+
+/// Opens `path` relative to `dir` with `openat2`, refusing anything outside of `dir` and any
+/// symlinks.
+fn openat2(dir: &fs::File, path: &Path, flags: libc::c_int) -> io::Result<fs::File> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let how = OpenHow {
+        flags: (flags | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS | RESOLVE_NO_MAGICLINKS,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd == -1 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EXDEV) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("the path escapes the directory: {}", err),
+            ),
+            Some(libc::ELOOP) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("the path contains a symlink: {}", err),
+            ),
+            // Linux before 5.6
+            Some(libc::ENOSYS) => unsupported(UnsupportedReason::Syscall, Some(err)),
+            _ => err,
+        });
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd as libc::c_int) })
+}
+
+/// The kernel release, e.g. `6.1.0-18-amd64`.
+pub fn os_version() -> Option<String> {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } == -1 {
+        return None;
+    }
+    let release = unsafe { std::ffi::CStr::from_ptr(name.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+pub fn can_reflink(from: &Path, dest_dir: &Path) -> io::Result<bool> {
+    let src = fs::File::open(from)?;
+    let dir = fs::File::open(dest_dir)?;
+    if !same_device(&src, &dir) {
+        return Ok(false);
+    }
+    let dest = match open_tmpfile(&dir) {
+        Ok(dest) => dest,
+        // file systems without O_TMPFILE
+        Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    // a single block is enough to tell, a length of 0 clones up to the end of the file
+    let block = clone_alignment(&src)?;
+    let len = if src.metadata()?.len() > block {
+        block
+    } else {
+        0
+    };
+    match reflink_range(&src, 0, &dest, 0, len) {
+        Ok(()) => Ok(true),
+        // OpenZFS clones through `copy_file_range` instead
+        Err(ref e) if crate::error::is_unsupported(e) => Ok(zfs_clones(&src, &dest)),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn extents_shared(a: &fs::File, b: &fs::File) -> io::Result<bool> {
+    let (a, b) = (fiemap(a)?, fiemap(b)?);
+    Ok(!a.is_empty() && a == b)
+}
+
+pub fn shared_bytes(a: &fs::File, b: &fs::File) -> io::Result<u64> {
+    let physical = |extents: Vec<(u64, u64, u64)>| {
+        extents
+            .into_iter()
+            .map(|(_, physical, length)| (physical, length))
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (physical(fiemap(a)?), physical(fiemap(b)?));
+    Ok(crate::sys::overlap(a, &b))
+}
+
+pub fn reflink_range(
+    src: &fs::File,
+    src_offset: u64,
+    dest: &fs::File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    // a closed descriptor fails here as well, and is explained by the clone below
+    if let (Ok(alignment), Ok(metadata)) = (clone_alignment(src), src.metadata()) {
+        crate::sys::check_alignment(
+            (src_offset, dest_offset, len),
+            metadata.len(),
+            alignment,
+            "block size",
+            true,
+        )?;
+    }
+    let range = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset,
+    };
+    // http://man7.org/linux/man-pages/man2/ioctl_ficlonerange.2.html
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONERANGE, &range) } == -1 {
+        Err(classify_clone(io::Error::last_os_error(), src))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn clone_alignment(file: &fs::File) -> io::Result<u64> {
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut buf) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf.f_bsize as u64)
+}
+
+pub fn copy(from: &Path, to: &Path, options: &ReflinkOptions) -> io::Result<u64> {
+    if options.preserve_compression {
+        // the copies below truncate the destination, but keep its inode flags and xattrs
+        copy_compression(from, to)?;
+    }
+    let written = if crate::sys::copies_sparsely(from, options) {
+        crate::sys::copy_sparse(from, to, data_ranges, |_, _| Ok(()), options)?
+    } else if options.preallocate_fallback {
+        crate::sys::copy_preallocated(from, to, preallocate, options)?
+    } else if options.is_cancellable() {
+        crate::sys::copy_plain(from, to, options)?
+    } else if let Some(written) = copy_server_side(from, to)? {
+        written
+    } else {
+        // tries `copy_file_range`, then `sendfile` for kernels without it, and only then a
+        // read/write loop, handling short transfers and files beyond 2 GiB
+        fs::copy(from, to)?
+    };
+    if options.rich_copy_fallback
+        || options.copy_file_flags
+        || options.chown.is_some()
+        || options.dest_mode.is_some()
+    {
+        let src = fs::File::open(from)?;
+        // none of the changes below need write access, which a copy of a read-only source
+        // would not grant
+        let dest = fs::File::open(to)?;
+        if let Some((uid, gid)) = options.chown {
+            if let Err(err) = chown(&dest, uid, gid) {
+                return Err(discard(dest, to, err));
+            }
+        }
+        if let Some(mode) = options.dest_mode {
+            if let Err(err) = dest.set_permissions(fs::Permissions::from_mode(mode)) {
+                return Err(discard(dest, to, err));
+            }
+        }
+        if options.rich_copy_fallback {
+            if let Err(err) = copy_xattrs(&src, &dest).and_then(|()| copy_times(&src, &dest)) {
+                return Err(discard(dest, to, err));
+            }
+        }
+        if options.copy_file_flags {
+            if let Err(err) = copy_file_flags(&src, &dest) {
+                return Err(discard(dest, to, err));
+            }
+        }
+    }
+    if options.drop_caches {
+        drop_caches(&fs::File::open(from)?);
+        drop_caches(&fs::File::open(to)?);
+    }
+    Ok(written)
+}
+
+/// Removes the copy `to`, open as `dest`, after adjusting it failed with `err`, so that an
+/// incomplete copy is not mistaken for a finished one. Returns `err`.
+fn discard(dest: fs::File, to: &Path, err: io::Error) -> io::Error {
+    drop(dest);
+    let _ = fs::remove_file(to);
+    err
+}
+
+/// Moves the temporary file `from` over `to` for `Conflict::Replace`.
+///
+/// An existing destination is swapped with `RENAME_EXCHANGE` and removed afterwards from its
+/// temporary name, a missing one is created with `RENAME_NOREPLACE`. If the destination appears
+/// or vanishes in between, or `renameat2` is not available, a plain `rename` does the job.
+pub fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    let (src, dest) = (
+        CString::new(from.as_os_str().as_bytes())?,
+        CString::new(to.as_os_str().as_bytes())?,
+    );
+    let existing = match fs::symlink_metadata(to) {
+        // exchanging would move the directory to the temporary name instead of failing
+        Ok(ref m) if m.is_dir() => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    let flags = if existing {
+        libc::RENAME_EXCHANGE
+    } else {
+        libc::RENAME_NOREPLACE
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            src.as_ptr(),
+            libc::AT_FDCWD,
+            dest.as_ptr(),
+            flags,
+        )
+    };
+    if ret != -1 {
+        if existing {
+            // the old destination, which nobody can open by its name any more
+            let _ = fs::remove_file(from);
+        }
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // ENOENT and EEXIST: the destination vanished or appeared in the meantime; ENOSYS and
+        // EINVAL: Linux before 3.15, or a file system without support
+        Some(libc::ENOENT | libc::EEXIST | libc::ENOSYS | libc::EINVAL) => fs::rename(from, to),
+        _ => Err(err),
+    }
+}
+
+/// Streams a non-regular source such as a FIFO into `to` with `splice`.
+pub fn copy_stream(from: &Path, to: &Path) -> io::Result<u64> {
+    let mut src = fs::File::open(from)?;
+    let mut dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    let mut written = 0u64;
+    loop {
+        let ret = unsafe {
+            libc::splice(
+                src.as_raw_fd(),
+                ptr::null_mut(),
+                dest.as_raw_fd(),
+                ptr::null_mut(),
+                1 << 20,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        match ret {
+            -1 => {
+                let err = io::Error::last_os_error();
+                // splice needs a pipe on one side, which e.g. character devices are not
+                if err.raw_os_error() == Some(libc::EINVAL) && written == 0 {
+                    return io::copy(&mut src, &mut dest);
+                }
+                return Err(err);
+            }
+            0 => return Ok(written),
+            n => written += n as u64,
+        }
+    }
+}
+
+/// Copies `from` to `to` with `copy_file_range` if both are on NFS, so that NFSv4.2 servers
+/// copy the data themselves (`COPY` operation) instead of sending it through the client.
+///
+/// Returns `None` if the files are not on NFS, or the server cannot copy across the two exports.
+fn copy_server_side(from: &Path, to: &Path) -> io::Result<Option<u64>> {
+    let src = fs::File::open(from)?;
+    if fs_magic(&src) != Some(NFS_SUPER_MAGIC) {
+        return Ok(None);
+    }
+    let metadata = src.metadata()?;
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    if fs_magic(&dest) != Some(NFS_SUPER_MAGIC) {
+        return Ok(None);
+    }
+
+    let written = match copy_file_range_all(&src, &dest)? {
+        Some(written) => written,
+        None => return Ok(None),
+    };
+    dest.set_permissions(metadata.permissions())?;
+    Ok(Some(written))
+}
+
+/// Copies `src` into the new file `to` with `copy_file_range`, which lets the file system copy
+/// the data without passing it through user space, or share blocks where it can.
+///
+/// Fails with `UnsupportedReason::Syscall` if the kernel or file system cannot do so.
+pub fn copy_file_range(src: &fs::File, to: &Path) -> io::Result<u64> {
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    match copy_file_range_all(src, &dest)? {
+        Some(written) => {
+            dest.set_permissions(src.metadata()?.permissions())?;
+            Ok(written)
+        }
+        None => Err(unsupported(UnsupportedReason::Syscall, None)),
+    }
+}
+
+/// Copies all of `src`, from its start, to the position of `dest` with `copy_file_range`.
+///
+/// Returns `None` if `copy_file_range` refuses the files before copying anything, e.g. across
+/// file systems on kernels before 5.3 or between NFS exports the server cannot copy across.
+fn copy_file_range_all(src: &fs::File, dest: &fs::File) -> io::Result<Option<u64>> {
+    let mut offset: libc::loff_t = 0;
+    loop {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                src.as_raw_fd(),
+                &mut offset as *mut libc::loff_t,
+                dest.as_raw_fd(),
+                ptr::null_mut::<libc::loff_t>(),
+                1usize << 30,
+                0u32,
+            )
+        };
+        match ret {
+            -1 => {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(libc::EXDEV)
+                    | Some(libc::ENOSYS)
+                    | Some(libc::EOPNOTSUPP)
+                    | Some(libc::EINVAL)
+                        if offset == 0 =>
+                    {
+                        Ok(None)
+                    }
+                    _ => Err(err),
+                };
+            }
+            0 => return Ok(Some(offset as u64)),
+            // the kernel advances `offset`
+            _ => {}
+        }
+    }
+}
+
+/// Clones all of `src` into `dest`, atomically with respect to writes to `src`.
+fn ficlone(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    let ret = unsafe {
+        // http://man7.org/linux/man-pages/man2/ioctl_ficlonerange.2.html
+        libc::ioctl(dest.as_raw_fd(), IOCTL_FICLONE!(), src.as_raw_fd())
+    };
+    if ret == -1 {
+        let err = io::Error::last_os_error();
+        let err = if is_drvfs(dest) && on_wsl() {
+            unsupported(
+                UnsupportedReason::Filesystem,
+                Some(io::Error::other(format!(
+                    "{}; Windows drives mounted into WSL (drvfs) do not support reflinks",
+                    err
+                ))),
+            )
+        } else if err.raw_os_error() == Some(libc::EXDEV) && same_device(src, dest) {
+            // Kernels before 5.18 refuse to clone across mounts, even if both are mounts of
+            // the same file system (e.g. bind mounts in containers).
+            unsupported(UnsupportedReason::Syscall, Some(err))
+        } else {
+            classify_clone(err, src)
+        };
+        // OpenZFS clones through `copy_file_range` instead
+        zfs_clone(src, dest, err)
+    } else {
+        Ok(())
+    }
+}
+
+/// Clones all of `src` into `dest` with `copy_file_range` if both are on ZFS, where OpenZFS 2.2
+/// and later share the blocks if `zfs_bclone_enabled` is set, instead of `FICLONE`.
+///
+/// Otherwise, or if `copy_file_range` refuses the files, fails with `err` of the `FICLONE`
+/// attempt. Copying is never attempted without block cloning enabled.
+fn zfs_clone(src: &fs::File, dest: &fs::File, err: io::Error) -> io::Result<()> {
+    if !crate::error::is_unsupported(&err) || !zfs_clones(src, dest) {
+        return Err(err);
+    }
+    match copy_file_range_all(src, dest)? {
+        Some(_) => Ok(()),
+        None => Err(err),
+    }
+}
+
+/// Whether `copy_file_range` between `src` and `dest` clones blocks, because both are on ZFS
+/// with block cloning enabled.
+fn zfs_clones(src: &fs::File, dest: &fs::File) -> bool {
+    fs_magic(src) == Some(ZFS_SUPER_MAGIC)
+        && fs_magic(dest) == Some(ZFS_SUPER_MAGIC)
+        // OpenZFS 2.2.0 and 2.2.1, which cloned unconditionally, lack the tunable and are
+        // skipped along with older releases
+        && fs::read_to_string("/sys/module/zfs/parameters/zfs_bclone_enabled")
+            .map(|enabled| enabled.trim() == "1")
+            .unwrap_or(false)
+}
+
+/// Classifies an error of `FICLONE` or `FICLONERANGE`, explaining why a source on tmpfs cannot
+/// be cloned: files in `/dev/shm` and `memfd_create` files live on tmpfs, which has no copy on
+/// write at all. Failures across devices keep their reason, since they would fail between any
+/// two file systems.
+fn classify_clone(err: io::Error, src: &fs::File) -> io::Error {
+    let code = err.raw_os_error();
+    let err = classify(err);
+    let reason = ReflinkError::from_io(&err).and_then(ReflinkError::unsupported_reason);
+    match code {
+        Some(code)
+            if reason == Some(UnsupportedReason::Filesystem)
+                && fs_magic(src) == Some(TMPFS_MAGIC) =>
+        {
+            unsupported(
+                UnsupportedReason::Filesystem,
+                Some(io::Error::other(format!(
+                    "{}; tmpfs (including /dev/shm and memfd files) does not support reflinks",
+                    io::Error::from_raw_os_error(code)
+                ))),
+            )
+        }
+        _ => err,
+    }
+}
+
+/// Allocates `len` bytes for `file` with `fallocate`, ignoring file systems without support.
+fn preallocate(file: &fs::File, len: u64) -> io::Result<()> {
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the configured post-processing to a freshly cloned destination.
+fn finish(src: &fs::File, dest: &fs::File, options: &ReflinkOptions) -> io::Result<()> {
+    // before the permissions, as changing the owner clears the set-user-ID bit
+    if options.preserve_ownership {
+        copy_owner(src, dest)?;
+    }
+    if options.preserve_metadata {
+        dest.set_permissions(src.metadata()?.permissions())?;
+        copy_xattrs(src, dest)?;
+        copy_times(src, dest)?;
+    }
+    if let Some((uid, gid)) = options.chown {
+        chown(dest, uid, gid)?;
+    }
+    // after chown, which clears the set-user-ID and set-group-ID bits
+    if let Some(mode) = options.dest_mode {
+        dest.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    // flags go last, the immutable flag would prevent any further change
+    if options.copy_file_flags {
+        copy_file_flags(src, dest)?;
+    }
+    Ok(())
+}
+
+/// Advises the kernel to evict the cached pages of `file`, ignoring any failure.
+fn drop_caches(file: &fs::File) {
+    // dirty pages are not dropped, so a copy needs to reach the disk first
+    let _ = file.sync_data();
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+/// Changes the owner and group of `dest`, explaining a lack of privileges.
+fn chown(dest: &fs::File, uid: u32, gid: u32) -> io::Result<()> {
+    if unsafe { libc::fchown(dest.as_raw_fd(), uid, gid) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EPERM) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "changing the owner of the destination to {}:{} requires CAP_CHOWN: {}",
+                    uid, gid, err
+                ),
+            ));
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Gives `dest` the owner and group of `src` as far as the caller is allowed to, like
+/// `cp --preserve=ownership`: without `CAP_CHOWN` only the group is changed, and failing that
+/// as well, `dest` is left alone.
+fn copy_owner(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    let metadata = src.metadata()?;
+    // an owner of -1 is left unchanged
+    for &(uid, gid) in &[(metadata.uid(), metadata.gid()), (!0, metadata.gid())] {
+        if unsafe { libc::fchown(dest.as_raw_fd(), uid, gid) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        // EINVAL for IDs which are not mapped into the user namespace of the caller
+        if !matches!(err.raw_os_error(), Some(libc::EPERM) | Some(libc::EINVAL)) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Creates an empty `to` with the compression settings of `from`, so that data copied into it
+/// is compressed as it is written.
+fn copy_compression(from: &Path, to: &Path) -> io::Result<()> {
+    let src = fs::File::open(from)?;
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    let ignored = |err: &io::Error| {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENODATA) | Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL)
+        )
+    };
+
+    // the algorithm, e.g. `zstd`, set with `btrfs property set <file> compression zstd`
+    let name = b"btrfs.compression\0".as_ptr() as *const libc::c_char;
+    match read_xattr(|buf, size| unsafe { libc::fgetxattr(src.as_raw_fd(), name, buf, size) }) {
+        Ok(value) => {
+            let ret = unsafe {
+                libc::fsetxattr(
+                    dest.as_raw_fd(),
+                    name,
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            if ret == -1 {
+                let err = io::Error::last_os_error();
+                if !ignored(&err) {
+                    return Err(err);
+                }
+            }
+        }
+        Err(ref err) if ignored(err) => {}
+        Err(err) => return Err(err),
+    }
+
+    let (mut src_flags, mut dest_flags): (libc::c_int, libc::c_int) = (0, 0);
+    for (file, flags) in [(&src, &mut src_flags), (&dest, &mut dest_flags)] {
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, flags) } == -1 {
+            let err = io::Error::last_os_error();
+            return if ignored(&err) { Ok(()) } else { Err(err) };
+        }
+    }
+    let compression = src_flags & (FS_COMPR_FL | FS_NOCOMP_FL);
+    if compression != 0 {
+        let flags = dest_flags | compression;
+        if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } == -1 {
+            let err = io::Error::last_os_error();
+            if !ignored(&err) {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies all extended attributes of `src` to `dest`.
+///
+/// Attributes the destination refuses (e.g. `trusted.*` without `CAP_SYS_ADMIN`, `user.*` on a
+/// read-only file, or a file system without xattr support) are skipped.
+fn copy_xattrs(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    let names = match read_xattr(|buf, size| unsafe {
+        libc::flistxattr(src.as_raw_fd(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name)?;
+        let value = read_xattr(|buf, size| unsafe {
+            libc::fgetxattr(src.as_raw_fd(), name.as_ptr(), buf, size)
+        })?;
+        let ret = unsafe {
+            libc::fsetxattr(
+                dest.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EOPNOTSUPP) => {}
+                _ => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Calls an xattr getter returning a variable-sized buffer, retrying if it grew meanwhile.
+fn read_xattr<F>(get: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let size = get(ptr::null_mut(), 0);
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let size = get(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if size != -1 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+// linux/fiemap.h
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+/// Extents whose data has no (known) location of its own.
+const FIEMAP_EXTENT_UNMAPPED: u32 = 0x0000_0002 // UNKNOWN
+    | 0x0000_0004 // DELALLOC
+    | 0x0000_0100 // NOT_ALIGNED
+    | 0x0000_0200 // DATA_INLINE
+    | 0x0000_0400; // DATA_TAIL
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; 32],
+}
+
+/// Returns the `(logical, physical, length)` extents of `file`, or none at all if any part of
+/// it has no location of its own (e.g. data inlined into the metadata).
+fn fiemap(file: &fs::File) -> io::Result<Vec<(u64, u64, u64)>> {
+    let mut extents = Vec::new();
+    let mut map = Fiemap {
+        length: u64::MAX,
+        flags: FIEMAP_FLAG_SYNC,
+        extent_count: 32,
+        ..Fiemap::default()
+    };
+    loop {
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) } == -1 {
+            return Err(classify(io::Error::last_os_error()));
+        }
+        let mapped = &map.extents[..map.mapped_extents as usize];
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_UNMAPPED != 0 {
+                return Ok(Vec::new());
+            }
+            extents.push((extent.logical, extent.physical, extent.length));
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                map.start = last.logical + last.length;
+                map.length = u64::MAX - map.start;
+                map.mapped_extents = 0;
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+/// `f_type` of 9p file systems, which WSL 2 uses to mount Windows drives (drvfs).
+const V9FS_MAGIC: u64 = 0x0102_1997;
+
+/// `f_type` of NFS mounts.
+const NFS_SUPER_MAGIC: u64 = 0x6969;
+const TMPFS_MAGIC: u64 = 0x0102_1994;
+const ZFS_SUPER_MAGIC: u64 = 0x2fc1_2fc1;
+
+/// Returns the file system type of `file` (`f_type` of `fstatfs`).
+fn fs_magic(file: &fs::File) -> Option<u64> {
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut buf) } == -1 {
+        return None;
+    }
+    Some(buf.f_type as u64)
+}
+
+/// Whether `file` resides on a 9p mount, as used for drvfs.
+fn is_drvfs(file: &fs::File) -> bool {
+    fs_magic(file) == Some(V9FS_MAGIC)
+}
+
+/// Whether we run on the Windows Subsystem for Linux.
+fn on_wsl() -> bool {
+    fs::read_to_string("/proc/version")
+        .map(|version| is_wsl_version(&version))
+        .unwrap_or(false)
+}
+
+/// Whether the contents of `/proc/version` denote a WSL kernel, e.g.
+/// `Linux version 5.15.90.1-microsoft-standard-WSL2` or `4.4.0-19041-Microsoft`.
+fn is_wsl_version(version: &str) -> bool {
+    version.to_ascii_lowercase().contains("microsoft")
+}
+
+/// Whether both files reside on the same file system, judged by `st_dev` of the open files.
+fn same_device(a: &fs::File, b: &fs::File) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+/// Copies the inode flags (as shown by `lsattr`) from `src` to `dest`.
+///
+/// File systems without inode flags are silently skipped. The immutable and append-only flags
+/// are dropped if the caller lacks `CAP_LINUX_IMMUTABLE`.
+fn copy_file_flags(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(src.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } == -1 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => Ok(()),
+            _ => Err(err),
+        };
+    }
+
+    let mut flags = flags & FS_FL_USER_MODIFIABLE;
+    loop {
+        if flags == 0 {
+            return Ok(());
+        }
+        if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != -1 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        let privileged = FS_IMMUTABLE_FL | FS_APPEND_FL;
+        if err.raw_os_error() == Some(libc::EPERM) && flags & privileged != 0 {
+            flags &= !privileged;
+        } else {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_wsl_version;
+
+    #[test]
+    fn wsl_version() {
+        assert!(is_wsl_version(
+            "Linux version 5.15.90.1-microsoft-standard-WSL2 (oe-user@oe-host) (gcc 12.2.0) #1 SMP"
+        ));
+        assert!(is_wsl_version(
+            "Linux version 4.4.0-19041-Microsoft (Microsoft@Microsoft.com) (gcc 5.4.0) #1237"
+        ));
+        assert!(!is_wsl_version(
+            "Linux version 6.1.0-18-amd64 (debian-kernel@lists.debian.org) (gcc-12 12.2.0) #1 SMP"
+        ));
+        assert!(!is_wsl_version(""));
+    }
+}
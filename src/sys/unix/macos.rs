@@ -0,0 +1,267 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use super::{classify, copy_times, data_ranges};
+use crate::error::{unsupported, UnsupportedReason};
+use crate::{ReflinkOptions, Sharing};
+
+/// Clones `from` by path with `clonefile`, which unlike `fclonefileat` also clones whole
+/// directories.
+pub fn reflink(from: &Path, to: &Path, options: &ReflinkOptions) -> io::Result<Sharing> {
+    fn cstr(path: &Path) -> io::Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    extern "C" {
+        // http://www.manpagez.com/man/2/clonefileat/
+        // https://github.com/apple/darwin-xnu/blob/0a798f6738bc1db01281fc08ae024145e84df927/bsd/sys/clonefile.h
+        // TODO We need weak linkage here (OSX > 10.12, iOS > 10.0), otherwise compilation will fail on older versions
+        fn clonefile(
+            src: *const libc::c_char,
+            dest: *const libc::c_char,
+            flags: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    let src = cstr(from)?;
+    let dest = cstr(to)?;
+
+    let ret = unsafe { clonefile(src.as_ptr(), dest.as_ptr(), clone_flags(options)) };
+
+    if ret == -1 {
+        let err = io::Error::last_os_error();
+        Err(clone_error(err, || mount_point(from), to))
+    } else {
+        Ok(Sharing::Shared)
+    }
+}
+
+pub fn reflink_file(src: &fs::File, to: &Path, options: &ReflinkOptions) -> io::Result<Sharing> {
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        // https://github.com/apple/darwin-xnu/blob/0a798f6738bc1db01281fc08ae024145e84df927/bsd/sys/clonefile.h
+        fn fclonefileat(
+            srcfd: libc::c_int,
+            dst_dirfd: libc::c_int,
+            dst: *const libc::c_char,
+            flags: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    let dest = CString::new(to.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        fclonefileat(
+            src.as_raw_fd(),
+            libc::AT_FDCWD,
+            dest.as_ptr(),
+            clone_flags(options),
+        )
+    };
+    if ret == -1 {
+        return Err(clone_error(
+            io::Error::last_os_error(),
+            || fmount_point(src),
+            to,
+        ));
+    }
+    let mut res = options.verify_clone(src, to);
+    if res.is_ok() && (options.post_clone.is_some() || options.durable) {
+        // clonefile leaves no handle behind; clones of read-only sources can only be read
+        res = fs::OpenOptions::new()
+            .write(true)
+            .open(to)
+            .or_else(|_| fs::File::open(to))
+            .and_then(|dest| {
+                options.run_post_clone(&dest)?;
+                options.sync_clone(&dest)
+            });
+    }
+    if let Err(err) = res {
+        if !options.keep_on_failure {
+            let _ = fs::remove_file(to);
+        }
+        return Err(err);
+    }
+    Ok(Sharing::Shared)
+}
+
+/// The flags passed to `clonefile` and `fclonefileat`.
+fn clone_flags(options: &ReflinkOptions) -> libc::c_int {
+    // const CLONE_NOFOLLOW: libc::c_int = 0x0001;
+    const CLONE_NOOWNERCOPY: libc::c_int = 0x0002;
+
+    let owner = if options.preserve_ownership {
+        0
+    } else {
+        CLONE_NOOWNERCOPY
+    };
+    owner | options.macos_raw_flags
+}
+
+/// Classifies an error of `clonefile` or `fclonefileat`, explaining a failure across the volume
+/// split given the mount point of the source, which is only looked up for `EXDEV`.
+fn clone_error<F>(err: io::Error, src_mount: F, to: &Path) -> io::Error
+where
+    F: FnOnce() -> Option<Vec<u8>>,
+{
+    if err.raw_os_error() == Some(libc::EXDEV) && crosses_system_volume(src_mount(), to) {
+        return unsupported(
+            UnsupportedReason::CrossDevice,
+            Some(io::Error::other(format!(
+                "{}; the read-only system volume and the data volume are separate APFS \
+                 volumes, even though firmlinks join them into one directory tree",
+                err
+            ))),
+        );
+    }
+    classify(err)
+}
+
+/// The mount point of the sealed system volume and of the data volume it is firmlinked to.
+const SYSTEM_VOLUME: &str = "/";
+const DATA_VOLUME: &str = "/System/Volumes/Data";
+
+/// Whether the source (given its mount point) is on the system volume and the destination on
+/// the data volume, or vice versa.
+fn crosses_system_volume(src_mount: Option<Vec<u8>>, to: &Path) -> bool {
+    let dest_dir = match to.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    match (src_mount, mount_point(dest_dir)) {
+        (Some(a), Some(b)) => {
+            let (a, b) = (a.as_slice(), b.as_slice());
+            let (system, data) = (SYSTEM_VOLUME.as_bytes(), DATA_VOLUME.as_bytes());
+            (a == system && b == data) || (a == data && b == system)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the mount point of the volume containing `path`, as reported by `statfs`.
+fn mount_point(path: &Path) -> Option<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } == -1 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.f_mntonname.as_ptr()) };
+    Some(name.to_bytes().to_vec())
+}
+
+/// Returns the mount point of the volume containing the open `file`, as reported by `fstatfs`.
+fn fmount_point(file: &fs::File) -> Option<Vec<u8>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut buf) } == -1 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.f_mntonname.as_ptr()) };
+    Some(name.to_bytes().to_vec())
+}
+
+/// The product version, e.g. `14.2.1`.
+pub fn os_version() -> Option<String> {
+    let mut buf = [0u8; 32];
+    let mut len = buf.len();
+    let ret = unsafe {
+        libc::sysctlbyname(
+            b"kern.osproductversion\0".as_ptr() as *const libc::c_char,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret == -1 {
+        return None;
+    }
+    let version = CStr::from_bytes_until_nul(&buf[..len]).ok()?;
+    Some(version.to_string_lossy().into_owned())
+}
+
+pub fn can_reflink(from: &Path, dest_dir: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    if fs::metadata(from)?.dev() != fs::metadata(dest_dir)?.dev() {
+        return Ok(false);
+    }
+    let path = CString::new(dest_dir.as_os_str().as_bytes())?;
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let fs_type = unsafe { CStr::from_ptr(buf.f_fstypename.as_ptr()) };
+    Ok(fs_type.to_bytes() == b"apfs")
+}
+
+pub fn extents_shared(_a: &fs::File, _b: &fs::File) -> io::Result<bool> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn shared_bytes(_a: &fs::File, _b: &fs::File) -> io::Result<u64> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn reflink_range(
+    _src: &fs::File,
+    _src_offset: u64,
+    _dest: &fs::File,
+    _dest_offset: u64,
+    _len: u64,
+) -> io::Result<()> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+pub fn clone_alignment(_file: &fs::File) -> io::Result<u64> {
+    Err(unsupported(UnsupportedReason::Os, None))
+}
+
+/// Copies the extended attributes of `src` to `dest`, including the resource fork
+/// (`com.apple.ResourceFork`), which `fcopyfile` in `fs::copy` would have copied along with the
+/// data.
+fn copy_xattrs(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fcopyfile(
+            src.as_raw_fd(),
+            dest.as_raw_fd(),
+            std::ptr::null_mut(),
+            libc::COPYFILE_XATTR,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn copy(from: &Path, to: &Path, options: &ReflinkOptions) -> io::Result<u64> {
+    let written = if crate::sys::copies_sparsely(from, options) {
+        crate::sys::copy_sparse(from, to, data_ranges, copy_xattrs, options)?
+    } else {
+        // `fs::copy` uses `fcopyfile`, which already copies extended attributes, unlike the
+        // chunked copy of a cancellable one
+        crate::sys::copy_plain(from, to, options)?
+    };
+    if options.rich_copy_fallback {
+        let src = fs::File::open(from)?;
+        // setting the times does not need write access, which a copy of a read-only source
+        // would not grant
+        let dest = fs::File::open(to)?;
+        if let Err(err) = copy_times(&src, &dest) {
+            drop(dest);
+            let _ = fs::remove_file(to);
+            return Err(err);
+        }
+    }
+    Ok(written)
+}
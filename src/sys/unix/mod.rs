@@ -0,0 +1,177 @@
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::linux::*;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod macos;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub use self::macos::*;
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub use super::others::*;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+use crate::error::{bad_handle, out_of_space, unsupported, UnsupportedReason};
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+use std::io;
+
+/// Wraps errors which denote an unsupported operation into a `ReflinkError::Unsupported`, and
+/// a full file system into a `ReflinkError::OutOfSpace`. Bad descriptors are explained.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn classify(err: io::Error) -> io::Error {
+    let reason = match err.raw_os_error() {
+        Some(libc::ENOSPC) => return out_of_space(err),
+        // a closed (or recycled) descriptor, or one opened without the required access mode
+        Some(libc::EBADF) => return bad_handle(err),
+        Some(e) if e == libc::EOPNOTSUPP || e == libc::ENOTSUP => UnsupportedReason::Filesystem,
+        // the file system does not know the ioctl at all, e.g. before Linux 4.5 made
+        // `FICLONE` generic
+        Some(libc::ENOTTY) => UnsupportedReason::Filesystem,
+        Some(libc::EXDEV) => UnsupportedReason::CrossDevice,
+        Some(libc::ENOSYS) => UnsupportedReason::Syscall,
+        _ => return err,
+    };
+    unsupported(reason, Some(err))
+}
+
+/// Copies the access and modification times of `src` to `dest`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn copy_times(src: &std::fs::File, dest: &std::fs::File) -> io::Result<()> {
+    let metadata = src.metadata()?;
+    let times = std::fs::FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    // the creation time can only be set on Apple platforms, Linux has no call for it
+    #[cfg(target_os = "macos")]
+    let times = std::os::macos::fs::FileTimesExt::set_created(times, metadata.created()?);
+    #[cfg(target_os = "ios")]
+    let times = std::os::ios::fs::FileTimesExt::set_created(times, metadata.created()?);
+    dest.set_times(times)
+}
+
+/// Returns the data ranges (offset and length) of `file`, skipping the holes found with
+/// `SEEK_DATA` and `SEEK_HOLE`. File systems without support report the whole file as data.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn data_ranges(file: &std::fs::File) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = file.metadata()?.len();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start =
+            unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+        if start == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // nothing but a hole up to the end of the file
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) if offset == 0 => return Ok(vec![(0, len)]),
+                _ => return Err(err),
+            }
+        }
+        let end = unsafe { libc::lseek(file.as_raw_fd(), start, libc::SEEK_HOLE) };
+        if end == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let (start, end) = (start as u64, (end as u64).min(len));
+        ranges.push((start, end - start));
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+/// Opens the source of a clone. `O_NONBLOCK` keeps the open from waiting for a writer if the
+/// source turns out to be a FIFO, so that the caller can reject it after `fstat`.
+pub fn open_source(
+    path: &std::path::Path,
+    _options: &crate::ReflinkOptions,
+) -> std::io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// Identifies the file system of `path` by its device number.
+pub fn device_id(path: &std::path::Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(Some(std::fs::metadata(path)?.dev()))
+}
+
+/// Identifies the inode behind `path` (device and inode number) if it has more than one link.
+pub fn hard_link_id(path: &std::path::Path) -> std::io::Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(path)?;
+    Ok(if metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    })
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::classify;
+    use crate::{ReflinkError, UnsupportedReason};
+    use std::io;
+
+    fn reason(errno: i32) -> Option<UnsupportedReason> {
+        let err = classify(io::Error::from_raw_os_error(errno));
+        ReflinkError::from_io(&err).and_then(ReflinkError::unsupported_reason)
+    }
+
+    #[test]
+    fn classify_errnos() {
+        assert_eq!(reason(libc::ENOTTY), Some(UnsupportedReason::Filesystem));
+        assert_eq!(
+            reason(libc::EOPNOTSUPP),
+            Some(UnsupportedReason::Filesystem)
+        );
+        assert_eq!(reason(libc::ENOTSUP), Some(UnsupportedReason::Filesystem));
+        assert_eq!(reason(libc::EXDEV), Some(UnsupportedReason::CrossDevice));
+        assert_eq!(reason(libc::ENOSYS), Some(UnsupportedReason::Syscall));
+        // invalid arguments (e.g. a FIFO as source) must not trigger a fallback
+        assert_eq!(reason(libc::EINVAL), None);
+        assert_eq!(reason(libc::EBADF), None);
+        let err = classify(io::Error::from_raw_os_error(libc::EBADF));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let err = classify(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    }
+}
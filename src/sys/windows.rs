@@ -1,143 +1,783 @@
-use std::cmp;
+use std::convert::TryFrom;
 use std::fs;
-use std::io;
+use std::io::{self, Seek, SeekFrom};
 use std::mem;
-use std::os::windows::fs::MetadataExt;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::{MetadataExt, OpenOptionsExt};
 use std::os::windows::io::AsRawHandle;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::thread;
 
-use winapi::um::fileapi::GetVolumeInformationByHandleW;
+use winapi::shared::minwindef::FILETIME;
+use winapi::shared::ntdef::BOOLEAN;
+use winapi::shared::winerror::{
+    ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL, ERROR_HANDLE_EOF, ERROR_INVALID_FUNCTION,
+    ERROR_INVALID_HANDLE, ERROR_MORE_DATA, ERROR_NOT_SAME_DEVICE, ERROR_NOT_SUPPORTED,
+    ERROR_SHARING_VIOLATION,
+};
+use winapi::um::fileapi::{
+    GetFileInformationByHandle, GetFileTime, GetVolumeInformationByHandleW, GetVolumePathNameW,
+    SetFileInformationByHandle, SetFileTime, BY_HANDLE_FILE_INFORMATION, FILE_ALLOCATION_INFO,
+};
 use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::minwinbase::{FileAllocationInfo, FileRemoteProtocolInfo};
+use winapi::um::winbase::{
+    CopyFile2, GetFileInformationByHandleEx, COPYFILE2_EXTENDED_PARAMETERS,
+    FILE_FLAG_BACKUP_SEMANTICS,
+};
 use winapi::um::winioctl::{
-    FSCTL_GET_INTEGRITY_INFORMATION, FSCTL_SET_INTEGRITY_INFORMATION, FSCTL_SET_SPARSE,
+    FSCTL_GET_INTEGRITY_INFORMATION, FSCTL_GET_RETRIEVAL_POINTERS, FSCTL_QUERY_ALLOCATED_RANGES,
+    FSCTL_SET_INTEGRITY_INFORMATION, FSCTL_SET_SPARSE, STARTING_VCN_INPUT_BUFFER,
+};
+use winapi::um::winnt::{
+    FILE_ATTRIBUTE_SPARSE_FILE, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+    FILE_SUPPORTS_BLOCK_REFCOUNTING, FILE_WRITE_ATTRIBUTES, GENERIC_READ, GENERIC_WRITE,
+    OSVERSIONINFOW,
+};
+
+use super::chunks::{
+    clone_chunks, copychunk_batch, max_chunk_len, overflow, round_up, COPYCHUNK_COUNT,
 };
-use winapi::um::winnt::{FILE_ATTRIBUTE_SPARSE_FILE, FILE_SUPPORTS_BLOCK_REFCOUNTING};
+use crate::error::{bad_handle, out_of_space, unsupported, UnsupportedReason};
+use crate::options::should_fall_back;
+use crate::{ReflinkOptions, Sharing};
 
 macro_rules! try_cleanup {
-    ($expr:expr, $dest:ident) => {
+    ($expr:expr, $dest:ident, $options:ident) => {
         match $expr {
             Ok(val) => val,
             Err(err) => {
-                let _ = fs::remove_file($dest);
+                cleanup($dest, $options);
                 return Err(err);
             }
         }
     };
 }
 
-pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+/// Removes a partial destination after a failure, unless asked to keep it.
+fn cleanup(to: &Path, options: &ReflinkOptions) {
+    if !options.keep_on_failure {
+        let _ = fs::remove_file(to);
+    }
+}
+
+pub fn reflink_file(src: &fs::File, to: &Path, options: &ReflinkOptions) -> io::Result<Sharing> {
+    clone_file(src, to, options, false).map(|_| Sharing::Shared)
+}
+
+/// Like `reflink_file`, but completes a clone which failed after some of its chunks by copying
+/// the rest, returning the number of bytes copied.
+pub fn reflink_file_completing(
+    src: &fs::File,
+    to: &Path,
+    options: &ReflinkOptions,
+) -> io::Result<Option<u64>> {
+    clone_file(src, to, options, true)
+}
+
+/// Clones `src` to `to` chunk by chunk. With `copy_rest`, a chunk failing after others were
+/// cloned is copied along with the rest of the file, and the number of bytes copied is returned.
+fn clone_file(
+    src: &fs::File,
+    to: &Path,
+    options: &ReflinkOptions,
+    copy_rest: bool,
+) -> io::Result<Option<u64>> {
     // Inspired by https://github.com/0xbadfca11/reflink/blob/master/reflink.cpp
-    let src = fs::File::open(&from)?;
 
     let src_metadata = src.metadata()?;
     let src_file_size = src_metadata.file_size();
     let src_is_sparse = src_metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE > 0;
+    let src_integrity_info = src.get_integrity_information()?;
+    let cluster_size = src_integrity_info.ClusterSizeInBytes as i64;
 
-    let dest = fs::OpenOptions::new()
+    // We must end at a cluster boundary. Sources whose size is a multiple of the cluster size
+    // are cloned exactly, without any rounding.
+    let src_len = i64::try_from(src_file_size).map_err(|_| overflow("the source file size"))?;
+    let aligned = cluster_size == 0 || src_len % cluster_size == 0;
+    let total_copy_len: i64 = if aligned {
+        src_len
+    } else {
+        // Round to the next cluster size
+        round_up(src_len, cluster_size)?
+    };
+
+    let sidecar = if options.resumable {
+        Some(sidecar_path(to))
+    } else {
+        None
+    };
+    let mut progress = Progress {
+        len: src_file_size,
+        modified: src_metadata.last_write_time(),
+        offset: 0,
+    };
+    let resumed = match sidecar {
+        Some(ref sidecar) => resume(sidecar, to, &progress, total_copy_len, cluster_size),
+        None => None,
+    };
+    let dest = match resumed {
+        Some((dest, offset)) => {
+            progress.offset = offset;
+            dest
+        }
+        None => {
+            let dest = dest_options()
+                .create_new(!options.reuse_existing_dest)
+                .create(true)
+                .truncate(options.reuse_existing_dest)
+                .open(to)?;
+            prepare(src, &dest, to, options, src_is_sparse, &src_integrity_info)?;
+            dest
+        }
+    };
+
+    // file_size must be sufficient to hold the data.
+    // TODO test if the current implementation works:
+    // Later on, we round up the bytes to copy in order to end at a cluster boundary.
+    // This might very well result in us cloning past the file end.
+    // Let's hope windows api sanitizes this, because otherwise a clean implementation is not really possible.
+    try_cleanup!(dest.set_len(src_file_size), to, options);
+
+    // Preparation done, now reflink
+
+    if let Some(ref sidecar) = sidecar {
+        try_cleanup!(progress.save(sidecar), to, options);
+    }
+    let mut copied = None;
+    if sidecar.is_none() && total_copy_len <= max_chunk_len(total_copy_len, cluster_size) {
+        // Fast path: files below 4GB are cloned by a single call, without the chunk iterator
+        // and the bookkeeping of resumable clones.
+        if total_copy_len > 0 {
+            try_cleanup!(
+                dest.duplicate_extents(src, 0, 0, total_copy_len),
+                to,
+                options
+            );
+        }
+    } else {
+        let start = progress.offset;
+        let chunks =
+            clone_chunks(total_copy_len, cluster_size).skip_while(|&(offset, _)| offset < start);
+        for (offset, bytes_to_copy) in chunks {
+            if cluster_size != 0 {
+                debug_assert_eq!(bytes_to_copy % cluster_size, 0);
+                debug_assert_eq!(offset % cluster_size, 0);
+            }
+            let res = options
+                .check_cancel()
+                .and_then(|_| dest.duplicate_extents(src, offset, offset, bytes_to_copy));
+            if let Err(err) = res {
+                // copying the rest is cheaper than the copy of the whole file `reflink_or_copy`
+                // would make instead
+                if copy_rest && sidecar.is_none() && offset > 0 && should_fall_back(&err) {
+                    let rest = copy_tail(src, &dest, offset as u64, options);
+                    copied = Some(try_cleanup!(rest, to, options));
+                    break;
+                }
+                // a resumable clone keeps the completed chunks for the next attempt
+                if sidecar.is_none() {
+                    cleanup(to, options);
+                }
+                return Err(err);
+            }
+            if let Some(ref sidecar) = sidecar {
+                progress.offset = offset + bytes_to_copy;
+                progress.save(sidecar)?;
+            }
+        }
+    }
+    if !aligned {
+        // Cloning the rounded up tail must not grow the destination past the source size.
+        try_cleanup!(dest.set_len(src_file_size), to, options);
+    }
+
+    if src_is_sparse && options.force_sparse == Some(false) {
+        match dest.set_sparse(false) {
+            // already dense, on a volume without sparse files
+            Err(ref err) if refuses_sparse(err) => {}
+            res => try_cleanup!(res, to, options),
+        }
+    }
+    if options.preserve_metadata {
+        try_cleanup!(dest.copy_file_times(src), to, options);
+    }
+    try_cleanup!(options.verify_clone(src, to), to, options);
+    try_cleanup!(options.run_post_clone(&dest), to, options);
+    try_cleanup!(options.sync_clone(&dest), to, options);
+    if let Some(ref sidecar) = sidecar {
+        let _ = fs::remove_file(sidecar);
+    }
+    Ok(copied)
+}
+
+/// Copies `src` from `offset` on to the same offset of `dest`.
+fn copy_tail(
+    src: &fs::File,
+    dest: &fs::File,
+    offset: u64,
+    options: &ReflinkOptions,
+) -> io::Result<u64> {
+    for mut file in [src, dest] {
+        file.seek(SeekFrom::Start(offset))?;
+    }
+    super::copy_range(src, dest, u64::MAX, options)
+}
+
+/// Opens the source of a clone, retrying sharing violations as configured.
+///
+/// `FSCTL_DUPLICATE_EXTENTS_TO_FILE` reads the source through the handle passed in its input,
+/// which needs `FILE_READ_DATA` (part of `GENERIC_READ`). Other handles may keep reading, writing
+/// or deleting the source, as with `File::open`.
+pub fn open_source(from: &Path, options: &ReflinkOptions) -> io::Result<fs::File> {
+    let open = || {
+        fs::OpenOptions::new()
+            .access_mode(GENERIC_READ)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+            .open(from)
+    };
+    let (retries, delay) = options.sharing_retries.unwrap_or_default();
+    let mut attempt = 0;
+    loop {
+        match open() {
+            Err(ref err)
+                if attempt < retries
+                    && err.raw_os_error().map(|code| code as u32)
+                        == Some(ERROR_SHARING_VIOLATION) =>
+            {
+                attempt += 1;
+                thread::sleep(delay);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Options to open the destination of a clone.
+///
+/// The ioctl is issued on the destination handle, which needs `FILE_WRITE_DATA` (part of
+/// `GENERIC_WRITE`); setting the size, sparseness and times needs the rest of `GENERIC_WRITE`,
+/// and reading the integrity information `GENERIC_READ`.
+fn dest_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options
         .write(true)
-        .create_new(true)
-        .open(&to)?;
+        .access_mode(GENERIC_READ | GENERIC_WRITE)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+    options
+}
 
-    if src_is_sparse {
-        try_cleanup!(dest.set_sparse(), to);
+/// Prepares a newly created destination: volume checks, sparseness and integrity settings.
+fn prepare(
+    src: &fs::File,
+    dest: &fs::File,
+    to: &Path,
+    options: &ReflinkOptions,
+    src_is_sparse: bool,
+    src_integrity_info: &ffi::FSCTL_GET_INTEGRITY_INFORMATION_BUFFER,
+) -> io::Result<()> {
+    // The handle resolves junctions and volume mount points, so these checks concern the volume
+    // the destination really lives on, not the one its path starts on (e.g. `C:\mnt\refs`).
+    let dest_volume = try_cleanup!(dest.volume_serial_number(), to, options);
+    if dest_volume != try_cleanup!(src.volume_serial_number(), to, options) {
+        // Extents can only be shared within a single volume, so there is no common alignment
+        // to fall back to either. Report differing cluster sizes to make the cause obvious.
+        let cluster_size = src_integrity_info.ClusterSizeInBytes;
+        let clusters = match dest.get_integrity_information() {
+            Ok(ref info) if info.ClusterSizeInBytes != cluster_size => format!(
+                ", cluster size mismatch: source has {} bytes, destination has {} bytes",
+                cluster_size, info.ClusterSizeInBytes
+            ),
+            _ => String::new(),
+        };
+        cleanup(to, options);
+        return Err(unsupported(
+            UnsupportedReason::CrossDevice,
+            Some(io::Error::other(format!(
+                "the destination resolves to another volume than the source{}{}",
+                mounted_at(to),
+                clusters
+            ))),
+        ));
+    }
+    if !try_cleanup!(dest.is_block_cloning_supported(), to, options) {
+        cleanup(to, options);
+        return Err(unsupported(
+            UnsupportedReason::Filesystem,
+            Some(io::Error::other(format!(
+                "the destination volume{} does not support block cloning",
+                mounted_at(to)
+            ))),
+        ));
+    }
+
+    // a sparse source can only be cloned into a sparse destination
+    if src_is_sparse || options.force_sparse == Some(true) {
+        if let Err(err) = dest.set_sparse(true) {
+            try_cleanup!(sparse_refused(err, options), to, options);
+        }
     }
 
-    let src_integrity_info = try_cleanup!(src.get_integrity_information(), to);
     let cluster_size = src_integrity_info.ClusterSizeInBytes as i64;
     if cluster_size != 0 {
         // Cluster size must either be 4K or 64K (restricted by ReFS)
         assert!(cluster_size == 4 * 1024 || cluster_size == 64 * 1024);
-        // Copy over integrity information. Not sure if this is required.
+        // Copy over integrity information. Not required for cloning, and some volumes (e.g.
+        // Dev Drive) refuse it, so failures are ignored unless asked to be strict.
         let mut dest_integrity_info = ffi::FSCTL_SET_INTEGRITY_INFORMATION_BUFFER {
             ChecksumAlgorithm: src_integrity_info.ChecksumAlgorithm,
             Reserved: src_integrity_info.Reserved,
             Flags: src_integrity_info.Flags,
         };
-        try_cleanup!(dest.set_integrity_information(&mut dest_integrity_info), to);
+        if let Err(err) = dest.set_integrity_information(&mut dest_integrity_info) {
+            if options.strict_integrity {
+                cleanup(to, options);
+                return Err(err);
+            }
+        }
     }
+    Ok(())
+}
 
-    // file_size must be sufficient to hold the data.
-    // TODO test if the current implementation works:
-    // Later on, we round up the bytes to copy in order to end at a cluster boundary.
-    // This might very well result in us cloning past the file end.
-    // Let's hope windows api sanitizes this, because otherwise a clean implementation is not really possible.
-    try_cleanup!(dest.set_len(src_file_size), to);
+/// Decides whether a clone goes on after `FSCTL_SET_SPARSE` failed with `err` on the destination.
+///
+/// Volumes without sparse files (e.g. FAT) refuse the ioctl; this is reported as unsupported,
+/// unless `allow_dense_clone` lets the clone go on into a dense destination.
+fn sparse_refused(err: io::Error, options: &ReflinkOptions) -> io::Result<()> {
+    if !refuses_sparse(&err) {
+        return Err(err);
+    }
+    if options.allow_dense_clone && options.force_sparse != Some(true) {
+        return Ok(());
+    }
+    Err(unsupported(
+        UnsupportedReason::Filesystem,
+        Some(io::Error::new(
+            err.kind(),
+            format!(
+                "the destination volume does not support sparse files: {}",
+                err
+            ),
+        )),
+    ))
+}
 
-    // Preparation done, now reflink
-    let mut dup_extent: ffi::DUPLICATE_EXTENTS_DATA = unsafe { mem::uninitialized() };
-    dup_extent.FileHandle = src.as_raw_handle();
+/// Whether `err` of `FSCTL_SET_SPARSE` means that the volume has no sparse files.
+fn refuses_sparse(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error().map(|code| code as u32),
+        Some(ERROR_INVALID_FUNCTION) | Some(ERROR_NOT_SUPPORTED)
+    )
+}
 
-    // We must end at a cluster boundary
-    let total_copy_len: i64 = {
-        if cluster_size == 0 {
-            src_file_size as i64
-        } else {
-            // Round to the next cluster size
-            round_up(src_file_size as i64, cluster_size)
+/// Describes where the volume containing `path` is mounted, e.g. ` (mounted at C:\mnt\refs\)`,
+/// or nothing if `GetVolumePathNameW` fails.
+fn mounted_at(path: &Path) -> String {
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut buf = [0u16; 261];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), buf.as_mut_ptr(), buf.len() as u32) } == 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    format!(" (mounted at {})", String::from_utf16_lossy(&buf[..len]))
+}
+
+/// Progress of a resumable clone, stored in the sidecar file `<dest>.reflink-progress` as a
+/// single line: `reflink-progress 1 <source size> <source last write time> <offset>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Progress {
+    len: u64,
+    modified: u64,
+    offset: i64,
+}
+
+impl Progress {
+    fn parse(line: &str) -> Option<Progress> {
+        let mut fields = line.trim_end().split(' ');
+        if fields.next()? != "reflink-progress" || fields.next()? != "1" {
+            return None;
         }
+        let progress = Progress {
+            len: fields.next()?.parse().ok()?,
+            modified: fields.next()?.parse().ok()?,
+            offset: fields.next()?.parse().ok()?,
+        };
+        match fields.next() {
+            Some(_) => None,
+            None => Some(progress),
+        }
+    }
+
+    fn save(&self, sidecar: &Path) -> io::Result<()> {
+        fs::write(
+            sidecar,
+            format!(
+                "reflink-progress 1 {} {} {}\n",
+                self.len, self.modified, self.offset
+            ),
+        )
+    }
+}
+
+fn sidecar_path(to: &Path) -> PathBuf {
+    let mut path = to.as_os_str().to_os_string();
+    path.push(".reflink-progress");
+    PathBuf::from(path)
+}
+
+/// Reopens the destination of an interrupted clone of the same, unchanged source.
+///
+/// Returns the destination and the offset to continue at. A stale sidecar is removed along
+/// with the partial destination it belongs to.
+fn resume(
+    sidecar: &Path,
+    to: &Path,
+    current: &Progress,
+    total_len: i64,
+    cluster_size: i64,
+) -> Option<(fs::File, i64)> {
+    let saved = Progress::parse(&fs::read_to_string(sidecar).ok()?);
+    let resumable = saved.filter(|saved| {
+        saved.len == current.len
+            && saved.modified == current.modified
+            // the clone proceeds chunk by chunk, so anything else is corrupt
+            && (saved.offset == total_len
+                || clone_chunks(total_len, cluster_size).any(|(offset, _)| offset == saved.offset))
+    });
+    match resumable {
+        Some(saved) => match dest_options().open(to) {
+            Ok(dest) => Some((dest, saved.offset)),
+            Err(_) => None,
+        },
+        None => {
+            let _ = fs::remove_file(to);
+            let _ = fs::remove_file(sidecar);
+            None
+        }
+    }
+}
+
+pub fn extents_shared(a: &fs::File, b: &fs::File) -> io::Result<bool> {
+    let (a, b) = (a.retrieval_pointers()?, b.retrieval_pointers()?);
+    // an LCN of -1 denotes a sparse run without any clusters
+    Ok(a.iter().any(|&(_, _, lcn)| lcn != -1) && a == b)
+}
+
+pub fn shared_bytes(a: &fs::File, b: &fs::File) -> io::Result<u64> {
+    let cluster_size = clone_alignment(b)?;
+    let clusters = |runs: Vec<(i64, i64, i64)>| {
+        runs.into_iter()
+            .filter(|&(_, _, lcn)| lcn != -1)
+            .map(|(vcn, next_vcn, lcn)| (lcn as u64, (next_vcn - vcn) as u64))
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (
+        clusters(a.retrieval_pointers()?),
+        clusters(b.retrieval_pointers()?),
+    );
+    Ok(crate::sys::overlap(a, &b).saturating_mul(cluster_size))
+}
+
+/// The version and build number, e.g. `10.0.22631`.
+pub fn os_version() -> Option<String> {
+    // `GetVersionEx` reports Windows 8 to applications without a compatibility manifest
+    #[link(name = "ntdll")]
+    extern "system" {
+        fn RtlGetVersion(info: *mut OSVERSIONINFOW) -> i32;
+    }
+
+    let mut info: OSVERSIONINFOW = unsafe { mem::zeroed() };
+    info.dwOSVersionInfoSize = mem::size_of::<OSVERSIONINFOW>() as u32;
+    if unsafe { RtlGetVersion(&mut info) } != 0 {
+        return None;
+    }
+    Some(format!(
+        "{}.{}.{}",
+        info.dwMajorVersion, info.dwMinorVersion, info.dwBuildNumber
+    ))
+}
+
+pub fn can_reflink(from: &Path, dest_dir: &Path) -> io::Result<bool> {
+    let src = fs::File::open(from)?;
+    // directories can only be opened with backup semantics
+    let dir = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(dest_dir)?;
+    if src.volume_serial_number()? != dir.volume_serial_number()? {
+        return Ok(false);
+    }
+    src.is_block_cloning_supported()
+}
+
+pub fn reflink_range(
+    src: &fs::File,
+    src_offset: u64,
+    dest: &fs::File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let cluster_size = src.get_integrity_information()?.ClusterSizeInBytes as u64;
+    // the ioctl needs whole clusters even at the end of the source
+    crate::sys::check_alignment(
+        (src_offset, dest_offset, len),
+        src.metadata()?.len(),
+        cluster_size,
+        "cluster size",
+        false,
+    )?;
+    let to_i64 = |value: u64| {
+        i64::try_from(value).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "offset or length out of range")
+        })
     };
+    dest.duplicate_extents(src, to_i64(src_offset)?, to_i64(dest_offset)?, to_i64(len)?)
+}
+
+pub fn clone_alignment(file: &fs::File) -> io::Result<u64> {
+    Ok(match file.get_integrity_information()?.ClusterSizeInBytes {
+        0 => 1,
+        cluster_size => cluster_size as u64,
+    })
+}
 
-    let mut bytes_copied = 0;
-    // Must be smaller than 4GB; This is always a multiple of ClusterSize
-    let max_copy_len: i64 = if cluster_size == 0 {
-        total_copy_len
+/// Identifies the file behind `path` (volume serial number and file index) if it has more than
+/// one link.
+pub fn hard_link_id(path: &Path) -> io::Result<Option<(u64, u64)>> {
+    let file = fs::File::open(path)?;
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(if info.nNumberOfLinks > 1 {
+        let index = (u64::from(info.nFileIndexHigh) << 32) | u64::from(info.nFileIndexLow);
+        Some((u64::from(info.dwVolumeSerialNumber), index))
     } else {
-        (4 * 1024 * 1024 * 1024) - cluster_size
+        None
+    })
+}
+
+/// Identifies the volume of `path` by its serial number.
+pub fn device_id(path: &Path) -> io::Result<Option<u64>> {
+    // directories can only be opened with backup semantics
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)?;
+    Ok(Some(u64::from(file.volume_serial_number()?)))
+}
+
+/// Whether the file at `from` is marked sparse.
+pub fn is_sparse(from: &Path) -> bool {
+    fs::metadata(from)
+        .map(|m| m.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0)
+        .unwrap_or(false)
+}
+
+pub fn copy(from: &Path, to: &Path, options: &ReflinkOptions) -> io::Result<u64> {
+    let written = if options.copy_file2_fallback {
+        copy_file2(from, to)?
+    } else if crate::sys::copies_sparsely(from, options) {
+        crate::sys::copy_sparse(
+            from,
+            to,
+            allocated_ranges,
+            |src, dest| {
+                // holes can only be left in a sparse destination
+                if src.metadata()?.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE > 0 {
+                    dest.set_sparse(true)?;
+                }
+                Ok(())
+            },
+            options,
+        )?
+    } else if options.preallocate_fallback {
+        crate::sys::copy_preallocated(from, to, |file, len| file.preallocate(len), options)?
+    } else if options.is_cancellable() {
+        crate::sys::copy_plain(from, to, options)?
+    } else if let Some(written) = copy_server_side(from, to)? {
+        written
+    } else {
+        fs::copy(from, to)?
     };
-    while bytes_copied < total_copy_len {
-        let bytes_to_copy = cmp::min(total_copy_len, max_copy_len);
-        if cluster_size != 0 {
-            debug_assert_eq!(bytes_to_copy % cluster_size, 0);
-            debug_assert_eq!(bytes_copied % cluster_size, 0);
-        }
-        unsafe {
-            *dup_extent.SourceFileOffset.QuadPart_mut() = bytes_copied;
-            *dup_extent.TargetFileOffset.QuadPart_mut() = bytes_copied;
-            *dup_extent.ByteCount.QuadPart_mut() = bytes_to_copy;
+    if options.rich_copy_fallback {
+        let src = fs::File::open(from)?;
+        // the copy of a read-only source is read-only as well, which still permits this
+        let dest = fs::OpenOptions::new()
+            .access_mode(FILE_WRITE_ATTRIBUTES)
+            .open(to)?;
+        if let Err(err) = dest.copy_file_times(&src) {
+            drop(dest);
+            let _ = fs::remove_file(to);
+            return Err(err);
         }
+    }
+    Ok(written)
+}
+
+/// Returns the allocated ranges (offset and length) of a sparse `file` as reported by
+/// `FSCTL_QUERY_ALLOCATED_RANGES`, or the whole file if it is not sparse.
+fn allocated_ranges(file: &fs::File) -> io::Result<Vec<(u64, u64)>> {
+    let metadata = file.metadata()?;
+    if metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE == 0 {
+        return Ok(vec![(0, metadata.file_size())]);
+    }
+    let mut ranges = Vec::new();
+    let mut query = ffi::FILE_ALLOCATED_RANGE_BUFFER {
+        FileOffset: 0,
+        Length: i64::try_from(metadata.file_size()).map_err(|_| overflow("the file size"))?,
+    };
+    loop {
+        let mut output = [ffi::FILE_ALLOCATED_RANGE_BUFFER {
+            FileOffset: 0,
+            Length: 0,
+        }; 64];
         let mut bytes_returned = 0u32;
         let res = unsafe {
             DeviceIoControl(
-                dest.as_raw_handle() as _,
-                ffi::FSCTL_DUPLICATE_EXTENTS_TO_FILE,
-                &mut dup_extent as *mut _ as *mut _,
-                mem::size_of::<ffi::DUPLICATE_EXTENTS_DATA>() as u32,
-                ptr::null_mut(),
-                0,
+                file.as_raw_handle() as _,
+                FSCTL_QUERY_ALLOCATED_RANGES,
+                &mut query as *mut _ as *mut _,
+                mem::size_of::<ffi::FILE_ALLOCATED_RANGE_BUFFER>() as u32,
+                output.as_mut_ptr() as *mut _,
+                mem::size_of_val(&output) as u32,
                 &mut bytes_returned as *mut _,
                 ptr::null_mut(),
             )
         };
-        if res == 0 {
-            let _ = fs::remove_file(to);
-            return Err(io::Error::last_os_error());
+        let more = if res == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error().map(|code| code as u32) != Some(ERROR_MORE_DATA) {
+                return Err(err);
+            }
+            true
+        } else {
+            false
+        };
+        let count = bytes_returned as usize / mem::size_of::<ffi::FILE_ALLOCATED_RANGE_BUFFER>();
+        for range in &output[..count] {
+            ranges.push((range.FileOffset as u64, range.Length as u64));
+        }
+        match output[..count].last() {
+            Some(last) if more => {
+                // continue behind the last range returned
+                let end = last.FileOffset + last.Length;
+                query.Length -= end - query.FileOffset;
+                query.FileOffset = end;
+            }
+            _ => return Ok(ranges),
         }
-        bytes_copied += bytes_to_copy;
     }
-    Ok(())
+}
+
+/// Copies `from` to `to` with `FSCTL_SRV_COPYCHUNK_WRITE` if both are on SMB shares, so that the
+/// server copies the data itself instead of sending it through the client.
+///
+/// Returns `None` if the files are not on SMB shares, or the server cannot copy between them,
+/// e.g. because they are on different servers.
+fn copy_server_side(from: &Path, to: &Path) -> io::Result<Option<u64>> {
+    let src = fs::File::open(from)?;
+    if src.remote_protocol() != Some(ffi::WNNC_NET_SMB) {
+        return Ok(None);
+    }
+    let metadata = src.metadata()?;
+    let dest = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    if dest.remote_protocol() != Some(ffi::WNNC_NET_SMB) {
+        return Ok(None);
+    }
+    // servers without server-side copies refuse to hand out a key
+    let key = match src.resume_key() {
+        Ok(key) => key,
+        Err(_) => return Ok(None),
+    };
+
+    let len = metadata.len();
+    let mut offset = 0;
+    while offset < len {
+        match dest.copy_chunks(&key, &copychunk_batch(offset, len)) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "the SMB server did not copy any data",
+                ))
+            }
+            // a short copy continues after the last byte written
+            Ok(written) => offset += written,
+            // e.g. a destination on another server than the source
+            Err(_) if offset == 0 => return Ok(None),
+            Err(err) => return Err(err),
+        }
+    }
+    dest.set_permissions(metadata.permissions())?;
+    Ok(Some(len))
+}
+
+/// Copies a file using `CopyFile2`, which lets the OS decide whether blocks can be cloned.
+///
+/// An existing `to` is overwritten, like by the other copy fallbacks.
+fn copy_file2(from: &Path, to: &Path) -> io::Result<u64> {
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain(Some(0)).collect()
+    }
+
+    let (src, dest) = (wide(from), wide(to));
+    let mut params: COPYFILE2_EXTENDED_PARAMETERS = unsafe { mem::zeroed() };
+    params.dwSize = mem::size_of::<COPYFILE2_EXTENDED_PARAMETERS>() as u32;
+
+    let hr = unsafe { CopyFile2(src.as_ptr(), dest.as_ptr(), &mut params) };
+    if hr < 0 {
+        // HRESULTs of FACILITY_WIN32 wrap a plain win32 error code.
+        return Err(if (hr >> 16) & 0x1fff == 7 {
+            io::Error::from_raw_os_error(hr & 0xffff)
+        } else {
+            io::Error::other(format!("CopyFile2 failed with HRESULT {:#x}", hr))
+        });
+    }
+    Ok(fs::metadata(to)?.len())
 }
 
 /// Additional functionality for windows files, needed for reflink
 trait FileExt {
-    fn set_sparse(&self) -> io::Result<()>;
+    fn set_sparse(&self, sparse: bool) -> io::Result<()>;
     fn get_integrity_information(&self) -> io::Result<ffi::FSCTL_GET_INTEGRITY_INFORMATION_BUFFER>;
     fn set_integrity_information(
         &self,
         integrity_info: &mut ffi::FSCTL_SET_INTEGRITY_INFORMATION_BUFFER,
     ) -> io::Result<()>;
     fn is_block_cloning_supported(&self) -> io::Result<bool>;
+    fn volume_serial_number(&self) -> io::Result<u32>;
+    fn duplicate_extents(
+        &self,
+        src: &fs::File,
+        src_offset: i64,
+        dest_offset: i64,
+        len: i64,
+    ) -> io::Result<()>;
+    fn copy_file_times(&self, src: &fs::File) -> io::Result<()>;
+    fn retrieval_pointers(&self) -> io::Result<Vec<(i64, i64, i64)>>;
+    fn preallocate(&self, len: u64) -> io::Result<()>;
+    fn remote_protocol(&self) -> Option<u32>;
+    fn resume_key(&self) -> io::Result<[u8; 24]>;
+    fn copy_chunks(&self, key: &[u8; 24], chunks: &[(u64, u32)]) -> io::Result<u64>;
 }
 
 impl FileExt for fs::File {
-    fn set_sparse(&self) -> io::Result<()> {
+    fn set_sparse(&self, sparse: bool) -> io::Result<()> {
         let mut bytes_returned = 0u32;
+        let mut buffer = ffi::FILE_SET_SPARSE_BUFFER {
+            SetSparse: sparse as BOOLEAN,
+        };
         let res = unsafe {
             DeviceIoControl(
                 self.as_raw_handle() as _,
                 FSCTL_SET_SPARSE,
-                ptr::null_mut(),
-                0,
+                &mut buffer as *mut _ as *mut _,
+                mem::size_of::<ffi::FILE_SET_SPARSE_BUFFER>() as u32,
                 ptr::null_mut(),
                 0,
                 &mut bytes_returned as *mut _,
@@ -166,7 +806,8 @@ impl FileExt for fs::File {
                 ptr::null_mut(),
             );
             if res == 0 {
-                Err(io::Error::last_os_error())
+                // the first ioctl to fail on volumes without ReFS
+                Err(classify(io::Error::last_os_error()))
             } else {
                 Ok(integrity_info)
             }
@@ -213,7 +854,6 @@ impl FileExt for fs::File {
         if res == 0 {
             Err(io::Error::last_os_error())
         } else {
-            println!("{:b}", flags);
             if flags & FILE_SUPPORTS_BLOCK_REFCOUNTING > 0 {
                 Ok(true)
             } else {
@@ -221,12 +861,230 @@ impl FileExt for fs::File {
             }
         }
     }
+
+    /// Returns the `(start VCN, next VCN, LCN)` runs mapping the file's clusters to the volume.
+    fn retrieval_pointers(&self) -> io::Result<Vec<(i64, i64, i64)>> {
+        let mut runs = Vec::new();
+        let mut input: STARTING_VCN_INPUT_BUFFER = unsafe { mem::zeroed() };
+        loop {
+            let mut output: ffi::RETRIEVAL_POINTERS_BUFFER = unsafe { mem::zeroed() };
+            let mut bytes_returned = 0u32;
+            let res = unsafe {
+                DeviceIoControl(
+                    self.as_raw_handle() as _,
+                    FSCTL_GET_RETRIEVAL_POINTERS,
+                    &mut input as *mut _ as *mut _,
+                    mem::size_of::<STARTING_VCN_INPUT_BUFFER>() as u32,
+                    &mut output as *mut _ as *mut _,
+                    mem::size_of::<ffi::RETRIEVAL_POINTERS_BUFFER>() as u32,
+                    &mut bytes_returned as *mut _,
+                    ptr::null_mut(),
+                )
+            };
+            let more = if res == 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error().map(|code| code as u32) {
+                    Some(ERROR_MORE_DATA) => true,
+                    // empty files have no clusters
+                    Some(ERROR_HANDLE_EOF) => return Ok(runs),
+                    _ => return Err(classify(err)),
+                }
+            } else {
+                false
+            };
+            let mut vcn = output.StartingVcn;
+            for extent in &output.Extents[..output.ExtentCount as usize] {
+                runs.push((vcn, extent.NextVcn, extent.Lcn));
+                vcn = extent.NextVcn;
+            }
+            if !more || output.ExtentCount == 0 {
+                return Ok(runs);
+            }
+            unsafe {
+                *input.StartingVcn.QuadPart_mut() = vcn;
+            }
+        }
+    }
+
+    fn volume_serial_number(&self) -> io::Result<u32> {
+        let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+        if unsafe { GetFileInformationByHandle(self.as_raw_handle() as _, &mut info) } == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(info.dwVolumeSerialNumber)
+        }
+    }
+
+    fn duplicate_extents(
+        &self,
+        src: &fs::File,
+        src_offset: i64,
+        dest_offset: i64,
+        len: i64,
+    ) -> io::Result<()> {
+        #[cfg(test)]
+        {
+            let calls = tests::DUPLICATE_CALLS.with(|calls| calls.replace(calls.get() + 1));
+            if tests::FAIL_AFTER.with(|fail_after| fail_after.get()) == Some(calls) {
+                return Err(unsupported(UnsupportedReason::Filesystem, None));
+            }
+        }
+        let mut dup_extent: ffi::DUPLICATE_EXTENTS_DATA = unsafe { mem::zeroed() };
+        dup_extent.FileHandle = src.as_raw_handle();
+        unsafe {
+            *dup_extent.SourceFileOffset.QuadPart_mut() = src_offset;
+            *dup_extent.TargetFileOffset.QuadPart_mut() = dest_offset;
+            *dup_extent.ByteCount.QuadPart_mut() = len;
+        }
+        let mut bytes_returned = 0u32;
+        let res = unsafe {
+            DeviceIoControl(
+                self.as_raw_handle() as _,
+                ffi::FSCTL_DUPLICATE_EXTENTS_TO_FILE,
+                &mut dup_extent as *mut _ as *mut _,
+                mem::size_of::<ffi::DUPLICATE_EXTENTS_DATA>() as u32,
+                ptr::null_mut(),
+                0,
+                &mut bytes_returned as *mut _,
+                ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            Err(classify(io::Error::last_os_error()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn copy_file_times(&self, src: &fs::File) -> io::Result<()> {
+        unsafe {
+            let mut created: FILETIME = mem::zeroed();
+            let mut accessed: FILETIME = mem::zeroed();
+            let mut written: FILETIME = mem::zeroed();
+            if GetFileTime(
+                src.as_raw_handle() as _,
+                &mut created,
+                &mut accessed,
+                &mut written,
+            ) == 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if SetFileTime(self.as_raw_handle() as _, &created, &accessed, &written) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn preallocate(&self, len: u64) -> io::Result<()> {
+        let mut info: FILE_ALLOCATION_INFO = unsafe { mem::zeroed() };
+        unsafe {
+            *info.AllocationSize.QuadPart_mut() = len as i64;
+        }
+        let res = unsafe {
+            SetFileInformationByHandle(
+                self.as_raw_handle() as _,
+                FileAllocationInfo,
+                &mut info as *mut _ as *mut _,
+                mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+            )
+        };
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn remote_protocol(&self) -> Option<u32> {
+        let mut info: ffi::FILE_REMOTE_PROTOCOL_INFO = unsafe { mem::zeroed() };
+        let res = unsafe {
+            GetFileInformationByHandleEx(
+                self.as_raw_handle() as _,
+                FileRemoteProtocolInfo,
+                &mut info as *mut _ as *mut _,
+                mem::size_of::<ffi::FILE_REMOTE_PROTOCOL_INFO>() as u32,
+            )
+        };
+        // fails for files on local volumes
+        if res == 0 {
+            None
+        } else {
+            Some(info.Protocol)
+        }
+    }
+
+    fn resume_key(&self) -> io::Result<[u8; 24]> {
+        let mut key: ffi::SRV_RESUME_KEY = unsafe { mem::zeroed() };
+        let mut bytes_returned = 0u32;
+        let res = unsafe {
+            DeviceIoControl(
+                self.as_raw_handle() as _,
+                ffi::FSCTL_SRV_REQUEST_RESUME_KEY,
+                ptr::null_mut(),
+                0,
+                &mut key as *mut _ as *mut _,
+                mem::size_of::<ffi::SRV_RESUME_KEY>() as u32,
+                &mut bytes_returned as *mut _,
+                ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(key.ResumeKey)
+        }
+    }
+
+    fn copy_chunks(&self, key: &[u8; 24], chunks: &[(u64, u32)]) -> io::Result<u64> {
+        debug_assert!(chunks.len() <= COPYCHUNK_COUNT);
+        let mut copy: ffi::SRV_COPYCHUNK_COPY = unsafe { mem::zeroed() };
+        copy.SourceFile = *key;
+        copy.ChunkCount = chunks.len() as u32;
+        for (chunk, &(offset, len)) in copy.Chunk.iter_mut().zip(chunks) {
+            // within the file, whose size Windows keeps as an i64
+            chunk.SourceOffset = offset as i64;
+            chunk.DestinationOffset = offset as i64;
+            chunk.Length = len;
+        }
+        // only the chunks in use are passed
+        let unused = (COPYCHUNK_COUNT - chunks.len()) * mem::size_of::<ffi::SRV_COPYCHUNK>();
+        let mut response: ffi::SRV_COPYCHUNK_RESPONSE = unsafe { mem::zeroed() };
+        let mut bytes_returned = 0u32;
+        let res = unsafe {
+            DeviceIoControl(
+                self.as_raw_handle() as _,
+                ffi::FSCTL_SRV_COPYCHUNK_WRITE,
+                &mut copy as *mut _ as *mut _,
+                (mem::size_of::<ffi::SRV_COPYCHUNK_COPY>() - unused) as u32,
+                &mut response as *mut _ as *mut _,
+                mem::size_of::<ffi::SRV_COPYCHUNK_RESPONSE>() as u32,
+                &mut bytes_returned as *mut _,
+                ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            Err(classify(io::Error::last_os_error()))
+        } else {
+            Ok(u64::from(response.TotalBytesWritten))
+        }
+    }
 }
 
-/// Rounds `num_to_round` to the next multiple of `multiple`, if `mutliple is a power of 2`
-fn round_up(num_to_round: i64, multiple: i64) -> i64 {
-    assert!(multiple != 0 && ((multiple & (multiple - 1)) == 0));
-    (num_to_round + multiple - 1) & -multiple
+/// Wraps errors which denote an unsupported operation into a `ReflinkError::Unsupported`, and
+/// a full volume into a `ReflinkError::OutOfSpace`. Invalid handles are explained.
+fn classify(err: io::Error) -> io::Error {
+    let reason = match err.raw_os_error().map(|code| code as u32) {
+        Some(ERROR_DISK_FULL) | Some(ERROR_HANDLE_DISK_FULL) => return out_of_space(err),
+        // a closed (or recycled) handle
+        Some(ERROR_INVALID_HANDLE) => return bad_handle(err),
+        // returned by file systems without block cloning, e.g. NTFS or FAT
+        Some(ERROR_INVALID_FUNCTION) | Some(ERROR_NOT_SUPPORTED) => UnsupportedReason::Filesystem,
+        Some(ERROR_NOT_SAME_DEVICE) => UnsupportedReason::CrossDevice,
+        _ => return err,
+    };
+    unsupported(reason, Some(err))
 }
 
 /// Contains definitions not included in winapi
@@ -234,9 +1092,12 @@ fn round_up(num_to_round: i64, multiple: i64) -> i64 {
 mod ffi {
     use std::os::windows::raw::HANDLE;
     use winapi::shared::minwindef::{DWORD, WORD};
-    use winapi::shared::ntdef::LARGE_INTEGER;
+    use winapi::shared::ntdef::{BOOLEAN, LARGE_INTEGER};
 
     pub const FSCTL_DUPLICATE_EXTENTS_TO_FILE: u32 = 0x98344;
+    pub const FSCTL_SRV_REQUEST_RESUME_KEY: u32 = 0x140078;
+    pub const FSCTL_SRV_COPYCHUNK_WRITE: u32 = 0x1480F2;
+    pub const WNNC_NET_SMB: DWORD = 0x20000;
 
     #[derive(Debug)]
     #[repr(C)]
@@ -256,6 +1117,18 @@ mod ffi {
         pub Flags: DWORD,
     }
 
+    #[repr(C)]
+    pub struct FILE_SET_SPARSE_BUFFER {
+        pub SetSparse: BOOLEAN,
+    }
+
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct FILE_ALLOCATED_RANGE_BUFFER {
+        pub FileOffset: i64,
+        pub Length: i64,
+    }
+
     #[repr(C)]
     pub struct DUPLICATE_EXTENTS_DATA {
         pub FileHandle: HANDLE,
@@ -263,4 +1136,200 @@ mod ffi {
         pub TargetFileOffset: LARGE_INTEGER,
         pub ByteCount: LARGE_INTEGER,
     }
+
+    #[repr(C)]
+    pub struct FILE_REMOTE_PROTOCOL_INFO {
+        pub StructureVersion: WORD,
+        pub StructureSize: WORD,
+        pub Protocol: DWORD,
+        pub ProtocolMajorVersion: WORD,
+        pub ProtocolMinorVersion: WORD,
+        pub ProtocolRevision: WORD,
+        pub Reserved: WORD,
+        pub Flags: DWORD,
+        pub GenericReserved: [DWORD; 8],
+        pub ProtocolSpecific: [DWORD; 16],
+    }
+
+    /// The output of `FSCTL_SRV_REQUEST_RESUME_KEY`, with room for the context SMB servers
+    /// return.
+    #[repr(C)]
+    pub struct SRV_RESUME_KEY {
+        pub ResumeKey: [u8; 24],
+        pub ContextLength: DWORD,
+        pub Context: [u8; 4],
+    }
+
+    /// `SRV_COPYCHUNK_COPY` with room for the most chunks passed per call.
+    #[repr(C)]
+    pub struct SRV_COPYCHUNK_COPY {
+        pub SourceFile: [u8; 24],
+        pub ChunkCount: DWORD,
+        pub Reserved: DWORD,
+        pub Chunk: [SRV_COPYCHUNK; super::COPYCHUNK_COUNT],
+    }
+
+    #[repr(C)]
+    pub struct SRV_COPYCHUNK {
+        pub SourceOffset: i64,
+        pub DestinationOffset: i64,
+        pub Length: DWORD,
+        pub Reserved: DWORD,
+    }
+
+    #[repr(C)]
+    pub struct SRV_COPYCHUNK_RESPONSE {
+        pub ChunksWritten: DWORD,
+        pub ChunkBytesWritten: DWORD,
+        pub TotalBytesWritten: DWORD,
+    }
+
+    /// `RETRIEVAL_POINTERS_BUFFER` with room for several extents per call.
+    #[repr(C)]
+    pub struct RETRIEVAL_POINTERS_BUFFER {
+        pub ExtentCount: DWORD,
+        pub StartingVcn: i64,
+        pub Extents: [RETRIEVAL_POINTER; 64],
+    }
+
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct RETRIEVAL_POINTER {
+        pub NextVcn: i64,
+        pub Lcn: i64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fs;
+
+    use std::io;
+
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_INVALID_FUNCTION};
+
+    use super::{clone_alignment, max_chunk_len, reflink_file, sparse_refused, Progress};
+    use crate::{ReflinkError, ReflinkOptions, UnsupportedReason};
+
+    thread_local! {
+        /// The `FSCTL_DUPLICATE_EXTENTS_TO_FILE` calls made by the current thread.
+        pub(super) static DUPLICATE_CALLS: Cell<usize> = const { Cell::new(0) };
+        /// Fails the `FSCTL_DUPLICATE_EXTENTS_TO_FILE` call after as many calls succeeded.
+        pub(super) static FAIL_AFTER: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    const MAX_CHUNK: i64 = 4 * 1024 * 1024 * 1024 - 64 * 1024;
+
+    #[test]
+    fn progress_format() {
+        let progress = Progress {
+            len: 5 << 30,
+            modified: 133_000_000_000_000_000,
+            offset: MAX_CHUNK,
+        };
+        let line = format!(
+            "reflink-progress 1 {} {} {}\n",
+            5u64 << 30,
+            133_000_000_000_000_000u64,
+            MAX_CHUNK
+        );
+        assert_eq!(Progress::parse(&line), Some(progress));
+
+        assert_eq!(Progress::parse(""), None);
+        assert_eq!(Progress::parse("reflink-progress 2 1 2 3"), None);
+        assert_eq!(Progress::parse("reflink-progress 1 1 2"), None);
+        assert_eq!(Progress::parse("reflink-progress 1 1 2 3 4"), None);
+        assert_eq!(Progress::parse("reflink-progress 1 1 2 x"), None);
+    }
+
+    #[test]
+    fn small_file_is_cloned_in_one_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let src_path = dir.path().join("src");
+        // not a multiple of the cluster size, so the clone is rounded up
+        fs::write(&src_path, vec![7u8; 3 * 1024 * 1024 + 5]).unwrap();
+        let src = fs::File::open(&src_path).unwrap();
+
+        DUPLICATE_CALLS.with(|calls| calls.set(0));
+        let to = dir.path().join("dest");
+        match reflink_file(&src, &to, &ReflinkOptions::new()) {
+            Ok(_) => {
+                assert_eq!(DUPLICATE_CALLS.with(Cell::get), 1);
+                assert_eq!(fs::read(&to).unwrap(), fs::read(&src_path).unwrap());
+            }
+            // not on ReFS or a Dev Drive
+            Err(_) => assert!(DUPLICATE_CALLS.with(Cell::get) <= 1),
+        }
+    }
+
+    /// Needs a ReFS volume, e.g. `REFLINK_TEST_REFS_DIR_A=R:\tmp`
+    #[test]
+    fn partial_clone_is_completed_by_copy() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let dir = match std::env::var_os("REFLINK_TEST_REFS_DIR_A") {
+            Some(dir) => tempfile::tempdir_in(dir).unwrap(),
+            None => return,
+        };
+        let src_path = dir.path().join("src.img");
+        let mut src = fs::File::create(&src_path).unwrap();
+        let chunk = max_chunk_len(i64::MAX, clone_alignment(&src).unwrap() as i64) as u64;
+        // two chunks, with distinct data around the chunk boundary and at the end
+        let len = chunk + 3 * 1024 * 1024 + 5;
+        let marks = [
+            (0, vec![1u8; 4096]),
+            (chunk - 4096, vec![2u8; 8192]),
+            (len - 4096, vec![3u8; 4096]),
+        ];
+        src.set_len(len).unwrap();
+        for (offset, data) in &marks {
+            src.seek(SeekFrom::Start(*offset)).unwrap();
+            src.write_all(data).unwrap();
+        }
+        drop(src);
+
+        DUPLICATE_CALLS.with(|calls| calls.set(0));
+        FAIL_AFTER.with(|fail_after| fail_after.set(Some(1)));
+        let to = dir.path().join("dest.img");
+        let res = ReflinkOptions::new().reflink_or_copy(&src_path, &to);
+        FAIL_AFTER.with(|fail_after| fail_after.set(None));
+
+        // only the second chunk was copied
+        assert_eq!(res.unwrap(), Some(len - chunk));
+        assert_eq!(DUPLICATE_CALLS.with(Cell::get), 2);
+        let mut dest = fs::File::open(&to).unwrap();
+        assert_eq!(dest.metadata().unwrap().len(), len);
+        for (offset, data) in &marks {
+            let mut read = vec![0u8; data.len()];
+            dest.seek(SeekFrom::Start(*offset)).unwrap();
+            dest.read_exact(&mut read).unwrap();
+            assert_eq!(&read, data, "at {}", offset);
+        }
+    }
+
+    #[test]
+    fn sparse_refused_by_volume() {
+        let refused = || io::Error::from_raw_os_error(ERROR_INVALID_FUNCTION as i32);
+        let mut options = ReflinkOptions::new();
+
+        let err = sparse_refused(refused(), &options).unwrap_err();
+        assert!(matches!(
+            ReflinkError::from_io(&err),
+            Some(ReflinkError::Unsupported {
+                reason: UnsupportedReason::Filesystem,
+                ..
+            })
+        ));
+
+        options.allow_dense_clone(true);
+        assert!(sparse_refused(refused(), &options).is_ok());
+        // other failures are never ignored
+        let denied = io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32);
+        let err = sparse_refused(denied, &options).unwrap_err();
+        assert!(ReflinkError::from_io(&err).is_none());
+        // nor is sparseness which was asked for explicitly
+        options.force_sparse(Some(true));
+        assert!(sparse_refused(refused(), &options).is_err());
+    }
 }
@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::error::verification_failed;
+
+/// How thoroughly a clone is compared with its source, see
+/// [`ReflinkOptions::verify`](crate::ReflinkOptions::verify).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum VerifyLevel {
+    /// Trust the file system, nothing is read back.
+    #[default]
+    None,
+    /// Compare the sizes, and the first block, the last block and a few blocks spread evenly
+    /// in between. Cheap even for huge files, and catches a clone which went wrong as a whole.
+    Sampled,
+    /// Compare the sizes and every byte.
+    Full,
+}
+
+/// The number of blocks compared in between the first and the last one by
+/// [`VerifyLevel::Sampled`].
+const SAMPLES: u64 = 8;
+/// The size of a block compared by [`VerifyLevel::Sampled`].
+const SAMPLE_LEN: u64 = 4096;
+/// The size of the reads made by [`VerifyLevel::Full`].
+const CHUNK_LEN: u64 = 1024 * 1024;
+
+/// Compares `dest` with `src` as thoroughly as `level` asks for.
+///
+/// Fails with `ReflinkError::VerificationFailed` at the first offset where the files differ.
+/// Both files are read by seeking to each offset, so their positions change.
+pub(crate) fn verify(src: &File, dest: &File, level: VerifyLevel) -> io::Result<()> {
+    if level == VerifyLevel::None {
+        return Ok(());
+    }
+    let len = src.metadata()?.len();
+    let dest_len = dest.metadata()?.len();
+    if len != dest_len {
+        return Err(verification_failed(len.min(dest_len)));
+    }
+    if level == VerifyLevel::Full {
+        let mut offset = 0;
+        while offset < len {
+            compare(src, dest, offset, CHUNK_LEN.min(len - offset))?;
+            offset += CHUNK_LEN;
+        }
+        return Ok(());
+    }
+    let mut last_end = 0;
+    for offset in samples(len) {
+        // blocks of small files overlap, so skip what was compared already
+        let start = offset.max(last_end);
+        let end = (offset + SAMPLE_LEN).min(len);
+        if start < end {
+            compare(src, dest, start, end - start)?;
+            last_end = end;
+        }
+    }
+    Ok(())
+}
+
+/// The offsets of the blocks compared by [`VerifyLevel::Sampled`], in ascending order.
+fn samples(len: u64) -> impl Iterator<Item = u64> {
+    let last = len.saturating_sub(SAMPLE_LEN);
+    (0..=SAMPLES + 1).map(move |i| {
+        // align the blocks in between, where clones share whole blocks
+        let offset = last / (SAMPLES + 1) * i;
+        if i == SAMPLES + 1 {
+            last
+        } else {
+            offset - offset % SAMPLE_LEN
+        }
+    })
+}
+
+/// Compares `len` bytes of both files at `offset`.
+fn compare(src: &File, dest: &File, offset: u64, len: u64) -> io::Result<()> {
+    let expected = read_exact_at(src, offset, len)?;
+    let actual = read_exact_at(dest, offset, len)?;
+    match expected.iter().zip(&actual).position(|(a, b)| a != b) {
+        Some(pos) => Err(verification_failed(offset + pos as u64)),
+        None => Ok(()),
+    }
+}
+
+fn read_exact_at(mut file: &File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReflinkError;
+    use std::fs;
+    use std::io::Write;
+
+    fn files(len: usize, corrupt_at: Option<u64>) -> (tempfile::TempDir, File, File) {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("src"), &data).unwrap();
+        let mut dest = data;
+        if let Some(at) = corrupt_at {
+            dest[at as usize] ^= 0xff;
+        }
+        fs::File::create(dir.path().join("dest"))
+            .and_then(|mut f| f.write_all(&dest))
+            .unwrap();
+        let src = File::open(dir.path().join("src")).unwrap();
+        let dest = File::open(dir.path().join("dest")).unwrap();
+        (dir, src, dest)
+    }
+
+    fn failed_at(res: io::Result<()>) -> Option<u64> {
+        let err = res.err()?;
+        match ReflinkError::from_io(&err) {
+            Some(ReflinkError::VerificationFailed { offset }) => Some(*offset),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn none_reads_nothing() {
+        let (_dir, src, dest) = files(100, Some(0));
+        assert_eq!(failed_at(verify(&src, &dest, VerifyLevel::None)), None);
+    }
+
+    #[test]
+    fn sampled_checks_the_ends_and_blocks_in_between() {
+        let len = 1024 * 1024;
+        let (_dir, src, dest) = files(len, None);
+        assert_eq!(failed_at(verify(&src, &dest, VerifyLevel::Sampled)), None);
+        for &at in &[0, len as u64 - 1, 4 * len as u64 / 9 + 100] {
+            let (_dir, src, dest) = files(len, Some(at));
+            assert_eq!(
+                failed_at(verify(&src, &dest, VerifyLevel::Sampled)),
+                Some(at)
+            );
+        }
+        // a single flipped byte between the samples goes unnoticed
+        let (_dir, src, dest) = files(len, Some(SAMPLE_LEN + 1));
+        assert_eq!(failed_at(verify(&src, &dest, VerifyLevel::Sampled)), None);
+    }
+
+    #[test]
+    fn sampled_covers_small_files_entirely() {
+        for &len in &[0, 1, 4095, 4097, 20_000] {
+            let (_dir, src, dest) = files(len, None);
+            assert_eq!(failed_at(verify(&src, &dest, VerifyLevel::Sampled)), None);
+        }
+        let (_dir, src, dest) = files(6000, Some(4500));
+        assert_eq!(
+            failed_at(verify(&src, &dest, VerifyLevel::Sampled)),
+            Some(4500)
+        );
+    }
+
+    #[test]
+    fn full_catches_any_corruption() {
+        let len = 3 * 1024 * 1024 + 17;
+        let (_dir, src, dest) = files(len, None);
+        assert_eq!(failed_at(verify(&src, &dest, VerifyLevel::Full)), None);
+        let (_dir, src, dest) = files(len, Some(SAMPLE_LEN + 1));
+        assert_eq!(
+            failed_at(verify(&src, &dest, VerifyLevel::Full)),
+            Some(SAMPLE_LEN + 1)
+        );
+    }
+
+    #[test]
+    fn size_mismatch_fails() {
+        let (dir, src, _dest) = files(100, None);
+        fs::write(dir.path().join("short"), vec![0; 60]).unwrap();
+        let short = File::open(dir.path().join("short")).unwrap();
+        assert_eq!(
+            failed_at(verify(&src, &short, VerifyLevel::Sampled)),
+            Some(60)
+        );
+    }
+}
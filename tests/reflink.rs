@@ -1,16 +1,18 @@
 use std::fs::File;
 use std::io::{self, Read, Write};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use tempfile::tempdir;
 
-use reflink::{reflink, reflink_or_copy};
+use reflink::{reflink, reflink_or_copy, ReflinkError, UnsupportedReason};
 
 #[test]
 fn reflink_file_does_not_exist() {
     let from = Path::new("test/nonexistent-bogus-path");
     let to = Path::new("test/other-bogus-path");
 
-    match reflink(&from, &to) {
+    match reflink(from, to) {
         Ok(..) => panic!(),
         Err(..) => {
             assert!(!from.exists());
@@ -24,8 +26,8 @@ fn reflink_src_does_not_exist() -> io::Result<()> {
     let tmpdir = tempdir()?;
     let from = Path::new("test/nonexistent-bogus-path");
     let to = tmpdir.path().join("out.txt");
-    File::create(&to)?.write(b"hello")?;
-    assert!(reflink(&from, &to).is_err());
+    File::create(&to)?.write_all(b"hello")?;
+    assert!(reflink(from, &to).is_err());
     assert!(!from.exists());
     let mut v = Vec::new();
     File::open(&to)?.read_to_end(&mut v)?;
@@ -91,7 +93,7 @@ fn reflink_ok() -> io::Result<()> {
     let dest_file_path = dir.path().join("dest.txt");
 
     let mut src_file = File::create(&src_file_path)?;
-    src_file.write(b"this is a test")?;
+    src_file.write_all(b"this is a test")?;
 
     match reflink(&src_file_path, &dest_file_path) {
         Ok(()) => {}
@@ -113,7 +115,7 @@ fn reflink_or_copy_ok() -> io::Result<()> {
     let input = tmpdir.path().join("in.txt");
     let out = tmpdir.path().join("out.txt");
 
-    File::create(&input)?.write(b"hello")?;
+    File::create(&input)?.write_all(b"hello")?;
     reflink_or_copy(&input, &out)?;
     let mut v = Vec::new();
     File::open(&out)?.read_to_end(&mut v)?;
@@ -125,3 +127,3255 @@ fn reflink_or_copy_ok() -> io::Result<()> {
     );
     Ok(())
 }
+
+fn unsupported_reason(e: &io::Error) -> Option<UnsupportedReason> {
+    ReflinkError::from_io(e).and_then(ReflinkError::unsupported_reason)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_cross_device_is_unsupported() -> io::Result<()> {
+    // /dev/shm is a tmpfs mount on virtually every linux system
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return Ok(());
+    }
+    let src_dir = tempdir()?;
+    let dest_dir = tempfile::tempdir_in(shm)?;
+    let src_file_path = src_dir.path().join("src.txt");
+    let dest_file_path = dest_dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let e = reflink(&src_file_path, &dest_file_path).unwrap_err();
+    println!("{:?}", e);
+    if src_dir.path().metadata()?.dev() != dest_dir.path().metadata()?.dev() {
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::CrossDevice));
+    }
+    assert!(!dest_file_path.exists());
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_tmpfs_is_unsupported() -> io::Result<()> {
+    // tmpfs does not implement block cloning
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return Ok(());
+    }
+    let dir = tempfile::tempdir_in(shm)?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let e = reflink(&src_file_path, &dest_file_path).unwrap_err();
+    println!("{:?}", e);
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::Filesystem));
+    let source = std::error::Error::source(ReflinkError::from_io(&e).unwrap()).unwrap();
+    assert!(source.to_string().contains("tmpfs"), "{}", source);
+    assert!(!dest_file_path.exists());
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn reflink_or_copy_with_copy_file2_fallback() -> io::Result<()> {
+    let tmpdir = tempdir()?;
+    let input = tmpdir.path().join("in.txt");
+    let ioctl_out = tmpdir.path().join("ioctl.txt");
+    let copy_file2_out = tmpdir.path().join("copy_file2.txt");
+
+    File::create(&input)?.write_all(b"hello")?;
+    reflink_or_copy(&input, &ioctl_out)?;
+    reflink::ReflinkOptions::new()
+        .copy_file2_fallback(true)
+        .reflink_or_copy(&input, &copy_file2_out)?;
+
+    let (mut a, mut b) = (Vec::new(), Vec::new());
+    File::open(&ioctl_out)?.read_to_end(&mut a)?;
+    File::open(&copy_file2_out)?.read_to_end(&mut b)?;
+    assert_eq!(a, b"hello");
+    assert_eq!(a, b);
+
+    // CopyFile2 overwrites an existing destination, like the other fallbacks
+    File::create(&input)?.write_all(b"hello again")?;
+    reflink::ReflinkOptions::new()
+        .copy_file2_fallback(true)
+        .reflink_or_copy(&input, &copy_file2_out)?;
+    assert_eq!(std::fs::read(&copy_file2_out)?, b"hello again");
+    Ok(())
+}
+
+/// Needs two ReFS volumes with different cluster sizes, e.g.
+/// `REFLINK_TEST_REFS_DIR_A=D:\tmp REFLINK_TEST_REFS_DIR_B=E:\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_mixed_cluster_sizes_is_unsupported() -> io::Result<()> {
+    let (dir_a, dir_b) = match (
+        std::env::var_os("REFLINK_TEST_REFS_DIR_A"),
+        std::env::var_os("REFLINK_TEST_REFS_DIR_B"),
+    ) {
+        (Some(a), Some(b)) => (tempfile::tempdir_in(a)?, tempfile::tempdir_in(b)?),
+        _ => return Ok(()),
+    };
+    let src_file_path = dir_a.path().join("src.txt");
+    let dest_file_path = dir_b.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let e = reflink(&src_file_path, &dest_file_path).unwrap_err();
+    println!(
+        "{}",
+        std::error::Error::source(ReflinkError::from_io(&e).unwrap()).unwrap()
+    );
+    assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::CrossDevice));
+    assert!(!dest_file_path.exists());
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_across_bind_mounts_is_same_device() -> io::Result<()> {
+    use std::process::Command;
+
+    struct Unmount<'a>(&'a Path);
+    impl Drop for Unmount<'_> {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(self.0).status();
+        }
+    }
+
+    let dir = tempdir()?;
+    let (orig, bind) = (dir.path().join("orig"), dir.path().join("bind"));
+    std::fs::create_dir(&orig)?;
+    std::fs::create_dir(&bind)?;
+    // needs CAP_SYS_ADMIN, skip otherwise
+    match Command::new("mount")
+        .arg("--bind")
+        .arg(&orig)
+        .arg(&bind)
+        .output()
+    {
+        Ok(out) if out.status.success() => {}
+        _ => return Ok(()),
+    }
+    let _unmount = Unmount(&bind);
+
+    let src_file_path = orig.join("src.txt");
+    let dest_file_path = bind.join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    assert_eq!(orig.metadata()?.dev(), bind.metadata()?.dev());
+
+    if let Err(e) = reflink(&src_file_path, &dest_file_path) {
+        println!("{:?}", e);
+        assert_ne!(unsupported_reason(&e), Some(UnsupportedReason::CrossDevice));
+        assert!(!dest_file_path.exists());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_or_copy_copies_file_flags() -> io::Result<()> {
+    use std::process::Command;
+
+    fn has_nodump(path: &Path) -> io::Result<bool> {
+        let out = Command::new("lsattr").arg(path).output()?;
+        let out = String::from_utf8_lossy(&out.stdout);
+        Ok(out.split_whitespace().next().unwrap_or("").contains('d'))
+    }
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let plain_file_path = dir.path().join("plain.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    // skip on file systems without inode flags
+    match Command::new("chattr")
+        .arg("+d")
+        .arg(&src_file_path)
+        .status()
+    {
+        Ok(status) if status.success() => {}
+        _ => return Ok(()),
+    }
+    assert!(has_nodump(&src_file_path)?);
+
+    reflink::ReflinkOptions::new()
+        .copy_file_flags(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert!(has_nodump(&dest_file_path)?);
+
+    reflink_or_copy(&src_file_path, &plain_file_path)?;
+    assert!(!has_nodump(&plain_file_path)?);
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn reflink_preserves_bsd_flags() -> io::Result<()> {
+    use std::os::macos::fs::MetadataExt;
+    use std::process::Command;
+
+    // sys/stat.h
+    const UF_HIDDEN: u32 = 0x0000_8000;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    assert!(Command::new("chflags")
+        .arg("hidden")
+        .arg(&src_file_path)
+        .status()?
+        .success());
+    assert_ne!(src_file_path.metadata()?.st_flags() & UF_HIDDEN, 0);
+
+    if let Err(e) = reflink(&src_file_path, &dest_file_path) {
+        println!("{:?}", e);
+        return Ok(());
+    }
+    assert_ne!(dest_file_path.metadata()?.st_flags() & UF_HIDDEN, 0);
+    Ok(())
+}
+
+#[test]
+fn reflink_skip_source_check() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let mut options = reflink::ReflinkOptions::new();
+    options.skip_source_check(true);
+    match options.reflink(&src_file_path, &dest_file_path) {
+        Ok(()) => {
+            let mut v = Vec::new();
+            File::open(&dest_file_path)?.read_to_end(&mut v)?;
+            assert_eq!(v, b"this is a test");
+        }
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(!dest_file_path.exists());
+        }
+    }
+
+    let missing = dir.path().join("missing.txt");
+    let e = options
+        .reflink(&missing, dir.path().join("other.txt"))
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    Ok(())
+}
+
+#[test]
+fn reflink_range_ok() -> io::Result<()> {
+    use std::fs::OpenOptions;
+
+    // 64K is a multiple of every common block and cluster size
+    const BLOCK: usize = 64 * 1024;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.img");
+    let dest_file_path = dir.path().join("dest.img");
+    let mut data = vec![b'a'; BLOCK];
+    data.extend(vec![b'b'; BLOCK]);
+    File::create(&src_file_path)?.write_all(&data)?;
+    File::create(&dest_file_path)?.write_all(&vec![b'c'; 2 * BLOCK])?;
+
+    let src = File::open(&src_file_path)?;
+    let dest = OpenOptions::new().write(true).open(&dest_file_path)?;
+    match reflink::reflink_range(&src, BLOCK as u64, &dest, 0, BLOCK as u64) {
+        Ok(()) => {}
+        Err(e) => {
+            println!("{:?}", e);
+            // do not panic for now, CI envs are old and will probably error out
+            if cfg!(any(target_os = "macos", target_os = "ios")) {
+                assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::Os));
+            }
+            return Ok(());
+        }
+    }
+    let mut v = Vec::new();
+    File::open(&dest_file_path)?.read_to_end(&mut v)?;
+    assert_eq!(&v[..BLOCK], &data[BLOCK..]);
+    assert_eq!(&v[BLOCK..], &vec![b'c'; BLOCK][..]);
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_conflict_policies() -> io::Result<()> {
+    use reflink::{Conflict, ReflinkOptions};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    File::create(&dest_file_path)?.write_all(b"old")?;
+    let read = |path: &Path| -> io::Result<Vec<u8>> {
+        let mut v = Vec::new();
+        File::open(path)?.read_to_end(&mut v)?;
+        Ok(v)
+    };
+
+    let e = ReflinkOptions::new()
+        .on_conflict(Conflict::Error)
+        .reflink(&src_file_path, &dest_file_path)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(read(&dest_file_path)?, b"old");
+
+    let mut options = ReflinkOptions::new();
+    options.on_conflict(Conflict::Suffix);
+    options.reflink_or_copy(&src_file_path, &dest_file_path)?;
+    options.reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert_eq!(read(&dest_file_path)?, b"old");
+    assert_eq!(read(&dir.path().join("dest (1).txt"))?, b"this is a test");
+    assert_eq!(read(&dir.path().join("dest (2).txt"))?, b"this is a test");
+    if let Ok(path) = options.reflink_path(&src_file_path, &dest_file_path) {
+        assert_eq!(path, dir.path().join("dest (3).txt"));
+    }
+
+    ReflinkOptions::new()
+        .on_conflict(Conflict::Overwrite)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert_eq!(read(&dest_file_path)?, b"this is a test");
+    Ok(())
+}
+
+#[test]
+fn reflink_preserve_metadata() -> io::Result<()> {
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let mut src_file = File::create(&src_file_path)?;
+    src_file.write_all(b"this is a test")?;
+    src_file.set_modified(old)?;
+    drop(src_file);
+
+    match reflink::ReflinkOptions::new()
+        .preserve_metadata(true)
+        .reflink(&src_file_path, &dest_file_path)
+    {
+        Ok(()) => {}
+        Err(e) => {
+            println!("{:?}", e);
+            // do not panic for now, CI envs are old and will probably error out
+            return Ok(());
+        }
+    }
+    assert_eq!(dest_file_path.metadata()?.modified()?, old);
+    assert_eq!(
+        src_file_path.metadata()?.permissions(),
+        dest_file_path.metadata()?.permissions()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "tempfile")]
+#[test]
+fn reflink_to_tempfile_persist() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let tmp = match reflink::reflink_to_tempfile(&src_file_path, dir.path()) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            println!("{:?}", e);
+            // do not panic for now, CI envs are old and will probably error out
+            assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+            return Ok(());
+        }
+    };
+    assert_eq!(tmp.path().parent(), Some(dir.path()));
+    tmp.persist(&dest_file_path)?;
+    let mut v = Vec::new();
+    File::open(&dest_file_path)?.read_to_end(&mut v)?;
+    assert_eq!(v, b"this is a test");
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn reflink_across_system_volume_is_cross_device() -> io::Result<()> {
+    // lives on the sealed system volume since macOS 10.15
+    let src_file_path = Path::new("/System/Library/CoreServices/SystemVersion.plist");
+    if !src_file_path.is_file() {
+        return Ok(());
+    }
+    let dir = tempdir()?;
+    let dest_file_path = dir.path().join("dest.plist");
+
+    if let Err(e) = reflink(src_file_path, &dest_file_path) {
+        println!("{}", e);
+        if Path::new("/System/Volumes/Data").is_dir() {
+            assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::CrossDevice));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_rich_copy_fallback() -> io::Result<()> {
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    #[cfg(target_os = "linux")]
+    let has_xattr = xattr::set(&src_file_path, "user.reflink", b"test").is_ok();
+    File::options()
+        .write(true)
+        .open(&src_file_path)?
+        .set_modified(old)?;
+
+    // whether reflinked or copied, the destination must look the same
+    reflink::ReflinkOptions::new()
+        .preserve_metadata(true)
+        .rich_copy_fallback(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert_eq!(dest_file_path.metadata()?.modified()?, old);
+    assert_eq!(
+        src_file_path.metadata()?.permissions(),
+        dest_file_path.metadata()?.permissions()
+    );
+    #[cfg(target_os = "linux")]
+    {
+        if has_xattr {
+            assert_eq!(xattr::get(&dest_file_path, "user.reflink")?, b"test");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn cstr(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let name = CString::new(name).unwrap();
+        let ret = unsafe {
+            libc::setxattr(
+                cstr(path).as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+        let name = CString::new(name).unwrap();
+        let mut buf = vec![0u8; 256];
+        let ret = unsafe {
+            libc::getxattr(
+                cstr(path).as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(ret as usize);
+        Ok(buf)
+    }
+}
+
+#[test]
+fn version_info_reports_current_target() {
+    use reflink::Mechanism;
+
+    let info = reflink::version_info();
+    let expected = if cfg!(any(target_os = "linux", target_os = "android")) {
+        Mechanism::Ficlone
+    } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+        Mechanism::Clonefile
+    } else if cfg!(windows) {
+        Mechanism::DuplicateExtents
+    } else {
+        Mechanism::None
+    };
+    assert_eq!(info.mechanism, expected);
+    assert_eq!(info.os, std::env::consts::OS);
+    assert_eq!(
+        info.features.contains(&"tempfile"),
+        cfg!(feature = "tempfile")
+    );
+}
+
+#[test]
+fn reflink_range_with_clone_alignment() -> io::Result<()> {
+    use std::fs::OpenOptions;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.img");
+    let dest_file_path = dir.path().join("dest.img");
+    File::create(&src_file_path)?.write_all(&[b'a'; 256 * 1024])?;
+    File::create(&dest_file_path)?;
+    let src = File::open(&src_file_path)?;
+    let dest = OpenOptions::new().write(true).open(&dest_file_path)?;
+
+    let align = match reflink::clone_alignment(&src) {
+        Ok(align) => align,
+        Err(e) if cfg!(not(any(target_os = "linux", target_os = "android"))) => {
+            println!("{:?}", e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    assert!(align.is_power_of_two());
+    if cfg!(windows) {
+        dest.set_len(2 * align)?;
+    }
+
+    if let Err(e) = reflink::reflink_range(&src, align, &dest, align, align) {
+        println!("{:?}", e);
+        // do not panic for now, CI envs are old and will probably error out
+        return Ok(());
+    }
+    let mut v = Vec::new();
+    File::open(&dest_file_path)?.read_to_end(&mut v)?;
+    assert_eq!(v.len() as u64, 2 * align);
+    assert!(v[align as usize..].iter().all(|&b| b == b'a'));
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn reflink_or_copy_falls_back_on_ntfs() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    // the temp dir is usually on NTFS, which has no block cloning
+    if let Err(e) = reflink(&src_file_path, &dest_file_path) {
+        println!("{:?}", e);
+        assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::Filesystem));
+        assert!(!dest_file_path.exists());
+        assert_eq!(reflink_or_copy(&src_file_path, &dest_file_path)?, Some(14));
+    }
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_preallocate_fallback() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let data = vec![b'a'; 100 * 1024];
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    let res = reflink::ReflinkOptions::new()
+        .preallocate_fallback(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert!(res.is_none() || res == Some(data.len() as u64));
+    let mut v = Vec::new();
+    File::open(&dest_file_path)?.read_to_end(&mut v)?;
+    assert_eq!(v, data);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_or_copy_preallocate_fallback_detects_full_volume() -> io::Result<()> {
+    use std::process::Command;
+
+    struct Unmount<'a>(&'a Path);
+    impl Drop for Unmount<'_> {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(self.0).status();
+        }
+    }
+
+    let dir = tempdir()?;
+    let small = dir.path().join("small");
+    std::fs::create_dir(&small)?;
+    // needs CAP_SYS_ADMIN, skip otherwise
+    match Command::new("mount")
+        .args(["-t", "tmpfs", "-o", "size=1m", "tmpfs"])
+        .arg(&small)
+        .output()
+    {
+        Ok(out) if out.status.success() => {}
+        _ => return Ok(()),
+    }
+    let _unmount = Unmount(&small);
+
+    // sparse, so it takes no space on the source volume
+    let src_file_path = dir.path().join("src.img");
+    File::create(&src_file_path)?.set_len(64 * 1024 * 1024)?;
+    let dest_file_path = small.join("dest.img");
+
+    let e = reflink::ReflinkOptions::new()
+        .preallocate_fallback(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+    assert!(!dest_file_path.exists());
+    Ok(())
+}
+
+#[test]
+fn hash_file_is_deterministic() -> io::Result<()> {
+    /// 64 bit FNV-1a
+    struct Fnv(u64);
+    impl Write for Fnv {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &b in buf {
+                self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+    let dir = tempdir()?;
+    let path = dir.path().join("src.txt");
+    File::create(&path)?.write_all(b"hello")?;
+
+    let first = reflink::hash_file(&path, Fnv(FNV_OFFSET))?.0;
+    let second = reflink::hash_file(&path, Fnv(FNV_OFFSET))?.0;
+    assert_eq!(first, second);
+    assert_eq!(first, 0xa430_d846_80aa_bd0b);
+
+    File::create(&path)?.write_all(b"world")?;
+    assert_ne!(reflink::hash_file(&path, Fnv(FNV_OFFSET))?.0, first);
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_by_hash_names_by_content() -> io::Result<()> {
+    use reflink::Conflict;
+    use std::time::{Duration, SystemTime};
+
+    /// 64 bit FNV-1a
+    struct Fnv(u64);
+    impl Write for Fnv {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &b in buf {
+                self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+    let dir = tempdir()?;
+    let store = dir.path().join("store");
+    std::fs::create_dir(&store)?;
+    let src = dir.path().join("src.txt");
+    File::create(&src)?.write_all(b"hello")?;
+    let name = |hasher: Fnv| store.join(format!("{:016x}", hasher.0));
+
+    let path = reflink::reflink_or_copy_by_hash(&src, Fnv(FNV_OFFSET), name)?;
+    assert_eq!(path, store.join("a430d84680aabd0b"));
+    assert_eq!(std::fs::read(&path)?, b"hello");
+
+    // the content is in the store already, and the entry is left as it is
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    File::options().write(true).open(&path)?.set_modified(old)?;
+    for &conflict in &[Conflict::Error, Conflict::Overwrite, Conflict::Suffix] {
+        let again = reflink::ReflinkOptions::new()
+            .on_conflict(conflict)
+            .reflink_or_copy_by_hash(&src, Fnv(FNV_OFFSET), name)?;
+        assert_eq!(again, path);
+        assert_eq!(std::fs::metadata(&path)?.modified()?, old);
+    }
+    assert_eq!(std::fs::read_dir(&store)?.count(), 1);
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_to_tmpfile_fd_and_link() -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let src = File::open(&src_file_path)?;
+    let tmp = match reflink::reflink_to_tmpfile_fd(&src, &File::open(dir.path())?) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            println!("{:?}", e);
+            // do not panic for now, CI envs are old and will probably error out
+            assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+            return Ok(());
+        }
+    };
+    // usable before it has a name
+    let mut v = Vec::new();
+    (&tmp).read_to_end(&mut v)?;
+    assert_eq!(v, b"this is a test");
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+    let proc_path = CString::new(format!("/proc/self/fd/{}", tmp.as_raw_fd())).unwrap();
+    let dest = CString::new(dest_file_path.as_os_str().as_bytes()).unwrap();
+    let ret = unsafe {
+        libc::linkat(
+            libc::AT_FDCWD,
+            proc_path.as_ptr(),
+            libc::AT_FDCWD,
+            dest.as_ptr(),
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    };
+    assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+    let mut v = Vec::new();
+    File::open(&dest_file_path)?.read_to_end(&mut v)?;
+    assert_eq!(v, b"this is a test");
+    Ok(())
+}
+
+#[test]
+fn reflink_supported_leaves_no_probe_files() -> io::Result<()> {
+    let dir = tempdir()?;
+    let supported = reflink::reflink_supported(dir.path())?;
+    println!("reflink supported in {:?}: {}", dir.path(), supported);
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+    // tmpfs does not implement block cloning
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if Path::new("/dev/shm").is_dir() {
+            let shm = tempfile::tempdir_in("/dev/shm")?;
+            assert!(!reflink::reflink_supported(shm.path())?);
+        }
+    }
+
+    let e = reflink::reflink_supported(dir.path().join("missing")).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    Ok(())
+}
+
+#[test]
+fn reflink_supported_probes_concurrently() -> io::Result<()> {
+    let dir = tempdir()?;
+    let expected = reflink::reflink_supported(dir.path())?;
+
+    // probe files left behind by a crash are skipped; probes made by other tests in this
+    // process count towards the names as well, so leave plenty
+    for n in 0..32 {
+        let name = format!(".reflink-probe-{}.{}", std::process::id(), n);
+        std::fs::write(dir.path().join(&name), b"stale")?;
+        std::fs::write(dir.path().join(name + ".clone"), b"stale")?;
+    }
+    let results: Vec<io::Result<bool>> = std::thread::scope(|scope| {
+        let probes: Vec<_> = (0..8)
+            .map(|_| scope.spawn(|| reflink::reflink_supported(dir.path())))
+            .collect();
+        probes.into_iter().map(|p| p.join().unwrap()).collect()
+    });
+    for res in results {
+        assert_eq!(res?, expected);
+    }
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 64);
+    Ok(())
+}
+
+#[test]
+fn reflink_reported_ok() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(&[7; 64 * 1024])?;
+
+    let sharing = match reflink::reflink_reported(&src_file_path, &dest_file_path) {
+        Ok(sharing) => sharing,
+        Err(e) => {
+            println!("{:?}", e);
+            // do not panic for now, CI envs are old and will probably error out
+            assert!(unsupported_reason(&e).is_some());
+            assert!(!dest_file_path.exists());
+            return Ok(());
+        }
+    };
+    assert_eq!(sharing, reflink::Sharing::Shared);
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    assert!(reflink::extents_shared(
+        &File::open(&src_file_path)?,
+        &File::open(&dest_file_path)?
+    )?);
+    Ok(())
+}
+
+/// Needs a ReFS volume, e.g. `REFLINK_TEST_REFS_DIR_A=D:\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_cluster_aligned_keeps_exact_size() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_REFS_DIR_A") {
+        Some(a) => tempfile::tempdir_in(a)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let mut src = File::create(&src_file_path)?;
+    let align = reflink::clone_alignment(&src)?;
+    let data: Vec<u8> = (0..align * 3).map(|i| i as u8).collect();
+    src.write_all(&data)?;
+    drop(src);
+
+    for &len in &[align * 3, align * 2 + 1] {
+        let dest_file_path = dir.path().join(format!("dest-{}.bin", len));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&src_file_path)?
+            .set_len(len)?;
+        reflink(&src_file_path, &dest_file_path)?;
+        assert_eq!(std::fs::metadata(&dest_file_path)?.len(), len);
+        assert_eq!(std::fs::read(&dest_file_path)?, &data[..len as usize]);
+    }
+    Ok(())
+}
+
+/// Needs an NFSv4.2 mount, e.g. `REFLINK_TEST_NFS_DIR=/mnt/nfs/tmp`
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_or_copy_on_nfs_copies_server_side() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_NFS_DIR") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    match reflink_or_copy(&src_file_path, &dest_file_path)? {
+        None => println!("NFS server supports CLONE"),
+        Some(written) => assert_eq!(written, data.len() as u64),
+    }
+    assert_eq!(std::fs::read(&dest_file_path)?, data);
+    Ok(())
+}
+
+/// Needs an SMB share, e.g. `REFLINK_TEST_SMB_DIR=\\server\share\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_or_copy_on_smb_copies_server_side() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_SMB_DIR") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    // more than one request of 16 chunks, ending in a partial chunk
+    let data: Vec<u8> = (0..40 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    let written = reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert_eq!(written, Some(data.len() as u64));
+    assert_eq!(std::fs::read(&dest_file_path)?, data);
+    Ok(())
+}
+
+/// Needs a Dev Drive (or another volume refusing `FSCTL_SET_INTEGRITY_INFORMATION`), e.g.
+/// `REFLINK_TEST_DEV_DRIVE_DIR=D:\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_strict_integrity() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_DEV_DRIVE_DIR") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.txt");
+    let strict_path = dir.path().join("strict.txt");
+    let lenient_path = dir.path().join("lenient.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    match reflink::ReflinkOptions::new()
+        .strict_integrity(true)
+        .reflink(&src_file_path, &strict_path)
+    {
+        Ok(()) => println!("the volume accepted the integrity information"),
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(!strict_path.exists());
+        }
+    }
+    reflink(&src_file_path, &lenient_path)?;
+    assert_eq!(std::fs::read(&lenient_path)?, b"this is a test");
+    Ok(())
+}
+
+/// Needs a directory on a volume with block cloning (ReFS or a Dev Drive) which is reached
+/// through a volume mount point or junction on another volume, e.g.
+/// `REFLINK_TEST_MOUNTED_DIR=C:\mnt\devdrive\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_through_mount_point() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_MOUNTED_DIR") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    // both paths resolve to the mounted volume, whatever volume their prefix names
+    reflink(&src_file_path, &dest_file_path)?;
+    assert_eq!(std::fs::read(&dest_file_path)?, b"this is a test");
+
+    let outside = tempdir()?;
+    let outside_src = outside.path().join("src.txt");
+    File::create(&outside_src)?.write_all(b"this is a test")?;
+    let e = reflink(&outside_src, dir.path().join("outside.txt")).unwrap_err();
+    println!("{}", e);
+    assert!(unsupported_reason(&e).is_some());
+    assert!(!dir.path().join("outside.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn reflink_dir_iter_is_lazy() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    let dest = dir.path().join("dest");
+    std::fs::create_dir_all(src.join("a/b"))?;
+    std::fs::create_dir(src.join("empty"))?;
+    std::fs::write(src.join("top.txt"), b"top")?;
+    std::fs::write(src.join("a/one.txt"), b"one")?;
+    std::fs::write(src.join("a/b/two.txt"), b"two")?;
+
+    let mut iter = reflink::reflink_dir_iter(&src, &dest);
+    assert!(!dest.exists());
+    let first = iter.next().unwrap()?;
+    assert!(first.path.starts_with(&dest));
+    assert_eq!(std::fs::read(&first.path)?.len(), 3);
+
+    let mut paths = vec![first.path];
+    for entry in iter {
+        paths.push(entry?.path);
+    }
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            dest.join("a/b/two.txt"),
+            dest.join("a/one.txt"),
+            dest.join("top.txt")
+        ]
+    );
+    assert_eq!(std::fs::read(dest.join("a/b/two.txt"))?, b"two");
+    assert!(dest.join("empty").is_dir());
+
+    // the destination must not exist yet
+    let mut iter = reflink::reflink_dir_iter(&src, &dest);
+    assert_eq!(
+        iter.next().unwrap().unwrap_err().kind(),
+        io::ErrorKind::AlreadyExists
+    );
+    assert!(iter.next().is_none());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_dir_iter_preserve_hard_links() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    std::fs::create_dir_all(src.join("sub"))?;
+    std::fs::write(src.join("a.txt"), b"shared")?;
+    std::fs::hard_link(src.join("a.txt"), src.join("sub/b.txt"))?;
+    std::fs::write(src.join("c.txt"), b"single")?;
+
+    let dest = dir.path().join("dest");
+    for entry in reflink::ReflinkOptions::new()
+        .preserve_hard_links(true)
+        .reflink_dir_iter(&src, &dest)
+    {
+        entry?;
+    }
+    let a = std::fs::metadata(dest.join("a.txt"))?;
+    let b = std::fs::metadata(dest.join("sub/b.txt"))?;
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(a.nlink(), 2);
+    assert_ne!(a.ino(), std::fs::metadata(src.join("a.txt"))?.ino());
+    assert_eq!(std::fs::metadata(dest.join("c.txt"))?.nlink(), 1);
+
+    // without the option, every path becomes an independent file
+    let plain = dir.path().join("plain");
+    for entry in reflink::reflink_dir_iter(&src, &plain) {
+        entry?;
+    }
+    assert_eq!(std::fs::metadata(plain.join("a.txt"))?.nlink(), 1);
+    assert_eq!(std::fs::metadata(plain.join("sub/b.txt"))?.nlink(), 1);
+    Ok(())
+}
+
+#[test]
+fn can_reflink_same_file_system() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let can = reflink::can_reflink(&src_file_path, &dest_file_path)?;
+    assert!(!dest_file_path.exists());
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+    // the answer must match what actually happens
+    assert_eq!(can, reflink(&src_file_path, &dest_file_path).is_ok());
+
+    // clean "no" answers
+    assert!(!reflink::can_reflink(&src_file_path, &src_file_path)?);
+    assert!(!reflink::can_reflink(dir.path(), dir.path().join("other"))?);
+    assert!(reflink::can_reflink(&src_file_path, dir.path().join("missing/dest.txt")).is_err());
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn can_reflink_cross_device() -> io::Result<()> {
+    // /dev/shm is a tmpfs mount on virtually every linux system
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return Ok(());
+    }
+    let dir = tempdir()?;
+    let shm_dir = tempfile::tempdir_in(shm)?;
+    let src_file_path = dir.path().join("src.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    assert!(!reflink::can_reflink(
+        &src_file_path,
+        shm_dir.path().join("dest.txt")
+    )?);
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn reflink_macos_raw_flags() -> io::Result<()> {
+    const CLONE_NOFOLLOW: i32 = 0x0001;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let link_path = dir.path().join("link.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    std::os::unix::fs::symlink(&src_file_path, &link_path)?;
+
+    // by default, clonefile follows the symlink and clones the file
+    let followed = dir.path().join("followed.txt");
+    reflink(&link_path, &followed)?;
+    assert!(std::fs::symlink_metadata(&followed)?.is_file());
+
+    // with CLONE_NOFOLLOW, the symlink itself is cloned
+    let cloned_link = dir.path().join("cloned_link.txt");
+    reflink::ReflinkOptions::new()
+        .macos_raw_flags(CLONE_NOFOLLOW)
+        .reflink(&link_path, &cloned_link)?;
+    assert!(std::fs::symlink_metadata(&cloned_link)?
+        .file_type()
+        .is_symlink());
+    assert_eq!(std::fs::read_link(&cloned_link)?, src_file_path);
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn reflink_keeps_resource_fork() -> io::Result<()> {
+    use reflink::CopyMethod;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    const RESOURCE_FORK: &str = "com.apple.ResourceFork";
+
+    fn cstr(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+    fn resource_fork(path: &Path) -> io::Result<Vec<u8>> {
+        let name = CString::new(RESOURCE_FORK).unwrap();
+        let mut buf = vec![0u8; 4096];
+        let ret = unsafe {
+            libc::getxattr(
+                cstr(path).as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                0,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(ret as usize);
+        Ok(buf)
+    }
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    let fork: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let name = CString::new(RESOURCE_FORK).unwrap();
+    let ret = unsafe {
+        libc::setxattr(
+            cstr(&src_file_path).as_ptr(),
+            name.as_ptr(),
+            fork.as_ptr() as *const libc::c_void,
+            fork.len(),
+            0,
+            0,
+        )
+    };
+    assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+
+    // CLONE_NOOWNERCOPY is set unless the ownership is preserved
+    for &preserve_ownership in &[false, true] {
+        let dest_file_path = dir.path().join(format!("clone-{}.txt", preserve_ownership));
+        reflink::ReflinkOptions::new()
+            .preserve_ownership(preserve_ownership)
+            .reflink(&src_file_path, &dest_file_path)?;
+        assert_eq!(resource_fork(&dest_file_path)?, fork);
+    }
+
+    // the copies which write the data themselves
+    for &sparse in &[false, true] {
+        let dest_file_path = dir.path().join(format!("copy-{}.txt", sparse));
+        reflink::ReflinkOptions::new()
+            .methods(&[CopyMethod::PlainCopy])
+            .preserve_sparseness(sparse)
+            .reflink_with_methods(&src_file_path, &dest_file_path)?;
+        assert_eq!(resource_fork(&dest_file_path)?, fork);
+    }
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_case_insensitive_conflicts() -> io::Result<()> {
+    use reflink::{Conflict, ReflinkOptions};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let existing = dir.path().join("Foo.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    File::create(&existing)?.write_all(b"existing")?;
+    let dest_file_path = dir.path().join("foo.txt");
+
+    let e = ReflinkOptions::new()
+        .case_insensitive_conflicts(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read(&existing)?, b"existing");
+
+    let path = ReflinkOptions::new()
+        .case_insensitive_conflicts(true)
+        .on_conflict(Conflict::Suffix)
+        .reflink_path(&src_file_path, &dest_file_path);
+    match path {
+        Ok(path) => assert_eq!(path, dir.path().join("foo (1).txt")),
+        Err(e) => assert!(unsupported_reason(&e).is_some(), "{:?}", e),
+    }
+
+    ReflinkOptions::new()
+        .case_insensitive_conflicts(true)
+        .on_conflict(Conflict::Overwrite)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    let names: Vec<_> = std::fs::read_dir(dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    assert!(!names.iter().any(|name| name == "Foo.txt"));
+    assert_eq!(
+        std::fs::read(dir.path().join("foo.txt"))?,
+        b"this is a test"
+    );
+    Ok(())
+}
+
+/// Case-insensitive volumes refuse the collision even without the option, e.g. APFS by default.
+#[cfg(any(target_os = "macos", windows))]
+#[test]
+fn reflink_case_collision_on_case_insensitive_volume() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    File::create(dir.path().join("Foo.txt"))?.write_all(b"existing")?;
+    if !dir.path().join("FOO.TXT").exists() {
+        // case-sensitive volume
+        return Ok(());
+    }
+
+    let e = reflink(&src_file_path, dir.path().join("foo.txt")).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    let e = reflink::ReflinkOptions::new()
+        .case_insensitive_conflicts(true)
+        .reflink(&src_file_path, dir.path().join("foo.txt"))
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    Ok(())
+}
+
+#[test]
+fn reflink_never_copies() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let e = match reflink(&src_file_path, &dest_file_path) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    let err = ReflinkError::from_io(&e).expect("unsupported reflinks carry a ReflinkError");
+    println!("refused to reflink because {}", err);
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    assert!(matches!(err, ReflinkError::Unsupported { .. }));
+    assert!(err.to_string().starts_with("reflink not supported: "));
+    let names: Vec<_> = std::fs::read_dir(dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    assert_eq!(names, ["src.txt"]);
+    Ok(())
+}
+
+/// Needs a ReFS volume, e.g. `REFLINK_TEST_REFS_DIR_A=D:\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_resumable_continues_after_interruption() -> io::Result<()> {
+    use std::os::windows::fs::MetadataExt;
+
+    let dir = match std::env::var_os("REFLINK_TEST_REFS_DIR_A") {
+        Some(a) => tempfile::tempdir_in(a)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let sidecar = dir.path().join("dest.bin.reflink-progress");
+
+    // larger than one chunk; ReFS does not write the zeros
+    let len = 5 * 1024 * 1024 * 1024u64;
+    let src = File::create(&src_file_path)?;
+    src.set_len(len)?;
+    drop(src);
+    let src = File::open(&src_file_path)?;
+    let chunk = 4 * 1024 * 1024 * 1024 - reflink::clone_alignment(&src)?;
+
+    // simulate a clone interrupted after the first chunk
+    let dest = File::create(&dest_file_path)?;
+    dest.set_len(len)?;
+    reflink::reflink_range(&src, 0, &dest, 0, chunk)?;
+    drop(dest);
+    let modified = std::fs::metadata(&src_file_path)?.last_write_time();
+    std::fs::write(
+        &sidecar,
+        format!("reflink-progress 1 {} {} {}\n", len, modified, chunk),
+    )?;
+
+    reflink::ReflinkOptions::new()
+        .resumable(true)
+        .reflink(&src_file_path, &dest_file_path)?;
+    assert_eq!(std::fs::metadata(&dest_file_path)?.len(), len);
+    assert!(!sidecar.exists());
+
+    // without a sidecar, an existing destination is a conflict as usual
+    let e = reflink::ReflinkOptions::new()
+        .resumable(true)
+        .reflink(&src_file_path, &dest_file_path)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_or_copy_chown() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    let res = reflink::ReflinkOptions::new()
+        .chown(1234, 5678)
+        .reflink_or_copy(&src_file_path, &dest_file_path);
+    if unsafe { libc::geteuid() } != 0 {
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(!dest_file_path.exists());
+        return Ok(());
+    }
+    res?;
+    let metadata = std::fs::metadata(&dest_file_path)?;
+    assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+    Ok(())
+}
+
+#[test]
+fn extents_shared_by_clones_only() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let clone_path = dir.path().join("clone.bin");
+    let copy_path = dir.path().join("copy.bin");
+    let empty_path = dir.path().join("empty.bin");
+    File::create(&src_file_path)?.write_all(&[7; 256 * 1024])?;
+    File::create(&empty_path)?;
+    std::fs::copy(&src_file_path, &copy_path)?;
+
+    let src = File::open(&src_file_path)?;
+    match reflink::extents_shared(&src, &File::open(&copy_path)?) {
+        Ok(shared) => assert!(!shared),
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(unsupported_reason(&e).is_some());
+            return Ok(());
+        }
+    }
+    assert!(!reflink::extents_shared(&src, &File::open(&empty_path)?)?);
+    // a file trivially shares its blocks with itself
+    assert!(reflink::extents_shared(&src, &File::open(&src_file_path)?)?);
+
+    if reflink(&src_file_path, &clone_path).is_ok() {
+        assert!(reflink::extents_shared(&src, &File::open(&clone_path)?)?);
+    }
+    Ok(())
+}
+
+/// Needs a ReFS volume, e.g. `REFLINK_TEST_REFS_DIR_A=D:\tmp`
+#[cfg(windows)]
+#[test]
+fn extents_shared_after_refs_clone() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_REFS_DIR_A") {
+        Some(a) => tempfile::tempdir_in(a)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    File::create(&src_file_path)?.write_all(&[7; 1024 * 1024])?;
+
+    reflink(&src_file_path, &dest_file_path)?;
+    assert!(reflink::extents_shared(
+        &File::open(&src_file_path)?,
+        &File::open(&dest_file_path)?
+    )?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_or_copy_from_fifo() -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir()?;
+    let fifo_path = dir.path().join("fifo");
+    let dest_file_path = dir.path().join("dest.txt");
+    let path = CString::new(fifo_path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let e = reflink_or_copy(&fifo_path, &dest_file_path).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    let writer = {
+        let (fifo_path, data) = (fifo_path.clone(), data.clone());
+        std::thread::spawn(move || -> io::Result<()> {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(fifo_path)?
+                .write_all(&data)
+        })
+    };
+    let written = reflink::ReflinkOptions::new()
+        .allow_nonregular_copy(true)
+        .reflink_or_copy(&fifo_path, &dest_file_path)?;
+    writer.join().unwrap()?;
+    assert_eq!(written, Some(data.len() as u64));
+    assert_eq!(std::fs::read(&dest_file_path)?, data);
+    Ok(())
+}
+
+#[test]
+fn reflink_overwrite_existing_clone_is_noop() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    File::create(&src_file_path)?.write_all(&[9; 256 * 1024])?;
+
+    let mut options = reflink::ReflinkOptions::new();
+    options.on_conflict(reflink::Conflict::Overwrite);
+    if let Err(e) = options.reflink(&src_file_path, &dest_file_path) {
+        println!("{:?}", e);
+        assert!(unsupported_reason(&e).is_some());
+        return Ok(());
+    }
+    let first = File::open(&dest_file_path)?;
+    if !reflink::extents_shared(&File::open(&src_file_path)?, &first).unwrap_or(false) {
+        return Ok(());
+    }
+
+    options.reflink(&src_file_path, &dest_file_path)?;
+    // the open handle still refers to the destination, so it was not removed and re-cloned
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(first.metadata()?.nlink(), 1);
+    }
+    assert_eq!(std::fs::read(&dest_file_path)?, vec![9; 256 * 1024]);
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_exclusive_create() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"source")?;
+    File::create(&dest_file_path)?.write_all(b"an older and longer destination")?;
+
+    let e = reflink::ReflinkOptions::new()
+        .exclusive_create(true)
+        .reflink(&src_file_path, &dest_file_path)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert!(dest_file_path.exists());
+
+    match reflink::ReflinkOptions::new()
+        .exclusive_create(false)
+        .reflink(&src_file_path, &dest_file_path)
+    {
+        Ok(()) => assert_eq!(std::fs::read(&dest_file_path)?, b"source"),
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(unsupported_reason(&e).is_some());
+            assert!(!dest_file_path.exists());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn copy_tree_reports_entries() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    let dest = dir.path().join("dest");
+    std::fs::create_dir_all(src.join("sub").join("deeper"))?;
+    std::fs::write(src.join("a.txt"), b"abc")?;
+    std::fs::write(src.join("sub").join("b.txt"), b"bcdef")?;
+    std::fs::write(src.join("sub").join("deeper").join("c.txt"), b"cdefghi")?;
+
+    let report = reflink::copy_tree(&src, &dest, &reflink::ReflinkOptions::new())?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.reflinked + report.copied, 3);
+    assert_eq!(report.bytes, 15);
+    assert_eq!(
+        std::fs::read(dest.join("sub").join("deeper").join("c.txt"))?,
+        b"cdefghi"
+    );
+
+    let e = reflink::copy_tree(
+        src.join("a.txt"),
+        dir.path().join("file"),
+        &Default::default(),
+    )
+    .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = reflink::copy_tree(&src, &dest, &Default::default()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn copy_tree_reports_failures() -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    let dest = dir.path().join("dest");
+    std::fs::create_dir_all(src.join("sub"))?;
+    std::fs::write(src.join("sub").join("b.txt"), b"bcdef")?;
+    let fifo = CString::new(src.join("sub").join("fifo").as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let report = reflink::copy_tree(&src, &dest, &reflink::ReflinkOptions::new())?;
+    assert_eq!(report.reflinked + report.copied, 1);
+    assert_eq!(report.bytes, 5);
+    assert_eq!(report.failures.len(), 1);
+    let (path, e) = &report.failures[0];
+    assert_eq!(path, &dest.join("sub").join("fifo"));
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+/// Needs a ReFS volume, e.g. `REFLINK_TEST_REFS_DIR_A=D:\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_force_sparse() -> io::Result<()> {
+    use std::os::windows::fs::MetadataExt;
+    use std::process::Command;
+
+    const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x200;
+    let dir = match std::env::var_os("REFLINK_TEST_REFS_DIR_A") {
+        Some(a) => tempfile::tempdir_in(a)?,
+        None => return Ok(()),
+    };
+    let is_sparse = |path: &Path| -> io::Result<bool> {
+        Ok(std::fs::metadata(path)?.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0)
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dense_path = dir.path().join("dense.bin");
+    let sparse_path = dir.path().join("sparse.bin");
+    let mut data = vec![0; 1024 * 1024];
+    data[..4096].copy_from_slice(&[1; 4096]);
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    reflink::ReflinkOptions::new()
+        .force_sparse(Some(true))
+        .reflink(&src_file_path, &sparse_path)?;
+    assert!(is_sparse(&sparse_path)?);
+
+    // punch a hole behind the first 64K
+    let src = src_file_path.to_str().unwrap();
+    assert!(Command::new("fsutil")
+        .args(["sparse", "setflag", src])
+        .status()?
+        .success());
+    assert!(Command::new("fsutil")
+        .args(["sparse", "setrange", src, "65536", "983040"])
+        .status()?
+        .success());
+    assert!(is_sparse(&src_file_path)?);
+
+    reflink::ReflinkOptions::new()
+        .force_sparse(Some(false))
+        .reflink(&src_file_path, &dense_path)?;
+    assert!(!is_sparse(&dense_path)?);
+    assert_eq!(std::fs::read(&dense_path)?, data);
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_large_file() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    // not a multiple of any block or transfer size, so that short transfers show
+    let mut rng = fastrand::Rng::with_seed(0x0072_6566_6c69_6e6b);
+    let data: Vec<u8> = (0..48 * 1024 * 1024 + 12_345).map(|_| rng.u8(..)).collect();
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    if let Some(written) = reflink_or_copy(&src_file_path, &dest_file_path)? {
+        assert_eq!(written, data.len() as u64);
+    }
+    assert!(std::fs::read(&dest_file_path)? == data);
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_or_copy_dest_mode_ignores_umask() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    std::fs::set_permissions(&src_file_path, std::fs::Permissions::from_mode(0o600))?;
+
+    let old_umask = unsafe { libc::umask(0o077) };
+    let res = reflink::ReflinkOptions::new()
+        .dest_mode(0o654)
+        .reflink_or_copy(&src_file_path, &dest_file_path);
+    unsafe { libc::umask(old_umask) };
+    res?;
+
+    let mode = std::fs::metadata(&dest_file_path)?.permissions().mode();
+    assert_eq!(mode & 0o7777, 0o654);
+    Ok(())
+}
+
+/// Needs two btrfs file systems, e.g.
+/// `REFLINK_TEST_BTRFS_DIR_A=/mnt/btrfs-a/tmp REFLINK_TEST_BTRFS_DIR_B=/mnt/btrfs-b/tmp`
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_or_copy_preserve_compression() -> io::Result<()> {
+    use std::process::Command;
+
+    fn is_compressed(path: &Path) -> io::Result<bool> {
+        let out = Command::new("lsattr").arg(path).output()?;
+        let out = String::from_utf8_lossy(&out.stdout);
+        Ok(out.split_whitespace().next().unwrap_or("").contains('c'))
+    }
+
+    let (dir_a, dir_b) = match (
+        std::env::var_os("REFLINK_TEST_BTRFS_DIR_A"),
+        std::env::var_os("REFLINK_TEST_BTRFS_DIR_B"),
+    ) {
+        (Some(a), Some(b)) => (tempfile::tempdir_in(a)?, tempfile::tempdir_in(b)?),
+        _ => return Ok(()),
+    };
+    let src_file_path = dir_a.path().join("src.txt");
+    let dest_file_path = dir_b.path().join("dest.txt");
+    let plain_file_path = dir_b.path().join("plain.txt");
+    // the flag only takes effect on empty files
+    File::create(&src_file_path)?;
+    assert!(Command::new("chattr")
+        .arg("+c")
+        .arg(&src_file_path)
+        .status()?
+        .success());
+    File::create(&src_file_path)?.write_all(&[b'a'; 256 * 1024])?;
+    assert!(is_compressed(&src_file_path)?);
+
+    let written = reflink::ReflinkOptions::new()
+        .preserve_compression(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert_eq!(written, Some(256 * 1024));
+    assert!(is_compressed(&dest_file_path)?);
+
+    reflink_or_copy(&src_file_path, &plain_file_path)?;
+    assert!(!is_compressed(&plain_file_path)?);
+    Ok(())
+}
+
+#[test]
+fn reflink_accounted_shares_everything() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let len = 1024 * 1024 + 123;
+    File::create(&src_file_path)?.write_all(&vec![3; len])?;
+
+    match reflink::reflink_accounted(&src_file_path, &dest_file_path) {
+        Ok(stats) => {
+            assert_eq!(stats.total_bytes, len as u64);
+            assert_eq!(stats.shared_bytes, stats.total_bytes);
+        }
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(unsupported_reason(&e).is_some());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_keep_on_failure() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let copy_file_path = dir.path().join("copy.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+
+    // only file systems without reflink support make the clone fail reliably
+    if let Err(e) = reflink(&src_file_path, &dest_file_path) {
+        println!("{:?}", e);
+        assert!(!dest_file_path.exists());
+        reflink::ReflinkOptions::new()
+            .keep_on_failure(true)
+            .reflink(&src_file_path, &dest_file_path)
+            .unwrap_err();
+        assert!(dest_file_path.exists());
+
+        let written = reflink::ReflinkOptions::new()
+            .keep_on_failure(true)
+            .reflink_or_copy(&src_file_path, &copy_file_path)?;
+        assert_eq!(written, Some(14));
+        assert_eq!(std::fs::read(&copy_file_path)?, b"this is a test");
+    }
+    Ok(())
+}
+
+#[test]
+fn reflink_read_only_source() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let copy_file_path = dir.path().join("copy.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&src_file_path, std::fs::Permissions::from_mode(0o400))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(&src_file_path)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&src_file_path, permissions)?;
+    }
+
+    match reflink::ReflinkOptions::new()
+        .preserve_metadata(true)
+        .reflink(&src_file_path, &dest_file_path)
+    {
+        Ok(()) => assert_eq!(std::fs::read(&dest_file_path)?, b"this is a test"),
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(unsupported_reason(&e).is_some());
+        }
+    }
+    // the copy inherits the read-only mode before its times are set
+    reflink::ReflinkOptions::new()
+        .rich_copy_fallback(true)
+        .reflink_or_copy(&src_file_path, &copy_file_path)?;
+    assert_eq!(std::fs::read(&copy_file_path)?, b"this is a test");
+    assert_eq!(
+        std::fs::metadata(&copy_file_path)?.modified()?,
+        std::fs::metadata(&src_file_path)?.modified()?
+    );
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_steps_to_completion() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    let mut state =
+        reflink::ReflinkOptions::new().reflink_or_copy_steps(&src_file_path, &dest_file_path);
+    assert!(!dest_file_path.exists());
+    let mut progress = Vec::new();
+    let result = loop {
+        match state.step()? {
+            reflink::StepResult::Progress { copied, total } => {
+                assert_eq!(total, data.len() as u64);
+                progress.push(copied);
+            }
+            reflink::StepResult::Done(result) => break result,
+        }
+    };
+    match result {
+        None => assert!(progress.is_empty()),
+        Some(written) => {
+            assert_eq!(written, data.len() as u64);
+            // the preparation, three full chunks and the remainder
+            assert_eq!(progress.len(), 5);
+            assert!(progress.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+    assert_eq!(state.step()?, reflink::StepResult::Done(result));
+    assert_eq!(state.dest(), dest_file_path);
+    assert!(std::fs::read(&dest_file_path)? == data);
+
+    let mut state = reflink::ReflinkOptions::new()
+        .reflink_or_copy_steps(dir.path().join("missing.bin"), dir.path().join("x.bin"));
+    assert_eq!(
+        state.step().unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        state.step().unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    Ok(())
+}
+
+/// Needs a ReFS volume, e.g. `REFLINK_TEST_REFS_DIR_A=D:\tmp`
+#[cfg(windows)]
+#[test]
+fn reflink_access_rights() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_REFS_DIR_A") {
+        Some(a) => tempfile::tempdir_in(a)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let range_file_path = dir.path().join("range.bin");
+    let mut src = File::create(&src_file_path)?;
+    let align = reflink::clone_alignment(&src)?;
+    src.write_all(&vec![5; align as usize])?;
+
+    // another writer must not keep the source from being cloned
+    let writer = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&src_file_path)?;
+    reflink(&src_file_path, &dest_file_path)?;
+    drop(writer);
+    assert_eq!(std::fs::read(&dest_file_path)?, vec![5; align as usize]);
+
+    File::create(&range_file_path)?.set_len(align)?;
+    let read_only = File::open(&range_file_path)?;
+    let e =
+        reflink::reflink_range(&File::open(&src_file_path)?, 0, &read_only, 0, align).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    let write_only = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&range_file_path)?;
+    reflink::reflink_range(&File::open(&src_file_path)?, 0, &write_only, 0, align)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_beneath_rejects_escapes() -> io::Result<()> {
+    let dir = tempdir()?;
+    let sandbox_path = dir.path().join("sandbox");
+    std::fs::create_dir(&sandbox_path)?;
+    std::fs::write(dir.path().join("secret.txt"), b"secret")?;
+    std::fs::write(sandbox_path.join("src.txt"), b"this is a test")?;
+    std::os::unix::fs::symlink("../secret.txt", sandbox_path.join("link.txt"))?;
+    let sandbox = File::open(&sandbox_path)?;
+
+    match reflink::reflink_beneath(&sandbox, "src.txt", "dest.txt") {
+        Ok(()) => assert_eq!(
+            std::fs::read(sandbox_path.join("dest.txt"))?,
+            b"this is a test"
+        ),
+        Err(e) if unsupported_reason(&e) == Some(UnsupportedReason::Syscall) => return Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(unsupported_reason(&e).is_some());
+            assert!(!sandbox_path.join("dest.txt").exists());
+        }
+    }
+
+    for &(from, to) in &[
+        ("../secret.txt", "stolen.txt"),
+        ("link.txt", "stolen.txt"),
+        ("src.txt", "../escaped.txt"),
+        ("src.txt", "/tmp/escaped.txt"),
+    ] {
+        let e = reflink::reflink_beneath(&sandbox, from, to).unwrap_err();
+        assert_eq!(
+            e.kind(),
+            io::ErrorKind::PermissionDenied,
+            "{} -> {}",
+            from,
+            to
+        );
+    }
+    assert!(!sandbox_path.join("stolen.txt").exists());
+    assert!(!dir.path().join("escaped.txt").exists());
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", windows))]
+#[test]
+fn reflink_or_copy_preserves_creation_time() -> io::Result<()> {
+    #[cfg(target_os = "ios")]
+    use std::os::ios::fs::FileTimesExt;
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let mut src = File::create(&src_file_path)?;
+    src.write_all(b"this is a test")?;
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    src.set_times(std::fs::FileTimes::new().set_created(created))?;
+    drop(src);
+
+    reflink::ReflinkOptions::new()
+        .preserve_metadata(true)
+        .rich_copy_fallback(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    assert_eq!(std::fs::metadata(&dest_file_path)?.created()?, created);
+    Ok(())
+}
+
+#[test]
+fn reflink_fanout_to_several_targets() -> io::Result<()> {
+    let dir = tempdir()?;
+    let base_path = dir.path().join("base.bin");
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    File::create(&base_path)?.write_all(&data)?;
+    let targets: Vec<_> = (0..4)
+        .map(|i| dir.path().join(format!("target-{}.bin", i)))
+        .collect();
+    let target_refs: Vec<&Path> = targets.iter().map(|p| p.as_path()).collect();
+
+    let results = reflink::reflink_fanout(&File::open(&base_path)?, &target_refs);
+    assert_eq!(results.len(), targets.len());
+    for (result, target) in results.into_iter().zip(&targets) {
+        match result {
+            Ok(()) => assert!(std::fs::read(target)? == data),
+            Err(e) => {
+                println!("{:?}", e);
+                assert!(unsupported_reason(&e).is_some());
+                assert!(!target.exists());
+            }
+        }
+    }
+
+    // an existing target only fails its own clone
+    let results = reflink::reflink_fanout(
+        &File::open(&base_path)?,
+        &[&base_path, &dir.path().join("fresh.bin")],
+    );
+    assert_eq!(
+        results[0].as_ref().unwrap_err().kind(),
+        io::ErrorKind::AlreadyExists
+    );
+    assert!(results[1]
+        .as_ref()
+        .map_or_else(|e| unsupported_reason(e).is_some(), |_| true));
+
+    let results = reflink::reflink_fanout(&File::open(dir.path())?, &[&targets[0]]);
+    assert_eq!(
+        results[0].as_ref().unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn reflink_sparse_file_keeps_holes() -> io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let len = 64 * 1024 * 1024;
+    let mut src = File::create(&src_file_path)?;
+    src.write_all(&[1; 4096])?;
+    src.seek(SeekFrom::Start(len - 4096))?;
+    src.write_all(&[2; 4096])?;
+    src.sync_all()?;
+    drop(src);
+    let src_metadata = std::fs::metadata(&src_file_path)?;
+    // skip on volumes without sparse file support (e.g. HFS+)
+    if src_metadata.blocks() * 512 >= len {
+        return Ok(());
+    }
+
+    if let Err(e) = reflink(&src_file_path, &dest_file_path) {
+        println!("{:?}", e);
+        assert!(unsupported_reason(&e).is_some());
+        return Ok(());
+    }
+    let dest_metadata = std::fs::metadata(&dest_file_path)?;
+    assert_eq!(dest_metadata.len(), len);
+    // the holes were not materialized
+    assert!(dest_metadata.blocks() <= src_metadata.blocks());
+    let data = std::fs::read(&dest_file_path)?;
+    assert!(data[..4096].iter().all(|&b| b == 1));
+    assert!(data[4096..len as usize - 4096].iter().all(|&b| b == 0));
+    assert!(data[len as usize - 4096..].iter().all(|&b| b == 2));
+    Ok(())
+}
+
+#[test]
+fn runtime_supported_matches_os_version() {
+    match (reflink::min_os_version(), reflink::os_version()) {
+        (Some(min), Some(running)) => assert_eq!(reflink::runtime_supported(), running >= min),
+        (Some(_), None) => assert!(reflink::runtime_supported()),
+        (None, _) => assert!(!reflink::runtime_supported()),
+    }
+    #[cfg(target_os = "linux")]
+    assert!(reflink::os_version().is_some());
+}
+
+#[test]
+fn reflink_drop_caches() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.bin");
+    let data = vec![0x5a; 256 * 1024];
+    File::create(&src_file_path)?.write_all(&data)?;
+
+    let mut options = reflink::ReflinkOptions::new();
+    options.drop_caches(true);
+    let reflinked = dir.path().join("reflinked.bin");
+    match options.reflink(&src_file_path, &reflinked) {
+        Ok(()) => assert_eq!(std::fs::read(&reflinked)?, data),
+        Err(e) => {
+            println!("{:?}", e);
+            assert!(unsupported_reason(&e).is_some());
+        }
+    }
+    let copied = dir.path().join("copied.bin");
+    options.reflink_or_copy(&src_file_path, &copied)?;
+    assert_eq!(std::fs::read(&copied)?, data);
+    assert_eq!(std::fs::read(&src_file_path)?, data);
+    Ok(())
+}
+
+#[test]
+fn display_messages() {
+    use reflink::{
+        ClonedEntry, Conflict, CopyMethod, FileKind, Mechanism, ShareStats, Sharing, StepResult,
+        TreeReport,
+    };
+    use std::error::Error;
+
+    let os_err = || io::Error::from_raw_os_error(28);
+    let err = ReflinkError::Unsupported {
+        reason: UnsupportedReason::CrossDevice,
+        source: None,
+    };
+    assert_eq!(
+        err.to_string(),
+        "reflink not supported: source and destination are on different file systems"
+    );
+    assert!(err.source().is_none());
+    let err = ReflinkError::OutOfSpace { source: os_err() };
+    assert_eq!(err.to_string(), "not enough space left for the reflink");
+    assert_eq!(err.source().unwrap().to_string(), os_err().to_string());
+    let err = ReflinkError::SourceVanished { source: os_err() };
+    assert_eq!(
+        err.to_string(),
+        "the source was removed before it could be copied"
+    );
+    assert!(err.source().is_some());
+    assert_eq!(
+        ReflinkError::Aborted.to_string(),
+        "the reflink was denied by the audit callback"
+    );
+    assert!(ReflinkError::Aborted.source().is_none());
+    assert_eq!(
+        ReflinkError::Cancelled.to_string(),
+        "the reflink was cancelled"
+    );
+    assert!(ReflinkError::Cancelled.source().is_none());
+    let err = ReflinkError::VerificationFailed { offset: 4096 };
+    assert_eq!(
+        err.to_string(),
+        "the clone differs from the source at byte 4096"
+    );
+    assert!(err.source().is_none());
+
+    assert_eq!(
+        UnsupportedReason::Os.to_string(),
+        "the operating system does not support block cloning"
+    );
+    for reason in [UnsupportedReason::Filesystem, UnsupportedReason::Syscall] {
+        let err = ReflinkError::Unsupported {
+            reason,
+            source: Some(os_err()),
+        };
+        assert_eq!(
+            err.to_string(),
+            format!("reflink not supported: {}", reason)
+        );
+        assert!(err.source().is_some());
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        assert_eq!(
+            UnsupportedReason::Filesystem.to_string(),
+            "the file system does not support block cloning"
+        );
+        assert_eq!(
+            UnsupportedReason::Syscall.to_string(),
+            "the running kernel lacks a syscall needed for cloning"
+        );
+    }
+    #[cfg(windows)]
+    assert!(UnsupportedReason::Filesystem.to_string().contains("ReFS"));
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    assert!(UnsupportedReason::Filesystem.to_string().contains("APFS"));
+
+    assert_eq!(Mechanism::Ficlone.to_string(), "ioctl FICLONE");
+    assert_eq!(Mechanism::Clonefile.to_string(), "clonefile");
+    assert_eq!(
+        Mechanism::DuplicateExtents.to_string(),
+        "ioctl FSCTL_DUPLICATE_EXTENTS_TO_FILE"
+    );
+    assert_eq!(Mechanism::None.to_string(), "none");
+
+    assert_eq!(
+        Conflict::Error.to_string(),
+        "fail if the destination exists"
+    );
+    assert_eq!(
+        Conflict::Overwrite.to_string(),
+        "overwrite an existing destination"
+    );
+    assert_eq!(
+        Conflict::Suffix.to_string(),
+        "pick a free name for the destination"
+    );
+    let err = ReflinkError::NotRegular {
+        kind: FileKind::CharDevice,
+    };
+    assert_eq!(
+        err.to_string(),
+        "the source is a character device, not a regular file"
+    );
+    assert!(err.source().is_none());
+    assert_eq!(CopyMethod::HardLink.to_string(), "hard link");
+    assert_eq!(CopyMethod::CopyFileRange.to_string(), "copy_file_range");
+    assert_eq!(Sharing::Shared.to_string(), "blocks shared with the source");
+    assert_eq!(
+        Sharing::Duplicated.to_string(),
+        "data duplicated by the file system"
+    );
+    let stats = ShareStats {
+        shared_bytes: 4096,
+        total_bytes: 8192,
+    };
+    assert_eq!(
+        stats.to_string(),
+        "4096 of 8192 bytes shared with the source"
+    );
+
+    let progress = StepResult::Progress {
+        copied: 1,
+        total: 2,
+    };
+    assert_eq!(progress.to_string(), "copied 1 of 2 bytes");
+    assert_eq!(StepResult::Done(None).to_string(), "reflinked");
+    assert_eq!(StepResult::Done(Some(3)).to_string(), "copied 3 bytes");
+
+    let entry = ClonedEntry {
+        path: Path::new("a.txt").to_path_buf(),
+        mechanism: Some(Mechanism::Ficlone),
+    };
+    assert_eq!(entry.to_string(), "a.txt (reflinked with ioctl FICLONE)");
+    let entry = ClonedEntry {
+        path: Path::new("b.txt").to_path_buf(),
+        mechanism: None,
+    };
+    assert_eq!(entry.to_string(), "b.txt (copied)");
+    let mut report = TreeReport {
+        reflinked: 2,
+        copied: 1,
+        bytes: 10,
+        ..TreeReport::default()
+    };
+    assert_eq!(report.to_string(), "2 reflinked, 1 copied, 10 bytes");
+    report
+        .failures
+        .push((Path::new("c.txt").to_path_buf(), os_err()));
+    assert_eq!(
+        report.to_string(),
+        "2 reflinked, 1 copied, 10 bytes, 1 failed"
+    );
+
+    let info = reflink::version_info();
+    assert!(info.to_string().starts_with(&format!(
+        "reflink {} ({}-{}, {}",
+        info.version, info.os, info.arch, info.mechanism
+    )));
+    assert_eq!(reflink::OsVersion::new(10, 12, 0).to_string(), "10.12.0");
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_stale_handle() -> io::Result<()> {
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    let src = File::open(&src_file_path)?;
+    let dest = File::create(dir.path().join("dest.txt"))?;
+
+    // a high descriptor number, which the other tests running meanwhile will not reuse
+    let fd = unsafe { libc::fcntl(src.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 900) };
+    assert_ne!(fd, -1, "{}", io::Error::last_os_error());
+    assert_eq!(unsafe { libc::close(fd) }, 0);
+    // never dropped, so that the stale descriptor is not closed a second time
+    let stale = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+
+    let e = reflink::reflink_range(&stale, 0, &dest, 0, 0).unwrap_err();
+    println!("{}", e);
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert!(e.to_string().contains("closed"));
+    let e = reflink::reflink_to_tmpfile_fd(&stale, &File::open(dir.path())?).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    // a destination opened for reading only is refused the same way
+    let read_only = File::open(dir.path().join("dest.txt"))?;
+    let e = reflink::reflink_range(&src, 0, &read_only, 0, 0).unwrap_err();
+    println!("{}", e);
+    assert!(e.kind() == io::ErrorKind::InvalidInput || unsupported_reason(&e).is_some());
+    Ok(())
+}
+
+#[test]
+fn reflink_or_copy_preserves_sparseness() -> io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.img");
+    let dest_file_path = dir.path().join("dest.img");
+    let len = 16 * 1024 * 1024;
+    {
+        let mut src = File::create(&src_file_path)?;
+        #[cfg(windows)]
+        {
+            drop(src);
+            let path = src_file_path.to_str().unwrap();
+            let status = std::process::Command::new("fsutil")
+                .args(["sparse", "setflag", path])
+                .status()?;
+            assert!(status.success());
+            src = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&src_file_path)?;
+        }
+        src.set_len(len)?;
+        src.seek(SeekFrom::Start(8 * 1024 * 1024))?;
+        src.write_all(&[7; 4096])?;
+    }
+
+    reflink::ReflinkOptions::new()
+        .preserve_sparseness(true)
+        .reflink_or_copy(&src_file_path, &dest_file_path)?;
+    let data = std::fs::read(&dest_file_path)?;
+    assert_eq!(data.len() as u64, len);
+    assert!(data[8 * 1024 * 1024..][..4096].iter().all(|&b| b == 7));
+    assert_eq!(data.iter().filter(|&&b| b != 0).count(), 4096);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let allocated =
+            |path: &Path| -> io::Result<u64> { Ok(std::fs::metadata(path)?.blocks() * 512) };
+        // only meaningful if the file system kept the source sparse in the first place
+        if allocated(&src_file_path)? < 1024 * 1024 {
+            assert!(allocated(&dest_file_path)? < 1024 * 1024);
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+
+        const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x200;
+        let attributes = std::fs::metadata(&dest_file_path)?.file_attributes();
+        assert_ne!(attributes & FILE_ATTRIBUTE_SPARSE_FILE, 0);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_or_copy_keeps_sparse_sources_sparse() -> io::Result<()> {
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    let allocated =
+        |path: &Path| -> io::Result<u64> { Ok(std::fs::metadata(path)?.blocks() * 512) };
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.img");
+    let dest_file_path = dir.path().join("dest.img");
+    let len = 64 * 1024 * 1024;
+    let src = File::create(&src_file_path)?;
+    src.set_len(len)?;
+    src.write_all_at(&[7; 4096], 32 * 1024 * 1024)?;
+    drop(src);
+    // only meaningful if the file system keeps files sparse in the first place
+    if allocated(&src_file_path)? >= 1024 * 1024 {
+        return Ok(());
+    }
+
+    let written = reflink_or_copy(&src_file_path, &dest_file_path)?;
+    if written.is_some() {
+        assert_eq!(written, Some(len));
+        assert!(allocated(&dest_file_path)? < 1024 * 1024);
+    }
+    let data = std::fs::read(&dest_file_path)?;
+    assert_eq!(data.len() as u64, len);
+    assert!(data[32 * 1024 * 1024..][..4096].iter().all(|&b| b == 7));
+    assert_eq!(data.iter().filter(|&&b| b != 0).count(), 4096);
+    Ok(())
+}
+
+#[test]
+fn reflink_audit_denies() -> io::Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+    let existing_path = dir.path().join("existing.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    File::create(&existing_path)?.write_all(b"keep me")?;
+    let tree = dir.path().join("tree");
+    std::fs::create_dir(&tree)?;
+    File::create(tree.join("a.txt"))?.write_all(b"a")?;
+
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let mut options = reflink::ReflinkOptions::new();
+    options.audit({
+        let records = Arc::clone(&records);
+        move |record| {
+            records.lock().unwrap().push(record.clone());
+            false
+        }
+    });
+    let is_aborted = |e: &io::Error| {
+        e.kind() == io::ErrorKind::PermissionDenied
+            && matches!(ReflinkError::from_io(e), Some(ReflinkError::Aborted))
+    };
+
+    assert!(is_aborted(
+        &options
+            .reflink(&src_file_path, &dest_file_path)
+            .unwrap_err()
+    ));
+    assert!(is_aborted(
+        &options
+            .reflink_or_copy(&src_file_path, &dest_file_path)
+            .unwrap_err()
+    ));
+    let results = options.reflink_fanout(&File::open(&src_file_path)?, &[&dest_file_path]);
+    assert!(is_aborted(results[0].as_ref().unwrap_err()));
+    let mut state = options.reflink_or_copy_steps(&src_file_path, &dest_file_path);
+    assert!(is_aborted(&state.step().unwrap_err()));
+    assert!(!dest_file_path.exists());
+
+    // the existing destination is not removed by the conflict policy
+    options.on_conflict(reflink::Conflict::Overwrite);
+    assert!(is_aborted(
+        &options
+            .reflink_or_copy(&src_file_path, &existing_path)
+            .unwrap_err()
+    ));
+    assert_eq!(std::fs::read(&existing_path)?, b"keep me");
+
+    let report = reflink::copy_tree(&tree, dir.path().join("tree-copy"), &options)?;
+    assert_eq!(report.failures.len(), 1);
+    assert!(is_aborted(&report.failures[0].1));
+    assert!(!dir.path().join("tree-copy/a.txt").exists());
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 6);
+    assert_eq!(records[0].source.as_deref(), Some(src_file_path.as_path()));
+    assert_eq!(records[0].dest, dest_file_path);
+    assert!(!records[0].copy_fallback);
+    assert!(records[1].copy_fallback);
+    assert_eq!(records[2].source, None);
+    assert!(records[0]
+        .to_string()
+        .ends_with(&format!("dest.txt ({})", reflink::Mechanism::current())));
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn reflink_retries_sharing_violations() -> io::Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::time::Duration;
+
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.txt");
+    File::create(&src_file_path)?.write_all(b"this is a test")?;
+    let lock = || {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .share_mode(0)
+            .open(&src_file_path)
+    };
+
+    let locked = lock()?;
+    let e = reflink::ReflinkOptions::new()
+        .retry_sharing_violations(2, Duration::from_millis(10))
+        .reflink(&src_file_path, dir.path().join("dest.txt"))
+        .unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ERROR_SHARING_VIOLATION));
+    assert!(!dir.path().join("dest.txt").exists());
+
+    // the lock is released while retrying
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        drop(locked);
+    });
+    let res = reflink::ReflinkOptions::new()
+        .retry_sharing_violations(100, Duration::from_millis(20))
+        .reflink_or_copy(&src_file_path, dir.path().join("dest.txt"));
+    release.join().unwrap();
+    res?;
+    assert_eq!(
+        std::fs::read(dir.path().join("dest.txt"))?,
+        b"this is a test"
+    );
+    drop(lock()?);
+    Ok(())
+}
+
+// Windows refuses to replace a file which readers keep open without `FILE_SHARE_DELETE`
+#[cfg(unix)]
+#[test]
+fn reflink_replace_is_atomic() -> io::Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let dir = tempdir()?;
+    let old_path = dir.path().join("old.bin");
+    let new_path = dir.path().join("new.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let (old, new) = (vec![b'a'; 1024 * 1024], vec![b'b'; 2 * 1024 * 1024]);
+    File::create(&old_path)?.write_all(&old)?;
+    File::create(&new_path)?.write_all(&new)?;
+    std::fs::copy(&old_path, &dest_file_path)?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let (done, dest_file_path) = (Arc::clone(&done), dest_file_path.clone());
+        let (old, new) = (old.clone(), new.clone());
+        std::thread::spawn(move || -> io::Result<usize> {
+            let mut reads = 0;
+            while !done.load(Ordering::SeqCst) {
+                // the destination always exists and is never partial
+                let data = std::fs::read(&dest_file_path)?;
+                assert!(data == old || data == new, "read {} bytes", data.len());
+                reads += 1;
+            }
+            Ok(reads)
+        })
+    };
+    let mut options = reflink::ReflinkOptions::new();
+    options.on_conflict(reflink::Conflict::Replace);
+    for i in 0..20 {
+        let from = if i % 2 == 0 { &new_path } else { &old_path };
+        options.reflink_or_copy(from, &dest_file_path)?;
+        match options.reflink(from, &dest_file_path) {
+            Ok(()) => {}
+            Err(e) => assert!(unsupported_reason(&e).is_some(), "{:?}", e),
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    assert!(reader.join().unwrap()? > 0);
+    assert_eq!(std::fs::read(&dest_file_path)?, old);
+    // no temporary files are left behind
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 3);
+
+    let mut state = options.reflink_or_copy_steps(&new_path, &dest_file_path);
+    while let reflink::StepResult::Progress { .. } = state.step()? {
+        assert_eq!(std::fs::read(&dest_file_path)?, old);
+    }
+    assert_eq!(state.dest(), dest_file_path);
+    assert_eq!(std::fs::read(&dest_file_path)?, new);
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 3);
+    Ok(())
+}
+
+#[test]
+fn reflink_with_methods_in_order() -> io::Result<()> {
+    use reflink::{CopyMethod, ReflinkOptions};
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.txt");
+    File::create(&src)?.write_all(&[3; 64 * 1024])?;
+    let dest = |name: &str| dir.path().join(name);
+    let run = |methods: &[CopyMethod], to: &Path| {
+        ReflinkOptions::new()
+            .methods(methods)
+            .reflink_with_methods(&src, to)
+    };
+
+    // a copy always works, so nothing after it is tried
+    let method = run(&[CopyMethod::PlainCopy, CopyMethod::Reflink], &dest("copy"))?;
+    assert_eq!(method, CopyMethod::PlainCopy);
+    assert_eq!(std::fs::read(dest("copy"))?, std::fs::read(&src)?);
+
+    let method = run(
+        &[CopyMethod::HardLink, CopyMethod::PlainCopy],
+        &dest("link"),
+    )?;
+    assert_eq!(method, CopyMethod::HardLink);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(
+            std::fs::metadata(dest("link"))?.ino(),
+            std::fs::metadata(&src)?.ino()
+        );
+    }
+
+    // without fallback, a failed reflink leaves nothing behind
+    match run(&[CopyMethod::Reflink], &dest("reflink")) {
+        Ok(method) => assert_eq!(method, CopyMethod::Reflink),
+        Err(e) => {
+            assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+            assert!(!dest("reflink").exists());
+        }
+    }
+
+    let methods = [
+        CopyMethod::Reflink,
+        CopyMethod::ReflinkRange,
+        CopyMethod::CopyFileRange,
+        CopyMethod::PlainCopy,
+    ];
+    let method = run(&methods, &dest("chain"))?;
+    assert_eq!(std::fs::read(dest("chain"))?, std::fs::read(&src)?);
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        // copy_file_range works on any local file system
+        assert_ne!(method, CopyMethod::PlainCopy);
+    }
+    let reflinked = method == CopyMethod::Reflink;
+    // the range clone only gets its turn if the whole file clone failed
+    assert_eq!(
+        run(&methods[1..], &dest("range")).map(|m| m == CopyMethod::ReflinkRange)?,
+        reflinked && !cfg!(any(target_os = "macos", target_os = "ios")),
+    );
+
+    // the default tries a reflink, then copies
+    let method = ReflinkOptions::new().reflink_with_methods(&src, dest("default"))?;
+    assert_eq!(reflinked, method == CopyMethod::Reflink);
+    assert!(reflinked || method == CopyMethod::PlainCopy);
+
+    // an existing destination is not replaced by a later method
+    File::create(dest("existing"))?.write_all(b"old")?;
+    let err = run(
+        &[CopyMethod::Reflink, CopyMethod::PlainCopy],
+        &dest("existing"),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read(dest("existing"))?, b"old");
+
+    let err = run(&[], &dest("none")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!dest("none").exists());
+    Ok(())
+}
+
+#[test]
+fn reflink_special_sources_are_typed() -> io::Result<()> {
+    use reflink::FileKind;
+
+    fn check(from: &Path, kind: FileKind) {
+        let dir = tempdir().unwrap();
+        let to = dir.path().join("dest");
+        let results = [
+            reflink(from, &to),
+            reflink_or_copy(from, &to).map(drop),
+            reflink::ReflinkOptions::new()
+                .allow_nonregular_copy(kind == FileKind::Directory)
+                .reflink_or_copy(from, &to)
+                .map(drop),
+        ];
+        for res in results {
+            let e = res.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", from);
+            match ReflinkError::from_io(&e) {
+                Some(&ReflinkError::NotRegular { kind: found }) => assert_eq!(found, kind),
+                _ => panic!("{:?}: {:?}", from, e),
+            }
+            assert!(!to.exists());
+        }
+    }
+
+    let dir = tempdir()?;
+    check(dir.path(), FileKind::Directory);
+
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileTypeExt;
+
+        let fifo = dir.path().join("fifo");
+        let path = CString::new(fifo.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        check(&fifo, FileKind::Fifo);
+
+        let socket = dir.path().join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket)?;
+        check(&socket, FileKind::Socket);
+
+        check(Path::new("/dev/null"), FileKind::CharDevice);
+
+        // block devices cannot be created without privileges, but may exist
+        let block = std::fs::read_dir("/dev")?
+            .filter_map(Result::ok)
+            .find(|entry| entry.file_type().is_ok_and(|t| t.is_block_device()));
+        if let Some(block) = block {
+            check(&block.path(), FileKind::BlockDevice);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn reflink_source_to_many_destinations() -> io::Result<()> {
+    use reflink::{ReflinkOptions, ReflinkSource};
+
+    let dir = tempdir()?;
+    let src = dir.path().join("base.img");
+    let data: Vec<u8> = (0..128 * 1024).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&src, &data)?;
+
+    let source = ReflinkSource::open(&src)?;
+    assert_eq!(source.path(), src);
+    // later clones still read the file opened at the start
+    std::fs::rename(&src, dir.path().join("old.img"))?;
+    std::fs::write(&src, b"replaced")?;
+    for i in 0..50 {
+        let to = dir.path().join(format!("vm-{}.img", i));
+        match source.clone_to(&to) {
+            Ok(()) => assert!(std::fs::read(&to)? == data),
+            Err(e) => {
+                assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+                assert!(!to.exists());
+            }
+        }
+    }
+    let e = source.clone_to(dir.path()).unwrap_err();
+    assert_ne!(e.kind(), io::ErrorKind::Unsupported);
+
+    let e = ReflinkSource::open(dir.path()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = ReflinkOptions::new()
+        .reflink_source(dir.path().join("missing.img"))
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+#[test]
+fn reflink_post_clone_hook() -> io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.bin");
+    std::fs::write(&src, b"cloned data")?;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&calls);
+    let to = dir.path().join("appended.bin");
+    let res = reflink::ReflinkOptions::new()
+        .post_clone(move |mut dest| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            dest.seek(SeekFrom::End(0))?;
+            dest.write_all(b" and a trailer")
+        })
+        .reflink(&src, &to);
+    match res {
+        Ok(()) => {
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            assert_eq!(std::fs::read(&to)?, b"cloned data and a trailer");
+            assert_eq!(std::fs::read(&src)?, b"cloned data");
+        }
+        Err(e) => {
+            assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+            assert!(!to.exists());
+        }
+    }
+
+    let to = dir.path().join("failed.bin");
+    let e = reflink::ReflinkOptions::new()
+        .post_clone(|_| Err(io::Error::other("no header for you")))
+        .reflink(&src, &to)
+        .unwrap_err();
+    assert!(
+        unsupported_reason(&e).is_some() || e.to_string() == "no header for you",
+        "{:?}",
+        e
+    );
+    assert!(!to.exists());
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+#[test]
+fn reflink_preserve_ownership() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if unsafe { libc::geteuid() } != 0 {
+        println!("skipped, copying the ownership needs root");
+        return Ok(());
+    }
+    let dir = tempdir()?;
+    let src = dir.path().join("src.txt");
+    File::create(&src)?.write_all(b"restored")?;
+    std::os::unix::fs::chown(&src, Some(1234), Some(5678))?;
+
+    for &(preserve, owner) in &[(true, (1234, 5678)), (false, (0, 0))] {
+        let to = dir.path().join(format!("{}.txt", preserve));
+        match reflink::ReflinkOptions::new()
+            .preserve_ownership(preserve)
+            .reflink(&src, &to)
+        {
+            Ok(()) => {
+                let metadata = std::fs::metadata(&to)?;
+                assert_eq!(metadata.uid(), owner.0);
+                if preserve {
+                    assert_eq!(metadata.gid(), owner.1);
+                }
+            }
+            Err(e) => assert!(unsupported_reason(&e).is_some(), "{:?}", e),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn try_reflink_outcomes() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src.txt");
+    std::fs::write(&src, b"maybe shared")?;
+
+    let to = dir.path().join("dest.txt");
+    if reflink::try_reflink(&src, &to)? {
+        assert_eq!(std::fs::read(&to)?, b"maybe shared");
+    } else {
+        assert!(!to.exists());
+    }
+
+    // a reflink across file systems is never supported
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let shm = tempfile::tempdir_in("/dev/shm")?;
+        let to = shm.path().join("dest.txt");
+        assert!(!reflink::try_reflink(&src, &to)?);
+        assert!(!to.exists());
+    }
+
+    let missing = dir.path().join("missing.txt");
+    let e = reflink::try_reflink(&missing, dir.path().join("other.txt")).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    std::fs::write(dir.path().join("taken.txt"), b"taken")?;
+    let e = reflink::try_reflink(&src, dir.path().join("taken.txt")).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read(dir.path().join("taken.txt"))?, b"taken");
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_memfd_is_unsupported() -> io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+
+    let memfd = || -> io::Result<File> {
+        let fd = unsafe { libc::memfd_create(b"reflink\0".as_ptr().cast(), libc::MFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    };
+    let mut src = match memfd() {
+        Ok(src) => src,
+        // kernels before 3.17
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    src.write_all(b"shared memory")?;
+    let dest = memfd()?;
+
+    let e = reflink::reflink_range(&src, 0, &dest, 0, 0).unwrap_err();
+    println!("{:?}", e);
+    assert_eq!(unsupported_reason(&e), Some(UnsupportedReason::Filesystem));
+    let source = std::error::Error::source(ReflinkError::from_io(&e).unwrap()).unwrap();
+    assert!(source.to_string().contains("tmpfs"), "{}", source);
+
+    // memfd files live on an internal mount, so any path is on another file system
+    let dir = tempdir()?;
+    let to = dir.path().join("dest.txt");
+    let results = reflink::reflink_fanout(&src, &[&to]);
+    let e = results.into_iter().next().unwrap().unwrap_err();
+    assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+    assert!(!to.exists());
+    Ok(())
+}
+
+#[test]
+fn reflink_cancelled_copy_is_removed() -> io::Result<()> {
+    use reflink::CopyMethod;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.bin");
+    std::fs::write(&src, vec![7u8; 8 * 1024 * 1024])?;
+
+    for &sparse in &[false, true] {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&polls);
+        let to = dir.path().join(format!("dest-{}.bin", sparse));
+        let e = reflink::ReflinkOptions::new()
+            .methods(&[CopyMethod::PlainCopy])
+            .preserve_sparseness(sparse)
+            // stop after the first two chunks were written
+            .cancel(move || counter.fetch_add(1, Ordering::SeqCst) == 2)
+            .reflink_with_methods(&src, &to)
+            .unwrap_err();
+        assert!(
+            matches!(ReflinkError::from_io(&e), Some(ReflinkError::Cancelled)),
+            "{:?}",
+            e
+        );
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+        assert!(!to.exists());
+    }
+
+    let to = dir.path().join("done.bin");
+    let method = reflink::ReflinkOptions::new()
+        .methods(&[CopyMethod::PlainCopy])
+        .cancel(|| false)
+        .reflink_with_methods(&src, &to)?;
+    assert_eq!(method, CopyMethod::PlainCopy);
+    assert_eq!(std::fs::read(&to)?, std::fs::read(&src)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_preserve_all_matches_cp() -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.txt");
+    std::fs::write(&src, b"all of it")?;
+
+    // the metadata `cp --reflink=always --preserve=all` would give the copy
+    let mode = 0o640;
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let accessed = SystemTime::UNIX_EPOCH + Duration::from_secs(1_100_000_000);
+    let root = unsafe { libc::geteuid() } == 0;
+    let owner = if root {
+        (1234, 5678)
+    } else {
+        let metadata = std::fs::metadata(&src)?;
+        (metadata.uid(), metadata.gid())
+    };
+    if root {
+        std::os::unix::fs::chown(&src, Some(owner.0), Some(owner.1))?;
+    }
+    #[cfg(target_os = "linux")]
+    let has_xattr = xattr::set(&src, "user.reflink", b"kept").is_ok();
+    let times = std::fs::FileTimes::new()
+        .set_modified(modified)
+        .set_accessed(accessed);
+    File::options().write(true).open(&src)?.set_times(times)?;
+    std::fs::set_permissions(&src, std::fs::Permissions::from_mode(mode))?;
+
+    let to = dir.path().join("dest.txt");
+    match reflink::reflink_preserve_all(&src, &to) {
+        Ok(()) => {
+            let metadata = std::fs::metadata(&to)?;
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode);
+            assert_eq!(metadata.modified()?, modified);
+            assert_eq!(metadata.accessed()?, accessed);
+            assert_eq!((metadata.uid(), metadata.gid()), owner);
+            #[cfg(target_os = "linux")]
+            {
+                if has_xattr {
+                    assert_eq!(xattr::get(&to, "user.reflink")?, b"kept");
+                }
+            }
+            assert_eq!(std::fs::read(&to)?, b"all of it");
+        }
+        Err(e) => {
+            // never falls back to a copy
+            assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+            assert!(!to.exists());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", windows))]
+#[test]
+fn reflink_range_checks_alignment() -> io::Result<()> {
+    use std::fs::OpenOptions;
+
+    let dir = tempdir()?;
+    let src_file_path = dir.path().join("src.img");
+    let dest_file_path = dir.path().join("dest.img");
+    let src = File::create(&src_file_path)?;
+    let align = match reflink::clone_alignment(&src) {
+        Ok(align) => align,
+        Err(e) if cfg!(windows) => {
+            println!("{:?}", e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    // two and a half blocks
+    let len = 2 * align + align / 2;
+    drop(src);
+    std::fs::write(&src_file_path, vec![b'a'; len as usize])?;
+    std::fs::write(&dest_file_path, vec![b'b'; len as usize])?;
+    let src = File::open(&src_file_path)?;
+    let dest = OpenOptions::new().write(true).open(&dest_file_path)?;
+
+    for &(src_offset, dest_offset, range_len, name, value) in &[
+        (1, 0, align, "source offset", 1),
+        (0, align + 1, align, "destination offset", align + 1),
+        (0, 0, align - 1, "length", align - 1),
+    ] {
+        let e =
+            reflink::reflink_range(&src, src_offset, &dest, dest_offset, range_len).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", e);
+        let message = e.to_string();
+        assert!(
+            message.starts_with(&format!("{} {} ", name, value)),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(&align.to_string()), "{}", message);
+    }
+    assert_eq!(std::fs::read(&dest_file_path)?, vec![b'b'; len as usize]);
+
+    // aligned ranges, and on Linux an unaligned one ending at the end of the source, are
+    // left to the file system
+    let mut ranges = vec![(0, 0, align), (align, 0, align)];
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        ranges.push((align, align, len - align));
+        ranges.push((2 * align, 0, align));
+    }
+    for (src_offset, dest_offset, range_len) in ranges {
+        match reflink::reflink_range(&src, src_offset, &dest, dest_offset, range_len) {
+            Ok(()) => {}
+            Err(e) => assert!(unsupported_reason(&e).is_some(), "{:?}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Needs a ZFS pool with block cloning (OpenZFS 2.2 or later, `zfs_bclone_enabled=1`), e.g.
+/// `REFLINK_TEST_ZFS_DIR=/tank/tmp`
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_on_zfs_clones_blocks() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_ZFS_DIR") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src_file_path = dir.path().join("src.bin");
+    let dest_file_path = dir.path().join("dest.bin");
+    let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut src = File::create(&src_file_path)?;
+    src.write_all(&data)?;
+    // blocks still in the open transaction group would be copied instead
+    src.sync_all()?;
+    drop(src);
+
+    assert!(reflink::can_reflink(&src_file_path, &dest_file_path)?);
+    let sharing = reflink::reflink_reported(&src_file_path, &dest_file_path)?;
+    assert_eq!(sharing, reflink::Sharing::Shared);
+    assert_eq!(std::fs::read(&dest_file_path)?, data);
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+))]
+#[test]
+fn reflink_verified() -> io::Result<()> {
+    use reflink::VerifyLevel;
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.bin");
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&src, &data)?;
+
+    for &level in &[VerifyLevel::None, VerifyLevel::Sampled, VerifyLevel::Full] {
+        let to = dir.path().join(format!("{:?}.bin", level));
+        match reflink::ReflinkOptions::new()
+            .verify(level)
+            .reflink(&src, &to)
+        {
+            Ok(()) => assert_eq!(std::fs::read(&to)?, data),
+            Err(e) => {
+                assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+                assert!(!to.exists());
+            }
+        }
+        let to = dir.path().join(format!("{:?}-or-copy.bin", level));
+        reflink::ReflinkOptions::new()
+            .verify(level)
+            .reflink_or_copy(&src, &to)?;
+        assert_eq!(std::fs::read(&to)?, data);
+    }
+    Ok(())
+}
+
+#[test]
+fn reflink_observer_sees_clone_or_fallback() -> io::Result<()> {
+    use reflink::{CopyMethod, Mechanism, ReflinkObserver};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl ReflinkObserver for Recorder {
+        fn on_attempt(&self, from: &Path, to: &Path) {
+            let name = |p: &Path| p.file_name().unwrap().to_string_lossy().into_owned();
+            let event = format!("attempt {} -> {}", name(from), name(to));
+            self.0.lock().unwrap().push(event);
+        }
+        fn on_success(&self, mechanism: Mechanism) {
+            assert_eq!(mechanism, Mechanism::current());
+            self.0.lock().unwrap().push("success".to_owned());
+        }
+        fn on_fallback(&self, reason: &ReflinkError) {
+            assert!(reason.unsupported_reason().is_some(), "{:?}", reason);
+            self.0.lock().unwrap().push("fallback".to_owned());
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.bin");
+    std::fs::write(&src, b"observed")?;
+    let recorder = Arc::new(Recorder::default());
+    let mut options = reflink::ReflinkOptions::new();
+    options.observer(recorder.clone());
+
+    let copied = options.reflink_or_copy(&src, dir.path().join("copy.bin"))?;
+    let outcome = if copied.is_some() {
+        "fallback"
+    } else {
+        "success"
+    };
+    assert_eq!(recorder.take(), ["attempt src.bin -> copy.bin", outcome]);
+
+    options.methods(&[CopyMethod::Reflink, CopyMethod::PlainCopy]);
+    let method = options.reflink_with_methods(&src, dir.path().join("methods.bin"))?;
+    let outcome = match method {
+        CopyMethod::Reflink => "success",
+        _ => "fallback",
+    };
+    assert_eq!(recorder.take(), ["attempt src.bin -> methods.bin", outcome]);
+
+    // a missing parent directory rules out the copy as well
+    let e = options
+        .reflink_or_copy(&src, dir.path().join("missing").join("dest.bin"))
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert_eq!(recorder.take(), ["attempt src.bin -> dest.bin"]);
+
+    // copies without a clone attempt are not reported
+    options.methods(&[CopyMethod::PlainCopy]);
+    options.reflink_with_methods(&src, dir.path().join("plain.bin"))?;
+    assert!(recorder.take().is_empty());
+    Ok(())
+}
+
+/// Needs a btrfs file system, e.g. `REFLINK_TEST_BTRFS_DIR_A=/mnt/btrfs-a/tmp`
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_is_atomic_with_respect_to_writes() -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const LEN: usize = 1024 * 1024;
+
+    let dir = match std::env::var_os("REFLINK_TEST_BTRFS_DIR_A") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src = dir.path().join("src.bin");
+    std::fs::write(&src, vec![0u8; LEN])?;
+
+    // rewrite the whole source with one `pwrite` per generation while cloning
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (src, stop) = (File::options().write(true).open(&src)?, Arc::clone(&stop));
+        std::thread::spawn(move || -> io::Result<u8> {
+            let mut generation = 0u8;
+            while !stop.load(Ordering::Relaxed) {
+                generation = generation.wrapping_add(1);
+                assert_eq!(src.write_at(&vec![generation; LEN], 0)?, LEN);
+            }
+            Ok(generation)
+        })
+    };
+
+    let mut seen = Vec::new();
+    for i in 0..50 {
+        let to = dir.path().join(format!("clone-{}.bin", i));
+        reflink(&src, &to)?;
+        let data = std::fs::read(&to)?;
+        assert_eq!(data.len(), LEN);
+        let first = data[0];
+        if let Some(offset) = data.iter().position(|&b| b != first) {
+            panic!(
+                "clone {} is torn: generation {} up to byte {}, then {}",
+                i, first, offset, data[offset]
+            );
+        }
+        seen.push(first);
+        std::fs::remove_file(&to)?;
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap()?;
+    seen.dedup();
+    // the clones did observe the source changing
+    assert!(seen.len() > 1, "{:?}", seen);
+    Ok(())
+}
+
+#[test]
+fn estimate_cost_tells_reflink_from_copy() -> io::Result<()> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src.bin");
+    std::fs::write(&src, vec![1u8; 100_000])?;
+    let to = dir.path().join("dest.bin");
+
+    let estimate = reflink::estimate_cost(&src, &to)?;
+    assert_eq!(estimate.size, 100_000);
+    assert_eq!(estimate.reflink, reflink::can_reflink(&src, &to)?);
+    let expected = match reflink_or_copy(&src, &to)? {
+        None => "reflink of 100000 bytes",
+        Some(_) => "copy of 100000 bytes",
+    };
+    assert_eq!(estimate.to_string(), expected);
+    assert_eq!(
+        estimate.bytes_to_copy(),
+        if estimate.reflink { 0 } else { 100_000 }
+    );
+    // an existing destination is judged by its directory
+    assert_eq!(reflink::estimate_cost(&src, &to)?, estimate);
+
+    let e = reflink::estimate_cost(dir.path(), &to).unwrap_err();
+    assert!(matches!(
+        ReflinkError::from_io(&e),
+        Some(ReflinkError::NotRegular { .. })
+    ));
+    Ok(())
+}
+
+/// Needs a btrfs file system, e.g. `REFLINK_TEST_BTRFS_DIR_A=/mnt/btrfs-a/tmp`
+#[cfg(target_os = "linux")]
+#[test]
+fn estimate_cost_on_btrfs_is_a_reflink() -> io::Result<()> {
+    let dir = match std::env::var_os("REFLINK_TEST_BTRFS_DIR_A") {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => return Ok(()),
+    };
+    let src = dir.path().join("src.bin");
+    std::fs::write(&src, vec![1u8; 100_000])?;
+    let estimate = reflink::estimate_cost(&src, dir.path().join("dest.bin"))?;
+    assert!(estimate.reflink);
+    assert_eq!(estimate.bytes_to_copy(), 0);
+
+    // tmpfs cannot clone at all
+    let shm = tempfile::tempdir_in("/dev/shm")?;
+    let estimate = reflink::estimate_cost(&src, shm.path().join("dest.bin"))?;
+    assert!(!estimate.reflink);
+    assert_eq!(estimate.bytes_to_copy(), 100_000);
+    Ok(())
+}
+
+#[test]
+fn trailing_separator_clones_into_directory() -> io::Result<()> {
+    use reflink::{CopyMethod, StepResult};
+
+    let dir = tempdir()?;
+    let src = dir.path().join("src.txt");
+    std::fs::write(&src, b"into the directory")?;
+    let mut separators = vec!["/"];
+    if cfg!(windows) {
+        separators.push("\\");
+    }
+
+    for (i, separator) in separators.into_iter().enumerate() {
+        let into = |name: &str| -> io::Result<(std::path::PathBuf, String)> {
+            let target = dir.path().join(format!("{}-{}", name, i));
+            std::fs::create_dir(&target)?;
+            let requested = format!("{}{}", target.display(), separator);
+            Ok((target.join("src.txt"), requested))
+        };
+
+        let (expected, requested) = into("or-copy")?;
+        reflink_or_copy(&src, &requested)?;
+        assert_eq!(std::fs::read(&expected)?, b"into the directory");
+
+        let (expected, requested) = into("reflink")?;
+        match reflink::ReflinkOptions::new().reflink_path(&src, &requested) {
+            Ok(path) => assert_eq!(path, expected),
+            Err(e) => {
+                assert!(unsupported_reason(&e).is_some(), "{:?}", e);
+                assert!(!expected.exists());
+            }
+        }
+
+        let (expected, requested) = into("methods")?;
+        reflink::ReflinkOptions::new()
+            .methods(&[CopyMethod::PlainCopy])
+            .reflink_with_methods(&src, &requested)?;
+        assert_eq!(std::fs::read(&expected)?, b"into the directory");
+
+        let (expected, requested) = into("steps")?;
+        let mut steps = reflink::ReflinkOptions::new().reflink_or_copy_steps(&src, &requested);
+        while let StepResult::Progress { .. } = steps.step()? {}
+        assert_eq!(steps.dest(), expected);
+        assert_eq!(std::fs::read(&expected)?, b"into the directory");
+    }
+
+    // checks and estimates look into the directory as well
+    let target = dir.path().join("checked");
+    std::fs::create_dir(&target)?;
+    let requested = format!("{}/", target.display());
+    let expected = reflink::can_reflink(&src, target.join("src.txt"))?;
+    assert_eq!(reflink::can_reflink(&src, &requested)?, expected);
+    let estimate = reflink::estimate_cost(&src, &requested)?;
+    assert_eq!(estimate.reflink, expected);
+    assert_eq!(estimate.size, 18);
+    std::fs::write(target.join("src.txt"), b"taken")?;
+    assert!(!reflink::can_reflink(&src, &requested)?);
+
+    // without the separator, an existing directory is in the way
+    let target = dir.path().join("plain");
+    std::fs::create_dir(&target)?;
+    assert!(reflink_or_copy(&src, &target).is_err());
+    assert!(!target.join("src.txt").exists());
+    Ok(())
+}